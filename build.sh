@@ -21,8 +21,14 @@ rm -f ${DIST_DIR}/*
 #  WASM stuff
 #
 
-# update (in release mode) the wasm:
-cargo build --target wasm32-unknown-unknown --${MODE}
+# update (in release mode) the wasm.  THREADS=1 builds the shared memory
+# renderer, this needs a nightly toolchain with the rust-src component:
+if [ "${THREADS}" = "1" ]; then
+  RUSTFLAGS='-C target-feature=+atomics,+bulk-memory,+mutable-globals' \
+    cargo +nightly build --target wasm32-unknown-unknown --${MODE} --features threads -Z build-std=std,panic_abort
+else
+  cargo build --target wasm32-unknown-unknown --${MODE}
+fi
 
 # update the wasm bindings:
 wasm-bindgen ${TARG_DIR}/rust_web_rtrt.wasm --no-modules --no-modules-global rayTracer --no-typescript --out-dir js/wasm
@@ -34,7 +40,10 @@ cp js/wasm/rust_web_rtrt_bg.wasm ${DIST_DIR}
 #
 
 
-MAIN_JS="js/index.js js/ManageRayTracing.js"
+# Check the JS that can run outside a browser:
+node js/test/CopyStrip.test.js
+
+MAIN_JS="js/index.js js/ManageRayTracing.js js/ManageSharedRayTracing.js"
 browserify ${MAIN_JS} -o ${DIST_DIR}/main.js
 
 COMMON_JS="js/common/*.js"
@@ -46,6 +55,11 @@ browserify ${WORKER_JS}   -o ${DIST_DIR}/JSRayTraceWorker.js
 WORKER_WASM="js/wasm/RayTraceWorker.js"
 browserify ${WORKER_WASM}   -o ${DIST_DIR}/WasmRayTraceWorker.js
 
+if [ "${THREADS}" = "1" ]; then
+  WORKER_WASM_SHARED="js/wasm/SharedRayTraceWorker.js"
+  browserify ${WORKER_WASM_SHARED}   -o ${DIST_DIR}/WasmSharedRayTraceWorker.js
+fi
+
 cp js/index.html ${DIST_DIR}
 
 
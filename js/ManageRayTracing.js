@@ -26,12 +26,12 @@
 /* globals performance */
 
 const Abrupt = require('./lib/Abrupt.js');
+const copyStrip = require('./lib/CopyStrip.js');
 const constants = require('./common/Constants');
 
-function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, renderCallback, workerUri) {
+function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, renderCallback, workerUri, scene) {
   let activeImage = 0;
   const abrupt = new Abrupt({ maxDiffWorkId: 30, maxConcurrent: constants.NUM_WORKERS });
-  const rotationSpeed = 360 / 10; // Degrees per second
 
   const createWorkUnitsFn = imgId =>
     [...Array(numStrips).keys()].reverse().map(stripId => ({
@@ -43,13 +43,18 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
     0: createWorkUnitsFn(0),
     1: createWorkUnitsFn(1),
   };
-  const incWorkUnit = {
-    type: 'inc',
-    angle: 0.0,
-  };
+  let animationTime = 0.0;
+  // The workers only render what changed, the rest is copied from the last
+  // image rendered, if it was the other one.
+  let lastImage = null;
+  let copyFrom = null;
+  // Scene edits wait for the next frame, then every worker makes them, once.
+  let pendingEdits = [];
+  // Whether the workers filter whole frames, so one renders each frame.
+  let wholeFrame = false;
 
   // Load the workers
-  const workerUris = [...Array(numWorkers)].map(() => workerUri);
+  const workerUris = [...Array(numWorkers)].map(() => `${workerUri}?scene=${scene}`);
   abrupt.setWorkers(workerUris, handleMessageFromWorker).then(() => {
     next();
   });
@@ -64,9 +69,34 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
   // Functions
   //
 
+  // Send each edit to every worker in turn, waiting for them all to make it.
+  function sendEdits(edits) {
+    return edits.reduce(
+      (done, edit) =>
+        done
+          .then(() => abrupt.broadcast(edit))
+          .then(replies => {
+            // They all make the same edit, so they all fail the same way
+            if (replies[0].error) {
+              console.error(`Scene edit ${edit.method} failed: ${replies[0].error}`);
+            }
+          }),
+      Promise.resolve()
+    );
+  }
+
   function startRenderWork(imgId) {
-    abrupt.addWorkUnits([...Array(numWorkers)].map(() => incWorkUnit));
-    abrupt.addWorkUnits(renderWorkUnits[imgId]);
+    const edits = pendingEdits;
+    pendingEdits = [];
+    copyFrom = lastImage !== imgId ? lastImage : null;
+    lastImage = imgId;
+    sendEdits(edits)
+      .then(() => abrupt.broadcast({ type: 'time', t: animationTime }))
+      .then(replies => {
+        // Every worker has the same scene, so the same filters
+        wholeFrame = Boolean(replies[0].wholeFrame);
+        abrupt.addWorkUnits(wholeFrame ? [{ type: 'frame', imgId }] : renderWorkUnits[imgId]);
+      });
   }
 
   let startTime = performance.now();
@@ -76,8 +106,7 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
     const endTime = performance.now();
     const elapsedTimeSeconds = (endTime - startTime) / 1000;
     startTime = endTime;
-    incWorkUnit.angle = rotationSpeed * elapsedTimeSeconds;
-    console.log(incWorkUnit.angle);
+    animationTime += elapsedTimeSeconds;
 
     startRenderWork(activeImage === 0 ? 1 : 0);
     renderCallback(activeImage);
@@ -90,7 +119,8 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
         handleRenderUpdate(message);
         break;
 
-      case 'inc_done':
+      case 'frame':
+        images[message.imgId].data.set(new Uint8ClampedArray(message.buffer));
         break;
 
       case 'error':
@@ -102,9 +132,9 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
     }
   }
 
-  function handleRenderUpdate({ stripId, imgId, buffer }) {
-    const startPnt = stripId * buffer.byteLength;
-    images[imgId].data.set(new Uint8ClampedArray(buffer), startPnt);
+  function handleRenderUpdate(update) {
+    const previous = copyFrom !== null ? images[copyFrom].data : null;
+    copyStrip(images[update.imgId].data, previous, update);
   }
 
   return {
@@ -120,6 +150,31 @@ function ManageRayTracing(numWorkers, isPaused, numStrips, depth, images, render
     cancel: () => {
       abrupt.terminateAll();
     },
+    // Call a RayTracer scene editing method, e.g. editScene('remove_object', 2).
+    // It's made by every worker before the next frame.
+    editScene: (method, ...args) => {
+      pendingEdits.push({
+        type: 'edit',
+        method,
+        args,
+      });
+    },
+    // Turn the edge-aware denoiser on or off, from the next frame.
+    setDenoise: on => {
+      pendingEdits.push({
+        type: 'edit',
+        method: 'set_denoise',
+        args: [on],
+      });
+    },
+    // Set the post-processing, in the scene file format, from the next frame.
+    setPostProcess: text => {
+      pendingEdits.push({
+        type: 'edit',
+        method: 'set_post_process',
+        args: [text],
+      });
+    },
   };
 }
 
@@ -0,0 +1,135 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+'use strict';
+
+/* globals performance Worker */
+
+//
+// Drive the shared memory (wasm threads) renderer.  Unlike ManageRayTracing
+// the scene is only updated once per frame (by the owner worker) and the
+// workers share the strips between them, writing into one framebuffer.
+//
+
+function ManageSharedRayTracing(numWorkers, isPaused, numStrips, depth, images, renderCallback, workerUri, scene) {
+  let activeImage = 0;
+  let animationTime = 0.0;
+  const workers = [...Array(numWorkers)].map(() => new Worker(workerUri));
+  const ownerWorker = workers[0];
+  let frame = null;
+  let remaining = 0;
+  let isRendering = false;
+
+  ownerWorker.addEventListener('message', function handleOwnerReady(e) {
+    if (e.data.type !== 'ready') return;
+    ownerWorker.removeEventListener('message', handleOwnerReady);
+    const { module, memory, handle, framePtr, frameLen } = e.data;
+    frame = { memory, framePtr, frameLen };
+
+    let waiting = workers.length - 1;
+    workers.slice(1).forEach(worker => {
+      worker.addEventListener('message', function handleHelperReady(msg) {
+        if (msg.data.type !== 'ready') return;
+        worker.removeEventListener('message', handleHelperReady);
+        waiting -= 1;
+        if (waiting === 0) start();
+      });
+      worker.postMessage({ type: 'init_helper', module, memory, handle });
+    });
+    if (waiting === 0) start();
+  });
+  ownerWorker.postMessage({ type: 'init_owner', depth, scene });
+
+  //
+  // Functions
+  //
+
+  function start() {
+    workers.forEach(worker => worker.addEventListener('message', handleMessageFromWorker));
+    next();
+  }
+
+  let startTime = performance.now();
+  function next() {
+    if (isPaused || isRendering) return;
+
+    const endTime = performance.now();
+    const elapsedTimeSeconds = (endTime - startTime) / 1000;
+    startTime = endTime;
+
+    animationTime += elapsedTimeSeconds;
+
+    isRendering = true;
+    ownerWorker.postMessage({ type: 'time', t: animationTime });
+  }
+
+  function handleMessageFromWorker(e) {
+    switch (e.data.type) {
+      case 'time_done':
+        remaining = workers.length;
+        workers.forEach(worker => worker.postMessage({ type: 'raytrace' }));
+        break;
+
+      case 'raytrace_done':
+        remaining -= 1;
+        if (remaining === 0) ownerWorker.postMessage({ type: 'end_frame' });
+        break;
+
+      case 'end_frame_done':
+        frameComplete();
+        break;
+
+      case 'ready':
+        break;
+
+      default:
+        console.error(`Unexpected msg type: ${e.data.type}`);
+    }
+  }
+
+  function frameComplete() {
+    const { memory, framePtr, frameLen } = frame;
+    images[activeImage].data.set(new Uint8ClampedArray(memory.buffer, framePtr, frameLen));
+    renderCallback(activeImage);
+    activeImage = activeImage === 0 ? 1 : 0;
+    isRendering = false;
+    next();
+  }
+
+  return {
+    pause: setPause => {
+      if (setPause === isPaused) return;
+      isPaused = setPause;
+
+      // Need to resume
+      if (!isPaused) {
+        next();
+      }
+    },
+    cancel: () => {
+      workers.forEach(worker => worker.terminate());
+    },
+  };
+}
+
+module.exports = ManageSharedRayTracing;
@@ -25,20 +25,24 @@
 
 const constants = require('./common/Constants');
 const ManageRayTracing = require('./ManageRayTracing');
+const ManageSharedRayTracing = require('./ManageSharedRayTracing');
 const FPSTimer = require('./common/FPSTimer');
 
 // Let the page load.
 window.onload = () => {
+  const sharedWorkerUri = './WasmSharedRayTraceWorker.js';
+
   function loadRTManager() {
-    return ManageRayTracing(
-      constants.NUM_WORKERS,
-      false,
-      numStrips,
-      constants.DEPTH,
-      images,
-      renderCallback,
-      document.getElementById('selectRenderMethod').value
-    );
+    const workerUri = document.getElementById('selectRenderMethod').value;
+    const scene = document.getElementById('selectScene').value;
+    const manager = workerUri === sharedWorkerUri ? ManageSharedRayTracing : ManageRayTracing;
+    return manager(constants.NUM_WORKERS, false, numStrips, constants.DEPTH, images, renderCallback, workerUri, scene);
+  }
+
+  // SharedArrayBuffer is only available when the page is cross-origin isolated.
+  if (!window.crossOriginIsolated) {
+    const option = document.querySelector(`#selectRenderMethod option[value="${sharedWorkerUri}"]`);
+    if (option) option.remove();
   }
 
   let animationIsPaused = false;
@@ -76,17 +80,16 @@ window.onload = () => {
     false
   );
 
+  function reloadRTManager() {
+    timer.stop();
+    timer.reset();
+    rtMan.cancel();
+    rtMan = loadRTManager();
+  }
+
   // Attach runPause
-  document.getElementById('selectRenderMethod').addEventListener(
-    'change',
-    () => {
-      timer.stop();
-      timer.reset();
-      rtMan.cancel();
-      rtMan = loadRTManager();
-    },
-    false
-  );
+  document.getElementById('selectRenderMethod').addEventListener('change', reloadRTManager, false);
+  document.getElementById('selectScene').addEventListener('change', reloadRTManager, false);
 
   function renderCallback(imgId) {
     const fps = timer.stop();
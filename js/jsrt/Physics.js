@@ -29,10 +29,15 @@
  */
 function Physics() {
   this.obj_list = [];
+  this.start_list = [];
 }
 
+// How fast the objects circle, in degrees per second.
+const ORBIT_SPEED = 360 / 10;
+
 Physics.prototype.addObject = function addObject(obj) {
   this.obj_list[this.obj_list.length] = obj;
+  this.start_list[this.start_list.length] = obj.c.copy();
 };
 
 function rotateOnPlane(p, angle) {
@@ -52,4 +57,13 @@ Physics.prototype.applyForces = function applyForces(angle) {
   }
 };
 
+Physics.prototype.setTime = function setTime(t) {
+  const angle = ORBIT_SPEED * t * Math.PI / 180.0;
+  for (let i = 0; i < this.obj_list.length; i += 1) {
+    const c = this.start_list[i].copy();
+    rotateOnPlane(c, angle);
+    this.obj_list[i].c = c;
+  }
+};
+
 module.exports = Physics;
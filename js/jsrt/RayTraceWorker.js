@@ -38,8 +38,12 @@ function next() {
         raytrace(workUnit);
         break;
 
-      case 'inc':
-        increment(workUnit);
+      case 'time':
+        setTime(workUnit);
+        break;
+
+      case 'edit':
+        edit(workUnit);
         break;
 
       default:
@@ -57,10 +61,21 @@ function raytrace(workUnit) {
   handleNext();
 }
 
-function increment(workUnit) {
-  rt.increment(workUnit.message.angle);
+function setTime(workUnit) {
+  rt.setTime(workUnit.message.t);
+  workUnit.message = {
+    type: 'time_done',
+  };
+  self.postMessage(workUnit.toObject());
+  handleNext();
+}
+
+// The manager waits for every worker to answer an edit, so say it can't be done
+function edit(workUnit) {
   workUnit.message = {
-    type: 'inc_done',
+    type: 'edit_done',
+    method: workUnit.message.method,
+    error: 'the JavaScript renderer has no scene edits',
   };
   self.postMessage(workUnit.toObject());
   handleNext();
@@ -70,7 +85,8 @@ self.addEventListener('message', e => {
   const workUnit = WorkUnit.fromObject(e.data);
   switch (workUnit.message.type) {
     case 'raytrace':
-    case 'inc':
+    case 'time':
+    case 'edit':
       queue.push(workUnit);
       break;
 
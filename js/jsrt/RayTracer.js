@@ -202,6 +202,15 @@ RayTracer.prototype.increment = function increment(angle) {
   this.physics.applyForces(angle);
 };
 
+/**
+ * Place the objects where they are at time t (in seconds).
+ *
+ * @param {number} t - The animation time.
+ */
+RayTracer.prototype.setTime = function setTime(t) {
+  this.physics.setTime(t);
+};
+
 /**
  * Render the scene.  This will update the data object that was provided.
  *
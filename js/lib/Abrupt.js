@@ -27,6 +27,8 @@ class AbruptWorker {
     this.worker = null;
     this.workerUri = workerUri;
     this.remainingWork = 0;
+    this.nextBroadcastId = 1;
+    this.replies = {};
   }
 
   loadWorker() {
@@ -44,12 +46,29 @@ class AbruptWorker {
 
   addHandler(handleWorkerComplete) {
     this.worker.addEventListener('message', msg => {
+      // A reply to send() isn't work done
+      if (msg.data.broadcast) {
+        const { id } = msg.data.broadcast;
+        this.replies[id](msg.data.message);
+        delete this.replies[id];
+        return;
+      }
       this.workComplete();
       return handleWorkerComplete(msg);
     });
     return this;
   }
 
+  // Send a message straight to this worker, outside the work queue.  Resolves
+  // with its reply.
+  send(message) {
+    const id = this.nextBroadcastId++;
+    this.worker.postMessage({ message, broadcast: { id } });
+    return new Promise(resolve => {
+      this.replies[id] = resolve;
+    });
+  }
+
   addWork(workUnit) {
     if (this.remainingWork + 1.0 > MAX_WORK_UNIT_QUEUE) {
       throw new Error('Cannot handle more than one unit of work');
@@ -106,6 +125,13 @@ class Abrupt {
     );
   }
 
+  // Send a message to every worker exactly once.  Resolves with their replies
+  // once they have all replied.  A worker handles it after the work it was
+  // given before, and before any it's given after.
+  broadcast(message) {
+    return Promise.all(this.workers.map(worker => worker.send(message)));
+  }
+
   addWorkUnits(units) {
     if (!(units instanceof Array)) {
       throw new Error('addWorkUnits() expects an array as the parameter');
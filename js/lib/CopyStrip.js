@@ -0,0 +1,59 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+'use strict';
+
+const constants = require('../common/Constants');
+
+/**
+ * Copy a strip a worker rendered into `data`, the RGBA pixels of an image.
+ * The wasm workers only render the squares that changed and list them in
+ * `squares`, the rest comes from `previous` (the pixels of the image
+ * rendered before, or null if that was this one).  The JavaScript workers
+ * render every square and leave `squares` out.
+ *
+ * @param {Uint8ClampedArray} data - The image to copy the strip into.
+ * @param {?Uint8ClampedArray} previous - The image to copy unchanged squares from.
+ * @param {object} update - The stripId, buffer and squares the worker sent.
+ */
+function copyStrip(data, previous, { stripId, buffer, squares }) {
+  const startPnt = stripId * buffer.byteLength;
+  const strip = new Uint8ClampedArray(buffer);
+  if (!squares) {
+    data.set(strip, startPnt);
+    return;
+  }
+  if (previous !== null) {
+    data.set(previous.subarray(startPnt, startPnt + buffer.byteLength), startPnt);
+  }
+  const rowBytes = constants.WIDTH * 4;
+  const squareBytes = constants.SQUARE_SIZE * 4;
+  squares.forEach(square => {
+    for (let row = 0; row < constants.SQUARE_SIZE; row += 1) {
+      const start = row * rowBytes + square * squareBytes;
+      data.set(strip.subarray(start, start + squareBytes), startPnt + start);
+    }
+  });
+}
+
+module.exports = copyStrip;
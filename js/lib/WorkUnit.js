@@ -12,6 +12,10 @@ class WorkUnit {
     };
   }
   static fromObject(obj) {
+    // Sent to every worker by Abrupt.broadcast(), and replied to the same way
+    if (obj.broadcast) {
+      return new Broadcast(obj.message, obj.broadcast.id);
+    }
     return new WorkUnit(obj.message, obj.abrupt.workUnitId);
   }
   supersededBy(workUnit) {
@@ -20,6 +24,19 @@ class WorkUnit {
   }
 }
 
+class Broadcast {
+  constructor(message, id) {
+    this.message = message;
+    this.id = id;
+  }
+  toObject() {
+    return {
+      message: this.message,
+      broadcast: { id: this.id }
+    };
+  }
+}
+
 if (module.exports) {
   module.exports = WorkUnit;
 }
@@ -0,0 +1,59 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+'use strict';
+
+// Run with `node js/test/CopyStrip.test.js`, build.sh does.
+
+const assert = require('assert');
+const constants = require('../common/Constants');
+const copyStrip = require('../lib/CopyStrip');
+
+const stripBytes = constants.WIDTH * constants.SQUARE_SIZE * 4;
+const imageBytes = constants.WIDTH * constants.HEIGHT * 4;
+
+function strip(value) {
+  return new Uint8ClampedArray(stripBytes).fill(value).buffer;
+}
+
+// A JavaScript worker sends the whole strip, without squares
+const data = new Uint8ClampedArray(imageBytes);
+copyStrip(data, null, { stripId: 2, buffer: strip(7) });
+assert.ok(data.subarray(2 * stripBytes, 3 * stripBytes).every(b => b === 7));
+assert.ok(data.subarray(0, 2 * stripBytes).every(b => b === 0));
+assert.ok(data.subarray(3 * stripBytes).every(b => b === 0));
+
+// A wasm worker sends the squares it rendered, the rest comes from the last image
+const previous = new Uint8ClampedArray(imageBytes).fill(1);
+copyStrip(data, previous, { stripId: 2, buffer: strip(9), squares: [3] });
+const start = 2 * stripBytes;
+const squareBytes = constants.SQUARE_SIZE * 4;
+for (let row = 0; row < constants.SQUARE_SIZE; row += 1) {
+  const rowStart = start + row * constants.WIDTH * 4;
+  for (let i = 0; i < constants.WIDTH * 4; i += 1) {
+    const inSquare = i >= 3 * squareBytes && i < 4 * squareBytes;
+    assert.strictEqual(data[rowStart + i], inSquare ? 9 : 1);
+  }
+}
+
+console.log('copyStrip: ok');
@@ -28,6 +28,23 @@ require('./rust_web_rtrt.js');
 const constants = require('../common/Constants');
 const WorkUnit = require('../lib/WorkUnit');
 
+// The RayTracer methods a scene edit may call
+const EDIT_METHODS = [
+  'add_sphere',
+  'add_disc',
+  'add_mesh_file',
+  'add_point_light',
+  'add_directional_light',
+  'add_spot_light',
+  'set_object_position',
+  'set_object_colour',
+  'set_object_material',
+  'remove_object',
+  'remove_light',
+  'set_denoise',
+  'set_post_process',
+];
+
 // not ordinarily necessary, but for streaming WASM compilation to
 // work it needs to be served with a content-type of application/wasm,
 // which isn't always the case (eg with php -S), so we remove for now:
@@ -35,8 +52,9 @@ delete WebAssembly.instantiateStreaming;
 
 rayTracer('./rust_web_rtrt_bg.wasm').then(
   () => {
-    const { RayTracer, wasm } = rayTracer;
-    const rt = new RayTracer(12);
+    const { RayTracer } = rayTracer;
+    const scene = new URLSearchParams(self.location.search).get('scene') || 'default';
+    const rt = RayTracer.with_scene_name(12, scene);
 
     const queue = [];
 
@@ -48,8 +66,16 @@ rayTracer('./rust_web_rtrt_bg.wasm').then(
             raytrace(workUnit);
             break;
 
-          case 'inc':
-            increment(workUnit);
+          case 'frame':
+            renderFrame(workUnit);
+            break;
+
+          case 'time':
+            setTime(workUnit);
+            break;
+
+          case 'edit':
+            edit(workUnit);
             break;
 
           default:
@@ -63,15 +89,53 @@ rayTracer('./rust_web_rtrt_bg.wasm').then(
 
     function raytrace(workUnit) {
       workUnit.message.buffer = new Uint8Array(constants.SQUARE_SIZE * constants.WIDTH * 4);
-      rt.render(workUnit.message.stripId, workUnit.message.buffer);
+      // Only the squares that changed since the last frame are rendered
+      workUnit.message.squares = Array.from(rt.render_changed(workUnit.message.stripId, workUnit.message.buffer));
+      self.postMessage(workUnit.toObject(), [workUnit.message.buffer.buffer]);
+      handleNext();
+    }
+
+    // With the denoiser or post-processing on the whole image is rendered in one go
+    function renderFrame(workUnit) {
+      workUnit.message.buffer = new Uint8Array(constants.WIDTH * constants.HEIGHT * 4);
+      rt.render_frame(workUnit.message.buffer);
       self.postMessage(workUnit.toObject(), [workUnit.message.buffer.buffer]);
       handleNext();
     }
 
-    function increment(workUnit) {
-      rt.increment(workUnit.message.angle);
+    function setTime(workUnit) {
+      // Each worker is sent the time once a frame.  After a big jump the
+      // physics catches up over a few frames, the same way in every worker.
+      rt.catch_up_to(workUnit.message.t);
+      rt.begin_frame();
+      workUnit.message = {
+        type: 'time_done',
+        // A scene file or an edit may have turned on whole frame filters
+        wholeFrame: rt.filters_frames(),
+      };
+      self.postMessage(workUnit.toObject());
+      handleNext();
+    }
+
+    function edit(workUnit) {
+      const { method, args } = workUnit.message;
+      let result;
+      let error;
+      if (EDIT_METHODS.includes(method)) {
+        // A bad edit throws, and leaves the scene as it was
+        try {
+          result = rt[method](...args);
+        } catch (e) {
+          error = e.message;
+        }
+      } else {
+        error = `Unknown scene edit: ${method}`;
+      }
       workUnit.message = {
-        type: 'inc_done',
+        type: 'edit_done',
+        method,
+        result,
+        error,
       };
       self.postMessage(workUnit.toObject());
       handleNext();
@@ -82,7 +146,9 @@ rayTracer('./rust_web_rtrt_bg.wasm').then(
       const workUnit = WorkUnit.fromObject(e.data);
       switch (workUnit.message.type) {
         case 'raytrace':
-        case 'inc':
+        case 'frame':
+        case 'time':
+        case 'edit':
           queue.push(workUnit);
           break;
 
@@ -0,0 +1,107 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+/* globals self rayTracer WebAssembly */
+
+//
+// Worker for the shared memory (wasm threads) build.  The first worker
+// creates the SharedRayTracer and hands the module and memory to the
+// others, then every worker renders strips straight into the shared
+// framebuffer.
+//
+
+require('./rust_web_rtrt.js');
+
+delete WebAssembly.instantiateStreaming;
+
+let srt = null; // Only the owner holds the SharedRayTracer
+let handle = 0;
+
+function owner(depth, scene) {
+  rayTracer('./rust_web_rtrt_bg.wasm').then(
+    () => {
+      srt = rayTracer.SharedRayTracer.with_scene_name(depth, scene);
+      // Anti-alias, and converge over the frames even while things move
+      srt.set_temporal(true);
+      handle = srt.handle();
+      self.postMessage({
+        type: 'ready',
+        module: rayTracer.__wbindgen_wasm_module,
+        memory: rayTracer.wasm.memory,
+        handle,
+        framePtr: srt.frame_ptr(),
+        frameLen: srt.frame_len(),
+      });
+    },
+    _ => {
+      console.error('Error starting worker');
+    }
+  );
+}
+
+function helper(module, memory, ownerHandle) {
+  rayTracer(module, memory).then(
+    () => {
+      handle = ownerHandle;
+      self.postMessage({ type: 'ready' });
+    },
+    _ => {
+      console.error('Error starting worker');
+    }
+  );
+}
+
+self.addEventListener('message', e => {
+  const message = e.data;
+  switch (message.type) {
+    case 'init_owner':
+      owner(message.depth, message.scene);
+      break;
+
+    case 'init_helper':
+      helper(message.module, message.memory, message.handle);
+      break;
+
+    case 'time':
+      // Only ever sent to the owner, between frames.
+      // After a big jump the physics catches up over a few frames
+      srt.catch_up_to(message.t);
+      srt.begin_frame();
+      self.postMessage({ type: 'time_done' });
+      break;
+
+    case 'raytrace':
+      rayTracer.shared_render_strips(handle);
+      self.postMessage({ type: 'raytrace_done' });
+      break;
+
+    case 'end_frame':
+      // Only ever sent to the owner, once every strip is done.
+      srt.end_frame();
+      self.postMessage({ type: 'end_frame_done' });
+      break;
+
+    default:
+      console.error('Unexpected value: ', message);
+  }
+});
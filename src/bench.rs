@@ -20,39 +20,314 @@
  *   under the License.                                              *
  *                                                                   *
  *********************************************************************/
-extern crate time;
 extern crate wasm_bindgen;
 
+pub mod animation;
 pub mod constants;
+pub mod csg;
+pub mod denoise;
+pub mod dirty;
+pub mod error;
+pub mod gltf;
+pub mod json;
+pub mod mesh;
+pub mod mesh_file;
 pub mod objects;
 mod physics;
+pub mod post;
 pub mod raytracer;
+pub mod scene_file;
+pub mod scenes;
+pub mod sdf;
+pub mod stats;
+pub mod temporal;
+pub mod transform;
 pub mod vector;
+pub mod volume;
 
-use time::PreciseTime;
+//
+// Benchmark the ray tracer on one CPU.
+//
+// Usage: bench [--json] [--warmup N] [--iterations N] [--scene NAME]
+//
+// Each scene is rendered `warmup` times untimed and then `iterations` times
+// timed.  The median and 95th percentile frame times are reported, along
+// with the number of primary rays traced per second.  Use `--json` to get
+// output that can be saved and compared between commits.  Build with
+// `--features stats` to also get the ray and intersection counts per frame
+// and per strip.
+//
+
+use std::env;
+use std::process;
+use std::time::Instant;
 
 use crate::constants::*;
+use crate::objects::Scene;
 use crate::raytracer::RayTracer;
+use crate::stats::RayStats;
 
-fn main() {
-  let start = PreciseTime::now();
-  // whatever you want to do
-  let mut rt = RayTracer::new(5);
-  let mut strip_data = vec![0; NUM_COLS * SQUARE_SIZE];
-  const NUM_FRAMES: usize = 20;
-  for _ in 0..NUM_FRAMES {
-    for i in 0..NUM_STRIPS {
-      rt.render(i as u32, &mut strip_data);
+struct BenchScene {
+  name: &'static str,
+  depth: i32,
+  build: fn() -> Scene,
+}
+
+const BENCH_SCENES: [BenchScene; 4] = [
+  BenchScene {
+    name: "default",
+    depth: 5,
+    build: scenes::default_scene,
+  },
+  BenchScene {
+    name: "many_spheres",
+    depth: 5,
+    build: many_spheres,
+  },
+  BenchScene {
+    name: "meshes",
+    depth: 5,
+    build: meshes,
+  },
+  BenchScene {
+    name: "deep_recursion",
+    depth: 32,
+    build: scenes::default_scene,
+  },
+];
+
+fn many_spheres() -> Scene {
+  scenes::many_spheres(10)
+}
+
+fn meshes() -> Scene {
+  scenes::instances(6)
+}
+
+struct Options {
+  json: bool,
+  warmup: usize,
+  iterations: usize,
+  scene: Option<String>,
+}
+
+struct BenchResult {
+  name: &'static str,
+  depth: i32,
+  num_objects: usize,
+  frame_ms: Vec<f64>, // sorted
+  primary_rays: usize,
+  strip_stats: Vec<RayStats>, // of the last frame
+}
+
+impl BenchResult {
+  // Nearest-rank percentile of the frame times
+  fn percentile(&self, p: f64) -> f64 {
+    let rank = (p / 100.0 * self.frame_ms.len() as f64).ceil() as usize;
+    self.frame_ms[rank.max(1) - 1]
+  }
+
+  fn median(&self) -> f64 {
+    self.percentile(50.0)
+  }
+
+  fn mean(&self) -> f64 {
+    self.frame_ms.iter().sum::<f64>() / self.frame_ms.len() as f64
+  }
+
+  fn rays_per_sec(&self) -> f64 {
+    self.primary_rays as f64 / (self.median() / 1000.0)
+  }
+
+  fn frame_stats(&self) -> RayStats {
+    let mut stats = RayStats::default();
+    for s in &self.strip_stats {
+      stats.add_in_place(s);
     }
+    stats
   }
-  let end = PreciseTime::now();
-  let spf = start.to(end).num_milliseconds() as f64 / 1000.0 / (NUM_FRAMES as f64);
-  let fps = 1.0 / spf;
 
+  // The strip that fired the most rays
+  fn heaviest_strip(&self) -> (usize, RayStats) {
+    self
+      .strip_stats
+      .iter()
+      .copied()
+      .enumerate()
+      .max_by_key(|(_, s)| s.total_rays())
+      .unwrap_or_default()
+  }
+}
+
+fn parse_args() -> Result<Options, String> {
+  let mut opts = Options {
+    json: false,
+    warmup: 3,
+    iterations: 20,
+    scene: None,
+  };
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--json" => opts.json = true,
+      "--warmup" | "--iterations" | "--scene" => {
+        let val = args.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+          "--scene" => opts.scene = Some(val),
+          _ => {
+            let n = val
+              .parse::<usize>()
+              .map_err(|_| format!("{} expects a number, got '{}'", arg, val))?;
+            if arg == "--warmup" {
+              opts.warmup = n;
+            } else {
+              opts.iterations = n.max(1);
+            }
+          }
+        }
+      }
+      _ => return Err(format!("unknown argument '{}'", arg)),
+    }
+  }
+  Ok(opts)
+}
+
+// Render one whole frame, returns the number of primary rays traced.
+fn render_frame(rt: &RayTracer, strip_data: &mut [u8], strip_stats: &mut Vec<RayStats>) -> usize {
+  strip_stats.clear();
+  (0..NUM_STRIPS)
+    .map(|i| {
+      let rays = rt.render_strip(i as u32, strip_data).unwrap();
+      strip_stats.push(rt.take_stats());
+      rays
+    })
+    .sum()
+}
+
+fn run(bench: &BenchScene, opts: &Options) -> BenchResult {
+  let scene = (bench.build)();
+  let num_objects = scene.objs.len();
+  let rt = RayTracer::with_scene(bench.depth, scene);
+  let mut strip_data = vec![0; NUM_COLS * SQUARE_SIZE * 4];
+
+  let mut strip_stats = Vec::with_capacity(NUM_STRIPS);
+
+  for _ in 0..opts.warmup {
+    render_frame(&rt, &mut strip_data, &mut strip_stats);
+  }
+
+  let mut frame_ms = Vec::with_capacity(opts.iterations);
+  let mut primary_rays = 0;
+  for _ in 0..opts.iterations {
+    let start = Instant::now();
+    primary_rays = render_frame(&rt, &mut strip_data, &mut strip_stats);
+    frame_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+  }
+  frame_ms.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+  BenchResult {
+    name: bench.name,
+    depth: bench.depth,
+    num_objects,
+    frame_ms,
+    primary_rays,
+    strip_stats,
+  }
+}
+
+fn print_table(results: &[BenchResult]) {
   println!(
-    "Rendered {} frames in {} seconds on one CPU.  Hence it's running at {} fps",
-    NUM_FRAMES,
-    start.to(end).num_milliseconds() as f64 / 1000.0,
-    fps
+    "{:<16} {:>5} {:>7} {:>11} {:>11} {:>11} {:>8} {:>14}",
+    "scene", "depth", "objects", "median ms", "p95 ms", "mean ms", "fps", "rays/s"
   );
+  for r in results {
+    println!(
+      "{:<16} {:>5} {:>7} {:>11.3} {:>11.3} {:>11.3} {:>8.2} {:>14.0}",
+      r.name,
+      r.depth,
+      r.num_objects,
+      r.median(),
+      r.percentile(95.0),
+      r.mean(),
+      1000.0 / r.median(),
+      r.rays_per_sec()
+    );
+  }
+
+  if cfg!(feature = "stats") {
+    println!();
+    for r in results {
+      let (strip_id, strip) = r.heaviest_strip();
+      println!("{}", r.name);
+      println!("  per frame:  {}", r.frame_stats());
+      println!("  per strip:  {} (heaviest, strip {})", strip, strip_id);
+    }
+  }
+}
+
+fn print_json(results: &[BenchResult], opts: &Options) {
+  println!("{{");
+  println!("  \"version\": \"{}\",", env!("CARGO_PKG_VERSION"));
+  println!("  \"width\": {},", NUM_COLS);
+  println!("  \"height\": {},", NUM_ROWS);
+  println!("  \"warmup\": {},", opts.warmup);
+  println!("  \"iterations\": {},", opts.iterations);
+  println!("  \"scenes\": [");
+  for (i, r) in results.iter().enumerate() {
+    println!("    {{");
+    println!("      \"name\": \"{}\",", r.name);
+    println!("      \"depth\": {},", r.depth);
+    println!("      \"objects\": {},", r.num_objects);
+    println!("      \"median_ms\": {:.4},", r.median());
+    println!("      \"p95_ms\": {:.4},", r.percentile(95.0));
+    println!("      \"mean_ms\": {:.4},", r.mean());
+    println!("      \"min_ms\": {:.4},", r.frame_ms[0]);
+    println!("      \"max_ms\": {:.4},", r.frame_ms[r.frame_ms.len() - 1]);
+    println!("      \"primary_rays_per_frame\": {},", r.primary_rays);
+    if cfg!(feature = "stats") {
+      println!("      \"rays_per_sec\": {:.0},", r.rays_per_sec());
+      let stats = r.frame_stats();
+      println!("      \"total_rays_per_sec\": {:.0},", stats.total_rays() as f64 / (r.median() / 1000.0));
+      println!("      \"frame_stats\": {},", stats.to_json());
+      println!("      \"strip_stats\": [");
+      for (j, s) in r.strip_stats.iter().enumerate() {
+        let sep = if j + 1 < r.strip_stats.len() { "," } else { "" };
+        println!("        {}{}", s.to_json(), sep);
+      }
+      println!("      ]");
+    } else {
+      println!("      \"rays_per_sec\": {:.0}", r.rays_per_sec());
+    }
+    println!("    }}{}", if i + 1 < results.len() { "," } else { "" });
+  }
+  println!("  ]");
+  println!("}}");
+}
+
+fn main() {
+  let opts = match parse_args() {
+    Ok(opts) => opts,
+    Err(msg) => {
+      eprintln!("bench: {}", msg);
+      eprintln!("Usage: bench [--json] [--warmup N] [--iterations N] [--scene NAME]");
+      process::exit(2);
+    }
+  };
+
+  let selected: Vec<&BenchScene> = BENCH_SCENES
+    .iter()
+    .filter(|b| opts.scene.as_ref().is_none_or(|name| name == b.name))
+    .collect();
+  if selected.is_empty() {
+    let names: Vec<&str> = BENCH_SCENES.iter().map(|b| b.name).collect();
+    eprintln!("bench: unknown scene, choose one of: {}", names.join(", "));
+    process::exit(2);
+  }
+
+  let results: Vec<BenchResult> = selected.iter().map(|b| run(b, &opts)).collect();
+  if opts.json {
+    print_json(&results, &opts);
+  } else {
+    print_table(&results);
+  }
 }
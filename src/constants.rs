@@ -21,6 +21,8 @@
  *                                                                   *
  *********************************************************************/
 
+#![allow(clippy::legacy_numeric_constants)]
+
 use crate::vector::Vector as Colour;
 use crate::vector::Vector;
 use std::usize;
@@ -28,6 +30,9 @@ use std::usize;
 // Used to make sure we are on the near side of point of intersection
 pub const EPSILON: f64 = 0.00001;
 
+// How much brighter light focused through glass gets, when caustics are on
+pub const CAUSTIC_GAIN: f64 = 1.5;
+
 // How big a grid size to use for checking contents (in pixels)
 pub const SQUARE_SIZE: usize = 8;
 
@@ -0,0 +1,289 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Constructive solid geometry.  A CSG node combines two child objects with
+// union, intersection or difference.  The children must be solids: spheres,
+// closed meshes or other CSG nodes.  Discs have no inside and implicit
+// surfaces aren't supported yet, so they add nothing.
+//
+// To combine children we need every stretch of the ray that is inside each
+// of them (its spans), not just the nearest hit.
+
+use crate::objects::{Hit, Object, ObjectType, Ray};
+use crate::vector::Vector;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsgOp {
+  Union,
+  Intersection,
+  Difference, // left minus right
+}
+
+#[derive(Clone)]
+pub struct Csg {
+  pub op: CsgOp,
+  pub left: Object,
+  pub right: Object,
+}
+
+// A stretch of the ray inside a solid, from `enter` to `exit`.  The normals
+// of both hits point out of the solid.  Their `pi` is left in the space of
+// the child, intersect_csg() works out the world position of the one it
+// returns.
+#[derive(Clone, Copy)]
+pub struct Span {
+  pub enter: Hit,
+  pub exit: Hit,
+}
+
+/**
+ * The spans of `obj` along the world `ray`, sorted and not overlapping.  The
+ * `t` values match the ray, and may be behind its origin.
+ */
+pub fn spans(obj: &Object, ray: &Ray) -> Vec<Span> {
+  match &obj.transform {
+    None => spans_local(obj, ray),
+    Some(transform) => {
+      let mut spans = spans_local(obj, &transform.ray_to_object(ray));
+      for span in &mut spans {
+        for hit in [&mut span.enter, &mut span.exit] {
+          hit.geom_norm = transform.normal_to_world(&hit.geom_norm);
+          hit.norm = transform.normal_to_world(&hit.norm);
+        }
+      }
+      spans
+    }
+  }
+}
+
+// Spans of a ray already in object space.
+fn spans_local(obj: &Object, ray: &Ray) -> Vec<Span> {
+  match obj.typ {
+    ObjectType::Sphere => sphere_spans(obj, ray).into_iter().collect(),
+    ObjectType::Disc | ObjectType::Sdf => vec![],
+    ObjectType::Mesh => mesh_spans(obj, ray),
+    ObjectType::Csg => match &obj.csg {
+      Some(csg) => {
+        let local_ray = Ray::new(ray.origin.sub(&obj.c), ray.direction);
+        combine(csg.op, &spans(&csg.left, &local_ray), &spans(&csg.right, &local_ray))
+      }
+      None => vec![],
+    },
+  }
+}
+
+fn sphere_spans(obj: &Object, ray: &Ray) -> Option<Span> {
+  let local_p1 = ray.origin.sub(&obj.c);
+  let a = ray.direction.dot(&ray.direction);
+  let b = 2.0 * ray.direction.dot(&local_p1);
+  let c = local_p1.dot(&local_p1) - obj.radius * obj.radius;
+  let d = b * b - 4.0 * a * c;
+  if d <= 0.0 {
+    return None;
+  }
+  let sqrt_d = d.sqrt();
+  let hit = |t: f64| {
+    let norm = ray.origin.add(&ray.direction.scale(t)).sub(&obj.c).normalise();
+    Hit::new(ray, t, norm, norm, obj.col, 0.0, 0.0)
+  };
+  Some(Span {
+    enter: hit((-b - sqrt_d) / (2.0 * a)),
+    exit: hit((-b + sqrt_d) / (2.0 * a)),
+  })
+}
+
+// A closed mesh: pair each hit on a front face with the next back face.
+fn mesh_spans(obj: &Object, ray: &Ray) -> Vec<Span> {
+  let mesh = match &obj.mesh {
+    Some(mesh) => mesh,
+    None => return vec![],
+  };
+  // Hits behind the origin count too, so start the ray far back
+  let back = mesh.bounds().max.sub(&mesh.bounds().min).length() + ray.origin.sub(&obj.c).length();
+  let back = back / ray.direction.length();
+  let start = ray.origin.sub(&obj.c).sub(&ray.direction.scale(back));
+  let local_ray = Ray::new(start, ray.direction);
+
+  let mut spans = vec![];
+  let mut enter: Option<Hit> = None;
+  for mesh_hit in mesh.intersect_all(&local_ray) {
+    let geom_norm = mesh.face_normal(mesh_hit.triangle);
+    let hit = Hit::new(
+      ray,
+      mesh_hit.t - back,
+      geom_norm,
+      mesh.normal(&mesh_hit),
+      obj.col,
+      mesh_hit.u,
+      mesh_hit.v,
+    );
+    let entering = geom_norm.dot(&ray.direction) < 0.0;
+    match (entering, enter) {
+      (true, None) => enter = Some(hit),
+      (false, Some(e)) => {
+        spans.push(Span { enter: e, exit: hit });
+        enter = None;
+      }
+      _ => {}
+    }
+  }
+  spans
+}
+
+/**
+ * Combine two sorted span lists.  Walk along the ray through every boundary,
+ * keeping track of whether we're inside each side.
+ */
+pub fn combine(op: CsgOp, left: &[Span], right: &[Span]) -> Vec<Span> {
+  // (hit, from the left?, entering?)
+  let mut events: Vec<(Hit, bool, bool)> = vec![];
+  for span in left {
+    events.push((span.enter, true, true));
+    events.push((span.exit, true, false));
+  }
+  for span in right {
+    events.push((span.enter, false, true));
+    events.push((span.exit, false, false));
+  }
+  events.sort_by(|a, b| a.0.t.total_cmp(&b.0.t));
+
+  let inside = |in_left: bool, in_right: bool| match op {
+    CsgOp::Union => in_left || in_right,
+    CsgOp::Intersection => in_left && in_right,
+    CsgOp::Difference => in_left && !in_right,
+  };
+
+  let mut spans = vec![];
+  let (mut in_left, mut in_right) = (false, false);
+  let mut enter: Option<Hit> = None;
+  for (mut hit, from_left, entering) in events {
+    let was_inside = inside(in_left, in_right);
+    if from_left {
+      in_left = entering;
+    } else {
+      in_right = entering;
+    }
+    let is_inside = inside(in_left, in_right);
+    if was_inside == is_inside {
+      continue;
+    }
+    // The surface of a subtracted solid faces the other way
+    if op == CsgOp::Difference && !from_left {
+      hit.geom_norm = hit.geom_norm.scale(-1.0);
+      hit.norm = hit.norm.scale(-1.0);
+    }
+    if is_inside {
+      enter = Some(hit);
+    } else if let Some(e) = enter.take() {
+      spans.push(Span { enter: e, exit: hit });
+    }
+  }
+  spans
+}
+
+/**
+ * The first surface of a CSG node in front of the ray origin.
+ */
+pub fn intersect_csg(csg: &Csg, csg_c: &Vector, ray: &Ray) -> Option<Hit> {
+  let local_ray = Ray::new(ray.origin.sub(csg_c), ray.direction);
+  let spans = combine(csg.op, &spans(&csg.left, &local_ray), &spans(&csg.right, &local_ray));
+  spans
+    .iter()
+    .flat_map(|span| [span.enter, span.exit])
+    .find(|hit| hit.t > 0.0)
+    .map(|hit| {
+      Hit {
+        pi: ray.origin.add(&ray.direction.scale(hit.t)),
+        ..hit
+      }
+      .face(ray)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::COL_WHITE;
+  use crate::csg::{combine, spans, CsgOp};
+  use crate::objects::{Object, Ray};
+  use crate::vector::Vector;
+
+  fn sphere(x: f64) -> Object {
+    Object::new_sphere(Vector::new(x, 0.0, 0.0), 1.0, COL_WHITE, 0.0, 0.0, 0.2)
+  }
+
+  // A ray along the x axis, from x = -10
+  fn x_ray() -> Ray {
+    Ray::new(Vector::new(-10.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0))
+  }
+
+  fn ts(op: CsgOp) -> Vec<(f64, f64)> {
+    let ray = x_ray();
+    combine(op, &spans(&sphere(0.0), &ray), &spans(&sphere(1.0), &ray))
+      .iter()
+      .map(|s| (s.enter.t - 10.0, s.exit.t - 10.0))
+      .collect()
+  }
+
+  #[test]
+  fn csg_union() {
+    assert_eq!(ts(CsgOp::Union), vec![(-1.0, 2.0)]);
+  }
+
+  #[test]
+  fn csg_intersection() {
+    assert_eq!(ts(CsgOp::Intersection), vec![(0.0, 1.0)]);
+  }
+
+  #[test]
+  fn csg_difference() {
+    assert_eq!(ts(CsgOp::Difference), vec![(-1.0, 0.0)]);
+    // The cut face looks back along the ray
+    let ray = x_ray();
+    let s = combine(CsgOp::Difference, &spans(&sphere(0.0), &ray), &spans(&sphere(1.0), &ray));
+    assert!(s[0].exit.norm.equals(&Vector::new(1.0, 0.0, 0.0)));
+  }
+
+  #[test]
+  fn csg_mesh_spans() {
+    use crate::mesh::Mesh;
+    use std::sync::Arc;
+    let torus = Object::new_mesh(Arc::new(Mesh::torus(2.0, 0.5, 48, 24)), Vector::new(0.0, 0.0, 0.0), COL_WHITE, 0.0, 0.0, 0.2);
+    let s = spans(&torus, &x_ray());
+    assert_eq!(s.len(), 2);
+    assert!((s[0].enter.t - 7.5).abs() < 0.05 && (s[0].exit.t - 8.5).abs() < 0.05);
+    assert!((s[1].enter.t - 11.5).abs() < 0.05 && (s[1].exit.t - 12.5).abs() < 0.05);
+  }
+
+  #[test]
+  fn csg_hits_hollow_shell() {
+    // A ball with a hole right through it, seen from inside the hole.
+    let mut inner = sphere(0.0);
+    inner.radius = 0.5;
+    let obj = Object::new_csg(CsgOp::Difference, sphere(0.0), inner, Vector::new(0.0, 0.0, 0.0));
+    let ray = Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let hit = obj.intersect(&ray).expect("missed the shell");
+    assert!((hit.t - 0.5).abs() < 1e-9);
+    assert!(hit.norm.equals(&Vector::new(-1.0, 0.0, 0.0)));
+    assert!(hit.front_face);
+  }
+}
@@ -0,0 +1,178 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+//
+// Edge-aware denoising of an HDR frame.
+//
+// An a-trous wavelet filter, as in Dammertz et al., "Edge-Avoiding A-Trous
+// Wavelet Transform for fast Global Illumination Filtering": each pass blurs
+// with a 5x5 B3-spline kernel whose taps are 2^pass pixels apart, so a few
+// cheap passes reach a long way.  Each tap counts for less the more it
+// differs from the middle pixel in colour, albedo, normal and depth, so the
+// blur spreads along a surface but not across the edges between surfaces,
+// or across the edges of a texture.
+//
+
+use crate::constants::NUM_COLS;
+use crate::temporal::{HdrFrame, HdrPixel};
+use crate::vector::Vector as Colour;
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+#[derive(Clone, Copy)]
+pub struct Denoiser {
+  pub passes: u32,       // Each reaches twice as far as the last, 2 pixels for the first
+  pub sigma_colour: f64, // How different a colour can be and still count, halved each pass
+  pub sigma_albedo: f64,
+  pub sigma_normal: f64, // The power of the cosine between normals
+  pub sigma_depth: f64,  // As a fraction of the depth, per pixel apart
+}
+
+impl Default for Denoiser {
+  fn default() -> Denoiser {
+    Denoiser {
+      passes: 4,
+      sigma_colour: 0.5,
+      sigma_albedo: 0.1,
+      sigma_normal: 64.0,
+      sigma_depth: 0.01,
+    }
+  }
+}
+
+impl Denoiser {
+  // Denoise the colours of `frame`.
+  pub fn apply(&self, frame: &mut HdrFrame) {
+    self.filter(&mut frame.pixels, NUM_COLS);
+  }
+
+  // Denoise the colours of an image `width` pixels wide.
+  fn filter(&self, pixels: &mut [HdrPixel], width: usize) {
+    let height = pixels.len() / width;
+    let mut colours: Vec<Colour> = pixels.iter().map(|pxl| pxl.colour).collect();
+    for pass in 0..self.passes {
+      let step = 1 << pass;
+      let sigma_colour = self.sigma_colour / step as f64;
+      let mut filtered = colours.clone();
+      for row in 0..height {
+        for col in 0..width {
+          let i = row * width + col;
+          let mut sum = Colour::new(0.0, 0.0, 0.0);
+          let mut total = 0.0;
+          for (dr, kr) in KERNEL.iter().enumerate() {
+            let r = row as i64 + (dr as i64 - 2) * step;
+            if r < 0 || r >= height as i64 {
+              continue;
+            }
+            for (dc, kc) in KERNEL.iter().enumerate() {
+              let c = col as i64 + (dc as i64 - 2) * step;
+              if c < 0 || c >= width as i64 {
+                continue;
+              }
+              let j = r as usize * width + c as usize;
+              let distance = ((dr as i64 - 2).abs().max((dc as i64 - 2).abs()) * step) as f64;
+              let w = kr * kc * self.weight(&pixels[i], &pixels[j], &colours[i], &colours[j], sigma_colour, distance);
+              sum.add_in_place(&colours[j].scale(w));
+              total += w;
+            }
+          }
+          // The middle tap always counts, so total is never 0
+          filtered[i] = sum.scale(1.0 / total);
+        }
+      }
+      colours = filtered;
+    }
+    for (pxl, colour) in pixels.iter_mut().zip(colours) {
+      pxl.colour = colour;
+    }
+  }
+
+  // How much the tap at `q` counts towards the pixel `p`, from 0 to 1.
+  fn weight(&self, p: &HdrPixel, q: &HdrPixel, p_colour: &Colour, q_colour: &Colour, sigma_colour: f64, distance: f64) -> f64 {
+    // Nothing was hit at one but not the other
+    if p.depth.is_infinite() || q.depth.is_infinite() {
+      return if p.depth.is_infinite() && q.depth.is_infinite() { 1.0 } else { 0.0 };
+    }
+    let colour = p_colour.sub(q_colour);
+    let albedo = p.albedo.sub(&q.albedo);
+    let depth = (p.depth - q.depth).abs() / (self.sigma_depth * p.depth * distance.max(1.0));
+    let normal = p.normal.dot(&q.normal).max(0.0).powf(self.sigma_normal);
+    let e = colour.dot(&colour) / (sigma_colour * sigma_colour) + albedo.dot(&albedo) / (self.sigma_albedo * self.sigma_albedo) + depth;
+    (-e).exp() * normal
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::denoise::Denoiser;
+  use crate::temporal::HdrPixel;
+  use crate::vector::Vector;
+  use crate::vector::Vector as Colour;
+
+  const SIZE: usize = 16;
+
+  // The left half of the image a grey wall facing the eye, the right half a
+  // white one further away, with a little noise.
+  fn two_walls() -> Vec<HdrPixel> {
+    (0..SIZE * SIZE)
+      .map(|i| {
+        let noise = if (i * 7) % 3 == 0 { 0.05 } else { -0.025 };
+        let (albedo, depth) = if i % SIZE < SIZE / 2 {
+          (Colour::new(0.5, 0.5, 0.5), 10.0)
+        } else {
+          (Colour::new(1.0, 1.0, 1.0), 12.0)
+        };
+        HdrPixel {
+          colour: albedo.scale(0.6 + noise),
+          obj_id: 0,
+          motion: None,
+          albedo,
+          normal: Vector::new(0.0, 0.0, -1.0),
+          depth,
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn denoise_keeps_edges() {
+    let mut pixels = two_walls();
+    Denoiser::default().filter(&mut pixels, SIZE);
+
+    // The noise is smoothed out on each wall
+    for (i, pxl) in pixels.iter().enumerate() {
+      let expected = if i % SIZE < SIZE / 2 { 0.3 } else { 0.6 };
+      assert!((pxl.colour.x - expected).abs() < 0.01, "{} at {}", pxl.colour.x, i);
+    }
+  }
+
+  #[test]
+  fn denoise_without_passes_does_nothing() {
+    let mut pixels = two_walls();
+    let denoiser = Denoiser {
+      passes: 0,
+      ..Denoiser::default()
+    };
+    denoiser.filter(&mut pixels, SIZE);
+    assert!(pixels.iter().zip(two_walls()).all(|(a, b)| a.colour.equals(&b.colour)));
+  }
+}
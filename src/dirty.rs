@@ -0,0 +1,353 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Which squares of the image can have changed since the last frame, so only
+// those need rendering again.
+//
+// Anything a moved object could touch is covered: where it was and is now,
+// the shadows it casts then and now from every light and glowing object, its
+// reflection in a mirror disc, and anything curved that reflects or refracts
+// at all.  Each of these is a box in world space, put on the screen as the
+// box of pixels around it.  Shadows are cut down to the box of everything
+// that can receive them.  When the eye or the lights move,
+// something glowing moves, or there's fog, the whole image is redrawn.
+
+use crate::constants::{NUM_COLS, NUM_ROWS, SQUARE_SIZE};
+use crate::mesh::Aabb;
+use crate::objects::*;
+use crate::transform::Transform;
+use crate::vector::Vector;
+
+const TILE_COLS: usize = NUM_COLS / SQUARE_SIZE;
+const TILE_ROWS: usize = NUM_ROWS / SQUARE_SIZE;
+
+// A sphere, (centre, radius).
+type Ball = (Vector, f64);
+
+// One flag for each SQUARE_SIZE square of the image, by row then column.
+pub struct DirtyTiles {
+  tiles: Vec<bool>,
+}
+
+impl DirtyTiles {
+  pub fn all() -> DirtyTiles {
+    DirtyTiles {
+      tiles: vec![true; TILE_COLS * TILE_ROWS],
+    }
+  }
+
+  pub fn none() -> DirtyTiles {
+    DirtyTiles {
+      tiles: vec![false; TILE_COLS * TILE_ROWS],
+    }
+  }
+
+  pub fn is_dirty(&self, tile_row: usize, tile_col: usize) -> bool {
+    self.tiles[tile_row * TILE_COLS + tile_col]
+  }
+
+  pub fn count(&self) -> usize {
+    self.tiles.iter().filter(|&&t| t).count()
+  }
+
+  // Mark the squares under a box of pixels, [col_min, row_min, col_max, row_max].
+  fn mark(&mut self, rect: [f64; 4]) {
+    let tile = |v: f64, count: usize| (v / SQUARE_SIZE as f64).floor().clamp(0.0, count as f64 - 1.0) as usize;
+    if rect[2] < 0.0 || rect[3] < 0.0 || rect[0] >= NUM_COLS as f64 || rect[1] >= NUM_ROWS as f64 {
+      return;
+    }
+    for row in tile(rect[1], TILE_ROWS)..=tile(rect[3], TILE_ROWS) {
+      for col in tile(rect[0], TILE_COLS)..=tile(rect[2], TILE_COLS) {
+        self.tiles[row * TILE_COLS + col] = true;
+      }
+    }
+  }
+}
+
+/**
+ * What the last frame looked like, enough to tell what has changed since.
+ */
+pub struct Snapshot {
+  view: Eye,
+  eye: Vec<f64>,
+  lights: Vec<Vec<f64>>,
+  objects: Vec<(Vec<f64>, Aabb)>, // Where each object is and its bounds
+  placements: Vec<(Vector, Option<Transform>)>,
+}
+
+impl Snapshot {
+  pub fn of(scene: &Scene) -> Snapshot {
+    let eye = &scene.eye;
+    Snapshot {
+      view: *eye,
+      eye: vec![eye.c.x, eye.c.y, eye.c.z, eye.w, eye.h, eye.d],
+      lights: scene.lights.iter().map(light_key).collect(),
+      objects: scene.objs.iter().map(|obj| (object_key(obj), obj.bounds())).collect(),
+      placements: scene.objs.iter().map(|obj| (obj.c, obj.transform)).collect(),
+    }
+  }
+
+  /**
+   * Where the point `p` on object `obj_id` of `scene` was on the image when
+   * the snapshot was taken, as (col, row), following the object as it moved.
+   * None if the object wasn't there then, or was behind the eye.
+   */
+  pub fn pixel_then(&self, scene: &Scene, obj_id: usize, p: &Vector) -> Option<(f64, f64)> {
+    if self.placements.len() != scene.objs.len() {
+      return None;
+    }
+    let obj = scene.objs.get(obj_id)?;
+    let (c, transform) = &self.placements[obj_id];
+    let local = obj.transform.map_or(*p, |t| t.point_to_object(p));
+    let then = local.sub(&obj.c).add(c);
+    let then = transform.map_or(then, |t| t.point_to_world(&then));
+    self.view.pixel_at(&then)
+  }
+}
+
+fn light_key(light: &Light) -> Vec<f64> {
+  let mut key = vec![light.c.x, light.c.y, light.c.z, light.col.x, light.col.y, light.col.z];
+  match light.kind {
+    LightKind::Point => (),
+    LightKind::Directional { direction } => key.extend(&[direction.x, direction.y, direction.z]),
+    LightKind::Spot {
+      direction,
+      inner,
+      outer,
+    } => key.extend(&[direction.x, direction.y, direction.z, inner, outer]),
+  }
+  key
+}
+
+// Only where an object is, changes to anything else are edits.
+fn object_key(obj: &Object) -> Vec<f64> {
+  let mut key = vec![obj.c.x, obj.c.y, obj.c.z];
+  if let Some(transform) = &obj.transform {
+    key.extend(transform.matrix.m.iter().flatten());
+  }
+  key
+}
+
+fn ball(bounds: &Aabb) -> Ball {
+  if is_empty(bounds) {
+    return (Vector::new(0.0, 0.0, 0.0), 0.0);
+  }
+  (bounds.centre(), bounds.max.sub(&bounds.min).length() / 2.0)
+}
+
+/**
+ * The squares that can look different now from when `previous` was taken.
+ * Without a previous frame that's all of them.
+ */
+pub fn changed_tiles(previous: Option<&Snapshot>, scene: &Scene, emitters: &[(usize, Vec<EmitterSample>)]) -> DirtyTiles {
+  let previous = match previous {
+    Some(previous) => previous,
+    None => return DirtyTiles::all(),
+  };
+  let now = Snapshot::of(scene);
+  if now.eye != previous.eye || now.lights != previous.lights || now.objects.len() != previous.objects.len() {
+    return DirtyTiles::all();
+  }
+  let moved: Vec<usize> = (0..now.objects.len())
+    .filter(|&i| now.objects[i].0 != previous.objects[i].0)
+    .collect();
+  if moved.is_empty() {
+    return DirtyTiles::none();
+  }
+  let media = scene.fog.is_some() || !scene.volumes.is_empty();
+  if media || moved.iter().any(|&i| scene.objs[i].is_emissive()) {
+    return DirtyTiles::all();
+  }
+
+  match changed_hulls(scene, emitters, &previous.objects, &now.objects, &moved) {
+    Some(tiles) => tiles,
+    None => DirtyTiles::all(),
+  }
+}
+
+// None if the change covers the whole image.
+fn changed_hulls(
+  scene: &Scene,
+  emitters: &[(usize, Vec<EmitterSample>)],
+  before: &[(Vec<f64>, Aabb)],
+  now: &[(Vec<f64>, Aabb)],
+  moved: &[usize],
+) -> Option<DirtyTiles> {
+  // Shadows only show on what can receive them
+  let mut receivers = Aabb::empty();
+  for (i, obj) in scene.objs.iter().enumerate() {
+    if obj.can_receive_shadow {
+      receivers.grow(&before[i].1.min);
+      receivers.grow(&before[i].1.max);
+      receivers.grow(&now[i].1.min);
+      receivers.grow(&now[i].1.max);
+    }
+  }
+
+  let mut sources: Vec<LightKind> = vec![];
+  let mut positions = vec![];
+  for light in &scene.lights {
+    sources.push(light.kind);
+    positions.push(light.c);
+  }
+  for (_, samples) in emitters {
+    for sample in samples {
+      sources.push(LightKind::Point);
+      positions.push(sample.pi);
+    }
+  }
+
+  let mut hulls: Vec<Aabb> = vec![];
+  for &i in moved {
+    for bound in &[before[i].1, now[i].1] {
+      hulls.push(*bound);
+      if scene.objs[i].can_create_shadow && !is_empty(&receivers) {
+        for (kind, c) in sources.iter().zip(&positions) {
+          hulls.push(intersect(&shadow_hull(kind, c, &ball(bound), &ball(&receivers))?, &receivers));
+        }
+      }
+    }
+  }
+
+  // Things that reflect or refract can show the change anywhere on them.
+  // A single flat mirror shows it reflected in its plane.
+  let is_mirror = |obj: &Object| obj.typ == ObjectType::Disc && obj.rfr == 0.0 && obj.transform.is_none();
+  let mirrors: Vec<usize> = (0..scene.objs.len())
+    .filter(|&i| scene.objs[i].rfl > 0.0 && is_mirror(&scene.objs[i]))
+    .collect();
+  for (i, obj) in scene.objs.iter().enumerate() {
+    let shows_others = obj.rfl > 0.0 || obj.rfr > 0.0;
+    if shows_others && (!is_mirror(obj) || mirrors.len() > 1) {
+      hulls.push(now[i].1);
+    }
+  }
+  hulls.retain(|hull| !is_empty(hull));
+
+  let eye = &scene.eye;
+  let mut tiles = DirtyTiles::none();
+  for hull in &hulls {
+    tiles.mark(screen_rect(eye, hull)?);
+  }
+  if mirrors.len() == 1 {
+    let mirror = &scene.objs[mirrors[0]];
+    let mirror_rect = screen_rect(eye, &now[mirrors[0]].1)?;
+    for hull in &hulls {
+      let mut reflected = Aabb::empty();
+      for p in corners(hull).iter() {
+        reflected.grow(&reflect(p, mirror));
+      }
+      let rect = screen_rect(eye, &reflected)?;
+      tiles.mark([
+        rect[0].max(mirror_rect[0]),
+        rect[1].max(mirror_rect[1]),
+        rect[2].min(mirror_rect[2]),
+        rect[3].min(mirror_rect[3]),
+      ]);
+    }
+  }
+  Some(tiles)
+}
+
+/**
+ * A box holding the shadow that `bound` casts in the light, as far as
+ * `receivers` reach.  None if the light is inside it.
+ */
+fn shadow_hull(kind: &LightKind, light_c: &Vector, bound: &Ball, receivers: &Ball) -> Option<Aabb> {
+  let (c, r) = bound;
+  let mut hull = Aabb::empty();
+  match kind {
+    // Sunlight shadows are a cylinder
+    LightKind::Directional { direction } => {
+      let reach = receivers.0.sub(c).length() + receivers.1 + r;
+      grow_ball(&mut hull, c, *r);
+      grow_ball(&mut hull, &c.add(&direction.scale(reach)), *r);
+    }
+    // Otherwise a cone from the light, cut off where the object starts and
+    // where the receivers end
+    _ => {
+      let to_c = c.sub(light_c);
+      let d = to_c.length();
+      if d <= r * 1.001 {
+        return None;
+      }
+      let far = receivers.0.sub(light_c).length() + receivers.1;
+      let near = d - r;
+      if far > near {
+        let u = to_c.scale(1.0 / d);
+        let tan = r / (d * d - r * r).sqrt();
+        grow_ball(&mut hull, &light_c.add(&u.scale(near)), near * tan);
+        grow_ball(&mut hull, &light_c.add(&u.scale(far)), far * tan);
+      }
+    }
+  }
+  Some(hull)
+}
+
+fn grow_ball(bounds: &mut Aabb, c: &Vector, r: f64) {
+  bounds.grow(&c.sub(&Vector::new(r, r, r)));
+  bounds.grow(&c.add(&Vector::new(r, r, r)));
+}
+
+fn intersect(a: &Aabb, b: &Aabb) -> Aabb {
+  Aabb {
+    min: Vector::new(a.min.x.max(b.min.x), a.min.y.max(b.min.y), a.min.z.max(b.min.z)),
+    max: Vector::new(a.max.x.min(b.max.x), a.max.y.min(b.max.y), a.max.z.min(b.max.z)),
+  }
+}
+
+fn is_empty(bounds: &Aabb) -> bool {
+  bounds.min.x > bounds.max.x || bounds.min.y > bounds.max.y || bounds.min.z > bounds.max.z
+}
+
+fn corners(bounds: &Aabb) -> [Vector; 8] {
+  let mut corners = [bounds.min; 8];
+  for (i, p) in corners.iter_mut().enumerate() {
+    let pick = |bit: usize, min: f64, max: f64| if i & bit == 0 { min } else { max };
+    *p = Vector::new(
+      pick(1, bounds.min.x, bounds.max.x),
+      pick(2, bounds.min.y, bounds.max.y),
+      pick(4, bounds.min.z, bounds.max.z),
+    );
+  }
+  corners
+}
+
+// Reflect a point in the plane of a disc.
+fn reflect(p: &Vector, disc: &Object) -> Vector {
+  p.sub(&disc.n.scale(2.0 * p.sub(&disc.c).dot(&disc.n)))
+}
+
+/**
+ * The box of pixels, [col_min, row_min, col_max, row_max], around `hull` as
+ * the eye sees it.  None if it reaches behind the eye, as then it could be
+ * anywhere.
+ */
+fn screen_rect(eye: &Eye, hull: &Aabb) -> Option<[f64; 4]> {
+  let mut rect = [f64::MAX, f64::MAX, -f64::MAX, -f64::MAX];
+  for corner in corners(hull).iter() {
+    let (col, row) = eye.pixel_at(corner)?;
+    rect = [rect[0].min(col), rect[1].min(row), rect[2].max(col), rect[3].max(row)];
+  }
+  // A pixel either side for rounding
+  Some([rect[0] - 1.0, rect[1] - 1.0, rect[2] + 1.0, rect[3] + 1.0])
+}
@@ -0,0 +1,96 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Errors from the public API.  JavaScript sees them as a thrown `Error`
+// with the message from `Display`.
+
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+use crate::gltf::GltfError;
+use crate::mesh_file::MeshFileError;
+use crate::scene_file::ParseError;
+
+#[derive(Debug, PartialEq)]
+pub enum RayTracerError {
+  UnknownScene(String),
+  NoSuchStrip { strip_id: u32, num_strips: usize },
+  BufferTooSmall { needed: usize, len: usize },
+  BadRegion(String), // A region to render that isn't in the image
+  NoSuchObject(u32),
+  NoSuchLight(u32),
+  NoSuchRenderer(u32), // A shared renderer handle that isn't (or is no longer) live
+  InvalidScene(String), // Something in the scene that can't be rendered
+  SceneFile(ParseError),
+  Gltf(GltfError),
+  MeshFile(MeshFileError),
+}
+
+impl fmt::Display for RayTracerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RayTracerError::UnknownScene(name) => write!(f, "there's no scene called \"{}\"", name),
+      RayTracerError::NoSuchStrip { strip_id, num_strips } => {
+        write!(f, "there's no strip {}, there are {}", strip_id, num_strips)
+      }
+      RayTracerError::BufferTooSmall { needed, len } => {
+        write!(f, "the buffer is {} bytes, it needs to be at least {}", len, needed)
+      }
+      RayTracerError::BadRegion(message) => write!(f, "bad region: {}", message),
+      RayTracerError::NoSuchObject(id) => write!(f, "there's no object {}", id),
+      RayTracerError::NoSuchLight(id) => write!(f, "there's no light {}", id),
+      RayTracerError::NoSuchRenderer(handle) => write!(f, "there's no shared renderer {}", handle),
+      RayTracerError::InvalidScene(message) => write!(f, "{}", message),
+      RayTracerError::SceneFile(e) => write!(f, "scene file {}", e),
+      RayTracerError::Gltf(e) => write!(f, "{}", e),
+      RayTracerError::MeshFile(e) => write!(f, "mesh file: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for RayTracerError {}
+
+impl From<ParseError> for RayTracerError {
+  fn from(e: ParseError) -> Self {
+    RayTracerError::SceneFile(e)
+  }
+}
+
+impl From<GltfError> for RayTracerError {
+  fn from(e: GltfError) -> Self {
+    RayTracerError::Gltf(e)
+  }
+}
+
+impl From<MeshFileError> for RayTracerError {
+  fn from(e: MeshFileError) -> Self {
+    RayTracerError::MeshFile(e)
+  }
+}
+
+// Only used at the wasm boundary, JsValue can't be made natively.
+impl From<RayTracerError> for JsValue {
+  fn from(e: RayTracerError) -> Self {
+    JsError::new(&e.to_string()).into()
+  }
+}
@@ -0,0 +1,868 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Import glTF 2.0 scenes, either `.gltf` JSON with its buffers or a `.glb`.
+//
+// The eye can't turn, so the whole scene is moved in front of it instead:
+// every object and light is taken into the space of the first camera found,
+// and if there's no camera the scene is framed from the front.  glTF is
+// right handed, so z is flipped on the way in to keep the picture the right
+// way round.
+//
+// Materials use the metallic-roughness factors.  Textures and animations
+// can't be shown, so a file with any is an error rather than a scene that
+// looks different.  Light intensities are ignored, the lights here don't
+// fall off with distance.  A file with no lights gets one at the eye.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::constants::*;
+use crate::json::{self, Json};
+use crate::mesh::{Aabb, Mesh};
+use crate::objects::*;
+use crate::post::PostProcess;
+use crate::transform::{Matrix4, Transform};
+use crate::vector::Vector;
+use crate::vector::Vector as Colour;
+
+const GLB_MAGIC: u32 = 0x4654_6c67; // "glTF"
+const GLB_CHUNK_JSON: u32 = 0x4e4f_534a;
+const GLB_CHUNK_BIN: u32 = 0x004e_4942;
+
+// Extensions that change nothing we can't read without them.
+const SUPPORTED_EXTENSIONS: [&str; 4] = [
+  "KHR_lights_punctual",
+  "KHR_materials_emissive_strength",
+  "KHR_materials_transmission",
+  "KHR_materials_ior",
+];
+
+// Material textures, none of which are supported.
+const TEXTURES: [&str; 5] = [
+  "baseColorTexture",
+  "metallicRoughnessTexture",
+  "normalTexture",
+  "occlusionTexture",
+  "emissiveTexture",
+];
+
+const MESH_AMBIENT: f64 = 0.1;
+
+// Limits that keep a bad file from exhausting memory or the stack
+const MAX_ACCESSOR_COUNT: usize = 1 << 24;
+const MAX_NODE_DEPTH: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub struct GltfError {
+  pub message: String,
+}
+
+impl fmt::Display for GltfError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "glTF: {}", self.message)
+  }
+}
+
+fn error<T>(message: String) -> Result<T, GltfError> {
+  Err(GltfError { message })
+}
+
+/**
+ * Load a `.gltf` or `.glb` file.  Buffers in other files are looked for next
+ * to it.
+ */
+pub fn load_file(path: &Path) -> Result<Scene, GltfError> {
+  let read = |p: &Path| std::fs::read(p).ok();
+  let data = match read(path) {
+    Some(data) => data,
+    None => return error(format!("can't read {}", path.display())),
+  };
+  let dir = path.parent().unwrap_or_else(|| Path::new(""));
+  load(&data, |uri| read(&dir.join(uri)))
+}
+
+/**
+ * Load a glTF from memory, a `.glb` or the JSON of a `.gltf`.  `resolve`
+ * gives the contents of a buffer by its uri, buffers in data uris are
+ * decoded here.
+ */
+pub fn load(data: &[u8], resolve: impl Fn(&str) -> Option<Vec<u8>>) -> Result<Scene, GltfError> {
+  let (text, bin) = if data.starts_with(b"glTF") {
+    split_glb(data)?
+  } else {
+    (data, None)
+  };
+  let text = match std::str::from_utf8(text) {
+    Ok(text) => text,
+    Err(_) => return error("the JSON isn't UTF-8".to_string()),
+  };
+  let root = match json::parse(text) {
+    Ok(root) => root,
+    Err(e) => return error(format!("bad JSON: {}", e)),
+  };
+
+  let version = root.get("asset").and_then(|a| a.get("version")).and_then(Json::as_str);
+  match version {
+    Some(v) if v.starts_with("2.") => (),
+    Some(v) => return error(format!("version {} isn't supported, only 2.x", v)),
+    None => return error("no asset.version".to_string()),
+  }
+  for ext in array(&root, "extensionsRequired") {
+    let name = ext.as_str().unwrap_or("");
+    if !SUPPORTED_EXTENSIONS.contains(&name) {
+      return error(format!("required extension {} isn't supported", name));
+    }
+  }
+  if !array(&root, "animations").is_empty() {
+    return error("animations aren't supported".to_string());
+  }
+
+  let mut buffers = vec![];
+  for (i, buffer) in array(&root, "buffers").iter().enumerate() {
+    let data = match buffer.get("uri").and_then(Json::as_str) {
+      Some(uri) if uri.starts_with("data:") => match uri.find(";base64,") {
+        Some(at) => match decode_base64(&uri[at + 8..]) {
+          Some(data) => data,
+          None => return error(format!("buffers[{}] has bad base64", i)),
+        },
+        None => return error(format!("buffers[{}] has a data uri that isn't base64", i)),
+      },
+      Some(uri) => match resolve(uri) {
+        Some(data) => data,
+        None => return error(format!("can't load buffers[{}] from {}", i, uri)),
+      },
+      // The first buffer of a .glb is its BIN chunk
+      None => match (i, &bin) {
+        (0, Some(bin)) => bin.to_vec(),
+        _ => return error(format!("buffers[{}] has no uri", i)),
+      },
+    };
+    buffers.push(data);
+  }
+
+  let mut importer = Importer {
+    root: &root,
+    buffers,
+    meshes: HashMap::new(),
+    objs: vec![],
+    lights: vec![],
+    camera: None,
+    visited: HashSet::new(),
+  };
+
+  let scene_index = root.get("scene").and_then(Json::as_usize).unwrap_or(0);
+  let nodes = match array(&root, "scenes").get(scene_index) {
+    Some(scene) => array(scene, "nodes"),
+    None => return error(format!("no scenes[{}]", scene_index)),
+  };
+  for node in nodes {
+    importer.node(index(node, "scenes[].nodes")?, &Matrix4::identity(), 0)?;
+  }
+  importer.into_scene()
+}
+
+// The JSON and BIN chunks of a .glb.
+fn split_glb(data: &[u8]) -> Result<(&[u8], Option<&[u8]>), GltfError> {
+  let word = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+  if word(0) != Some(GLB_MAGIC) || word(4) != Some(2) {
+    return error("not a version 2 .glb".to_string());
+  }
+  let length = (word(8).unwrap_or(0) as usize).min(data.len());
+  let mut text = None;
+  let mut bin = None;
+  let mut at = 12;
+  while at + 8 <= length {
+    let size = word(at).unwrap() as usize;
+    let typ = word(at + 4).unwrap();
+    let chunk = match data.get(at + 8..at + 8 + size) {
+      Some(chunk) => chunk,
+      None => return error("a .glb chunk runs past the end of the file".to_string()),
+    };
+    match typ {
+      GLB_CHUNK_JSON if text.is_none() => text = Some(chunk),
+      GLB_CHUNK_BIN if bin.is_none() => bin = Some(chunk),
+      _ => (),
+    }
+    at += 8 + size;
+  }
+  match text {
+    Some(text) => Ok((text, bin)),
+    None => error("the .glb has no JSON chunk".to_string()),
+  }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+  let mut data = Vec::with_capacity(text.len() * 3 / 4);
+  let mut bits = 0u32;
+  let mut count = 0;
+  for c in text.bytes().take_while(|&c| c != b'=') {
+    let v = match c {
+      b'A'..=b'Z' => c - b'A',
+      b'a'..=b'z' => c - b'a' + 26,
+      b'0'..=b'9' => c - b'0' + 52,
+      b'+' => 62,
+      b'/' => 63,
+      _ => return None,
+    };
+    bits = bits << 6 | v as u32;
+    count += 6;
+    if count >= 8 {
+      count -= 8;
+      data.push((bits >> count) as u8);
+    }
+  }
+  Some(data)
+}
+
+// The array under `key`, empty if there isn't one.
+fn array<'a>(json: &'a Json, key: &str) -> &'a [Json] {
+  json.get(key).and_then(Json::as_array).unwrap_or(&[])
+}
+
+fn index(json: &Json, what: &str) -> Result<usize, GltfError> {
+  match json.as_usize() {
+    Some(i) => Ok(i),
+    None => error(format!("{} should be an index", what)),
+  }
+}
+
+// Numbers under `key`, or `default` if it isn't there.
+fn numbers(json: &Json, key: &str, default: &[f64]) -> Result<Vec<f64>, GltfError> {
+  match json.get(key) {
+    None => Ok(default.to_vec()),
+    Some(value) => match value.as_f64s() {
+      Some(values) if values.len() == default.len() => Ok(values),
+      _ => error(format!("{} should be {} numbers", key, default.len())),
+    },
+  }
+}
+
+fn number(json: Option<&Json>, key: &str, default: f64) -> f64 {
+  json.and_then(|j| j.get(key)).and_then(Json::as_f64).unwrap_or(default)
+}
+
+fn colour(v: &[f64]) -> Colour {
+  Colour::new(v[0], v[1], v[2])
+}
+
+// glTF matrices are column major.
+fn matrix(v: &[f64]) -> Matrix4 {
+  let mut m = Matrix4::identity();
+  for (i, x) in v.iter().enumerate() {
+    m.m[i % 4][i / 4] = *x;
+  }
+  m
+}
+
+// Translation * rotation * scale, the rotation a quaternion [x, y, z, w].
+fn trs(t: &[f64], r: &[f64], s: &[f64]) -> Matrix4 {
+  let (x, y, z, w) = (r[0], r[1], r[2], r[3]);
+  let rot = [
+    [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w)],
+    [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w)],
+    [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y)],
+  ];
+  let mut m = Matrix4::identity();
+  for r in 0..3 {
+    for c in 0..3 {
+      m.m[r][c] = rot[r][c] * s[c];
+    }
+    m.m[r][3] = t[r];
+  }
+  m
+}
+
+struct Importer<'a> {
+  root: &'a Json,
+  buffers: Vec<Vec<u8>>,
+  meshes: HashMap<(usize, usize), Arc<Mesh>>, // By mesh and primitive, so instances share
+  objs: Vec<(Object, Matrix4)>,               // With their glTF world matrix
+  lights: Vec<(Light, Matrix4)>,
+  camera: Option<(f64, Matrix4)>, // yfov and where it is
+  visited: HashSet<usize>,        // Nodes, which glTF only allows one parent
+}
+
+impl<'a> Importer<'a> {
+  fn item(&self, key: &str, i: usize) -> Result<&'a Json, GltfError> {
+    match array(self.root, key).get(i) {
+      Some(item) => Ok(item),
+      None => error(format!("no {}[{}]", key, i)),
+    }
+  }
+
+  fn node(&mut self, i: usize, parent: &Matrix4, depth: usize) -> Result<(), GltfError> {
+    // This also stops cycles, and nodes shared by several parents that could
+    // be visited exponentially many times
+    if !self.visited.insert(i) {
+      return error(format!("nodes[{}] has more than one parent", i));
+    }
+    if depth > MAX_NODE_DEPTH {
+      return error(format!("nodes[{}] is nested more than {} deep", i, MAX_NODE_DEPTH));
+    }
+    let node = self.item("nodes", i)?;
+    let local = match node.get("matrix") {
+      Some(_) => matrix(&numbers(node, "matrix", &[0.0; 16])?),
+      None => trs(
+        &numbers(node, "translation", &[0.0; 3])?,
+        &numbers(node, "rotation", &[0.0, 0.0, 0.0, 1.0])?,
+        &numbers(node, "scale", &[1.0; 3])?,
+      ),
+    };
+    let world = parent.mul(&local);
+
+    if node.get("skin").is_some() {
+      return error(format!("nodes[{}] is skinned, skins aren't supported", i));
+    }
+    if let Some(mesh) = node.get("mesh") {
+      self.mesh(index(mesh, "node.mesh")?, &world)?;
+    }
+    if let Some(camera) = node.get("camera") {
+      let camera = self.item("cameras", index(camera, "node.camera")?)?;
+      match camera.get("type").and_then(Json::as_str) {
+        Some("perspective") => {
+          let yfov = number(camera.get("perspective"), "yfov", 0.0);
+          if !(yfov > 0.0 && yfov < std::f64::consts::PI) {
+            return error(format!("the camera of nodes[{}] has a bad yfov", i));
+          }
+          if self.camera.is_none() {
+            self.camera = Some((yfov, world));
+          }
+        }
+        Some(typ) => return error(format!("{} cameras aren't supported", typ)),
+        None => return error(format!("the camera of nodes[{}] has no type", i)),
+      }
+    }
+    let light = node.get("extensions").and_then(|e| e.get("KHR_lights_punctual")).and_then(|l| l.get("light"));
+    if let Some(light) = light {
+      self.light(index(light, "node light")?, &world)?;
+    }
+
+    for child in array(node, "children") {
+      self.node(index(child, "node.children")?, &world, depth + 1)?;
+    }
+    Ok(())
+  }
+
+  fn mesh(&mut self, i: usize, world: &Matrix4) -> Result<(), GltfError> {
+    let mesh = self.item("meshes", i)?;
+    for (p, primitive) in array(mesh, "primitives").iter().enumerate() {
+      let shared = match self.meshes.get(&(i, p)) {
+        Some(shared) => shared.clone(),
+        None => {
+          let shared = Arc::new(self.primitive(primitive, i)?);
+          self.meshes.insert((i, p), shared.clone());
+          shared
+        }
+      };
+      let mut obj = Object::new_mesh(shared, ORIGIN, COL_WHITE, 0.0, 0.0, MESH_AMBIENT);
+      match primitive.get("material") {
+        Some(m) => self.material(&mut obj, index(m, "primitive.material")?)?,
+        None => self.material_from(&mut obj, &Json::Object(vec![])),
+      }
+      self.objs.push((obj, *world));
+    }
+    Ok(())
+  }
+
+  fn primitive(&self, primitive: &Json, mesh: usize) -> Result<Mesh, GltfError> {
+    let mode = primitive.get("mode").and_then(Json::as_usize).unwrap_or(4);
+    if mode != 4 {
+      return error(format!("meshes[{}] has a primitive of mode {}, only triangles (4) are supported", mesh, mode));
+    }
+    if primitive.get("targets").is_some() {
+      return error(format!("meshes[{}] has morph targets, they aren't supported", mesh));
+    }
+    let attributes = primitive.get("attributes");
+    let position = match attributes.and_then(|a| a.get("POSITION")) {
+      Some(position) => index(position, "POSITION")?,
+      None => return error(format!("meshes[{}] has a primitive without POSITION", mesh)),
+    };
+    let vertices = self.vectors(position)?;
+    let normals = match attributes.and_then(|a| a.get("NORMAL")) {
+      Some(normal) => self.vectors(index(normal, "NORMAL")?)?,
+      None => vec![],
+    };
+    if !normals.is_empty() && normals.len() != vertices.len() {
+      return error(format!("meshes[{}] has a different number of normals and positions", mesh));
+    }
+    let indices = match primitive.get("indices") {
+      Some(indices) => {
+        let (values, _) = self.accessor(index(indices, "indices")?)?;
+        values.iter().map(|&v| v as usize).collect()
+      }
+      None => (0..vertices.len()).collect::<Vec<usize>>(),
+    };
+    if indices.iter().any(|&v| v >= vertices.len()) {
+      return error(format!("meshes[{}] has an index past its last vertex", mesh));
+    }
+    let triangles = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+    Ok(Mesh::new(vertices, normals, triangles))
+  }
+
+  // A VEC3 float accessor.
+  fn vectors(&self, i: usize) -> Result<Vec<Vector>, GltfError> {
+    let accessor = self.item("accessors", i)?;
+    if accessor.get("type").and_then(Json::as_str) != Some("VEC3")
+      || accessor.get("componentType").and_then(Json::as_usize) != Some(5126)
+    {
+      return error(format!("accessors[{}] should be VEC3 floats", i));
+    }
+    let (values, _) = self.accessor(i)?;
+    Ok(values.chunks_exact(3).map(|v| Vector::new(v[0], v[1], v[2])).collect())
+  }
+
+  // Read an accessor, returning its values flattened and how many make an element.
+  fn accessor(&self, i: usize) -> Result<(Vec<f64>, usize), GltfError> {
+    let accessor = self.item("accessors", i)?;
+    if accessor.get("sparse").is_some() {
+      return error(format!("accessors[{}] is sparse, sparse accessors aren't supported", i));
+    }
+    let components = match accessor.get("type").and_then(Json::as_str) {
+      Some("SCALAR") => 1,
+      Some("VEC2") => 2,
+      Some("VEC3") => 3,
+      Some("VEC4") => 4,
+      _ => return error(format!("accessors[{}] has a type that isn't supported", i)),
+    };
+    let component_type = accessor.get("componentType").and_then(Json::as_usize).unwrap_or(0);
+    let size = match component_type {
+      5120 | 5121 => 1,
+      5122 | 5123 => 2,
+      5125 | 5126 => 4,
+      _ => return error(format!("accessors[{}] has a bad componentType", i)),
+    };
+    let normalized = accessor.get("normalized") == Some(&Json::Bool(true));
+    let count = accessor.get("count").and_then(Json::as_usize).unwrap_or(0);
+    if count > MAX_ACCESSOR_COUNT {
+      return error(format!("accessors[{}] has more than {} elements", i, MAX_ACCESSOR_COUNT));
+    }
+
+    // No buffer view means all zeros
+    let view = match accessor.get("bufferView") {
+      Some(view) => self.item("bufferViews", index(view, "accessor.bufferView")?)?,
+      None => return Ok((vec![0.0; count * components], components)),
+    };
+    let buffer = index(view.get("buffer").unwrap_or(&Json::Null), "bufferView.buffer")?;
+    let buffer = match self.buffers.get(buffer) {
+      Some(buffer) => buffer,
+      None => return error(format!("no buffers[{}]", buffer)),
+    };
+    let view_start = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+    let view_length = view.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+    let stride = view.get("byteStride").and_then(Json::as_usize).unwrap_or(size * components);
+    let offset = accessor.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+    // Checked, as the sizes in a bad file can overflow
+    let start = view_start.checked_add(offset);
+    let end = start.and_then(|start| {
+      let last = count.saturating_sub(1).checked_mul(stride)?;
+      last.checked_add(size * components)?.checked_add(start)
+    });
+    let (start, end, view_end) = match (start, end, view_start.checked_add(view_length)) {
+      (Some(start), Some(end), Some(view_end)) => (start, end, view_end),
+      _ => return error(format!("accessors[{}] reads past the end of its buffer", i)),
+    };
+    if count > 0 && (end > view_end || end > buffer.len()) {
+      return error(format!("accessors[{}] reads past the end of its buffer", i));
+    }
+
+    let mut values = Vec::with_capacity(count * components);
+    for e in 0..count {
+      for c in 0..components {
+        let b = &buffer[start + e * stride + c * size..];
+        let v = match component_type {
+          5120 => (b[0] as i8 as f64, 127.0),
+          5121 => (b[0] as f64, 255.0),
+          5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+          5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+          5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+          _ => (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+        };
+        values.push(if normalized { (v.0 / v.1).max(-1.0) } else { v.0 });
+      }
+    }
+    Ok((values, components))
+  }
+
+  fn material(&self, obj: &mut Object, i: usize) -> Result<(), GltfError> {
+    let material = self.item("materials", i)?;
+    let pbr = material.get("pbrMetallicRoughness");
+    for texture in TEXTURES.iter() {
+      if material.get(texture).or_else(|| pbr.and_then(|p| p.get(texture))).is_some() {
+        return error(format!("materials[{}] has a {}, textures aren't supported", i, texture));
+      }
+    }
+    self.material_from(obj, material);
+    Ok(())
+  }
+
+  /**
+   * Map metallic-roughness onto this renderer's materials.  Smooth surfaces
+   * reflect, metals more so, while rough ones are diffuse whatever they're
+   * made of since there's nothing sharp for them to reflect.
+   */
+  fn material_from(&self, obj: &mut Object, material: &Json) {
+    let pbr = material.get("pbrMetallicRoughness");
+    let base = pbr
+      .and_then(|p| p.get("baseColorFactor"))
+      .and_then(Json::as_f64s)
+      .filter(|v| v.len() == 4)
+      .unwrap_or_else(|| vec![1.0; 4]);
+    let metallic = number(pbr, "metallicFactor", 1.0).clamp(0.0, 1.0);
+    let roughness = number(pbr, "roughnessFactor", 1.0).clamp(0.0, 1.0);
+    let smooth = 1.0 - roughness;
+
+    obj.col = colour(&base);
+    obj.diff = 1.0 - metallic * smooth;
+    obj.spec = smooth;
+    obj.rfl = smooth * (0.04 + 0.96 * metallic);
+
+    let extensions = material.get("extensions");
+    let transmission = number(extensions.and_then(|e| e.get("KHR_materials_transmission")), "transmissionFactor", 0.0);
+    if transmission > 0.0 {
+      obj.rfr = number(extensions.and_then(|e| e.get("KHR_materials_ior")), "ior", 1.5);
+      obj.diff *= 1.0 - transmission.min(1.0);
+    }
+
+    let emissive = material.get("emissiveFactor").and_then(Json::as_f64s).filter(|v| v.len() == 3);
+    if let Some(emissive) = emissive {
+      let strength = number(extensions.and_then(|e| e.get("KHR_materials_emissive_strength")), "emissiveStrength", 1.0);
+      obj.set_emission(colour(&emissive), strength);
+    }
+  }
+
+  fn light(&mut self, i: usize, world: &Matrix4) -> Result<(), GltfError> {
+    let lights = self.root.get("extensions").and_then(|e| e.get("KHR_lights_punctual"));
+    let light = match lights.map(|l| array(l, "lights")).and_then(|l| l.get(i)) {
+      Some(light) => light,
+      None => return error(format!("no KHR_lights_punctual light {}", i)),
+    };
+    let col = colour(&numbers(light, "color", &[1.0; 3])?);
+    // Lights shine down their -z, which becomes +z when we flip z
+    let c = world.transform_point(&ORIGIN);
+    let direction = world.transform_vector(&Vector::new(0.0, 0.0, -1.0));
+    let light = match light.get("type").and_then(Json::as_str) {
+      Some("point") => Light::point(c, col),
+      Some("directional") => Light::directional(direction, col),
+      Some("spot") => {
+        let spot = light.get("spot");
+        let inner = number(spot, "innerConeAngle", 0.0).to_degrees();
+        let outer = number(spot, "outerConeAngle", std::f64::consts::FRAC_PI_4).to_degrees();
+        Light::spot(c, direction, inner, outer, col)
+      }
+      _ => return error(format!("KHR_lights_punctual light {} has a bad type", i)),
+    };
+    self.lights.push((light, *world));
+    Ok(())
+  }
+
+  // Put everything where the eye can see it.
+  fn into_scene(self) -> Result<Scene, GltfError> {
+    let flip_z = Transform::scale(&Vector::new(1.0, 1.0, -1.0)).matrix;
+    let (view, eye) = match self.camera {
+      Some((yfov, camera)) => {
+        let to_camera = match camera.inverse() {
+          Some(inverse) => inverse,
+          None => return error("the camera's transform can't be inverted".to_string()),
+        };
+        let size = 2.0 * (yfov / 2.0).tan();
+        let eye = Eye {
+          c: ORIGIN,
+          w: size,
+          h: size,
+          d: 1.0,
+        };
+        (flip_z.mul(&to_camera), eye)
+      }
+      None => (flip_z, self.framing_eye(&flip_z)),
+    };
+
+    let mut objs = vec![];
+    for (mut obj, world) in self.objs {
+      match Transform::from_matrix(view.mul(&world)) {
+        Some(transform) => obj.set_transform(transform),
+        None => return error("a mesh has a transform that can't be inverted".to_string()),
+      }
+      objs.push(obj);
+    }
+    let mut lights: Vec<Light> = self
+      .lights
+      .into_iter()
+      .map(|(mut light, _)| {
+        light.c = view.transform_point(&light.c);
+        light.kind = match light.kind {
+          LightKind::Point => LightKind::Point,
+          LightKind::Directional { direction } => LightKind::Directional {
+            direction: view.transform_vector(&direction).normalise(),
+          },
+          LightKind::Spot { direction, inner, outer } => LightKind::Spot {
+            direction: view.transform_vector(&direction).normalise(),
+            inner,
+            outer,
+          },
+        };
+        light
+      })
+      .collect();
+    if lights.is_empty() {
+      lights.push(Light::point(eye.c, COL_WHITE));
+    }
+
+    Ok(Scene {
+      eye,
+      lights,
+      objs,
+      fog: None,
+      volumes: vec![],
+      caustics: false,
+      post: PostProcess::default(),
+    })
+  }
+
+  // An eye looking down +z at the whole scene, with the usual field of view.
+  fn framing_eye(&self, view: &Matrix4) -> Eye {
+    let mut bounds = Aabb::empty();
+    for (obj, world) in &self.objs {
+      let to_view = view.mul(world);
+      for v in &obj.mesh.as_ref().unwrap().vertices {
+        bounds.grow(&to_view.transform_point(v));
+      }
+    }
+    let (w, d): (f64, f64) = (0.75, 2.0);
+    let (centre, radius) = if self.objs.is_empty() {
+      (ORIGIN, 1.0)
+    } else {
+      (bounds.centre(), bounds.max.sub(&bounds.min).length().max(1e-3) / 2.0)
+    };
+    // Far enough back that the bounding sphere fits in the picture
+    let distance = radius / (w / 2.0 / d).atan().sin();
+    Eye {
+      c: centre.sub(&Vector::new(0.0, 0.0, distance)),
+      w,
+      h: w,
+      d,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::gltf::{decode_base64, load};
+  use crate::objects::*;
+  use crate::vector::Vector;
+  use std::sync::Arc;
+
+  fn base64(data: &[u8]) -> String {
+    let table = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+      let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+      let bits = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+      for i in 0..4 {
+        if i <= chunk.len() {
+          text.push(table[(bits >> (18 - 6 * i) & 63) as usize] as char);
+        } else {
+          text.push('=');
+        }
+      }
+    }
+    text
+  }
+
+  // A triangle facing +z, indexed with u16s.
+  fn triangle() -> Vec<u8> {
+    let mut bin = vec![];
+    for v in &[-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0] {
+      bin.extend_from_slice(&v.to_le_bytes());
+    }
+    for i in &[0u16, 1, 2, 0] {
+      bin.extend_from_slice(&i.to_le_bytes());
+    }
+    bin
+  }
+
+  // Two instances of the triangle 5 in front of a camera, which is turned to
+  // look down +x, and a red spot light.
+  fn document(buffer: &str, extra: &str) -> String {
+    format!(
+      r#"{{
+        "asset": {{ "version": "2.0" }},
+        "scene": 0,
+        "scenes": [{{ "nodes": [0, 1] }}],
+        "nodes": [
+          {{ "camera": 0, "rotation": [0, -0.70710678, 0, 0.70710678], "children": [2] }},
+          {{ "translation": [5, 0, 0], "rotation": [0, -0.70710678, 0, 0.70710678], "children": [3] }},
+          {{ "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }} }},
+          {{ "mesh": 0, "children": [4] }},
+          {{ "mesh": 0, "translation": [0, 3, 0] }}
+        ],
+        "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.5, "znear": 0.1 }} }}],
+        "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }}] }}],
+        "materials": [{{
+          "pbrMetallicRoughness": {{ "baseColorFactor": [0.8, 0.2, 0.1, 1], "metallicFactor": 0, "roughnessFactor": 0.5 }},
+          "emissiveFactor": [1, 1, 0]
+        }}],
+        "extensions": {{ "KHR_lights_punctual": {{ "lights": [
+          {{ "type": "spot", "color": [1, 0, 0], "spot": {{ "outerConeAngle": 0.5 }} }}
+        ] }} }},
+        "accessors": [
+          {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+          {{ "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+        ],
+        "bufferViews": [
+          {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+          {{ "buffer": 0, "byteOffset": 36, "byteLength": 6 }}
+        ],
+        "buffers": [{{ {} "byteLength": 44 }}]
+        {}
+      }}"#,
+      buffer, extra
+    )
+  }
+
+  fn check_scene(scene: &Scene) {
+    assert_eq!(scene.objs.len(), 2);
+    let obj = &scene.objs[0];
+    assert!(obj.col.equals(&Vector::new(0.8, 0.2, 0.1)));
+    assert!((obj.diff - 1.0).abs() < 1e-9);
+    assert!(obj.is_emissive());
+    assert!(Arc::ptr_eq(obj.mesh.as_ref().unwrap(), scene.objs[1].mesh.as_ref().unwrap()));
+
+    // Straight ahead of the eye, facing it
+    let ray = Ray::new(scene.eye.c, Vector::new(0.0, 0.0, 1.0));
+    let hit = obj.intersect(&ray).unwrap();
+    assert!((hit.t - 5.0).abs() < 1e-6);
+    assert!(hit.front_face);
+    assert!(hit.norm.equals(&Vector::new(0.0, 0.0, -1.0)));
+    // The child is up 3
+    let up = Ray::new(Vector::new(0.0, 3.0, 0.0), Vector::new(0.0, 0.0, 1.0));
+    assert!(scene.objs[1].intersect(&up).is_some());
+
+    assert!((scene.eye.h - 2.0 * 0.25f64.tan()).abs() < 1e-9);
+    assert_eq!(scene.lights.len(), 1);
+    match scene.lights[0].kind {
+      LightKind::Spot { direction, outer, .. } => {
+        assert!(direction.sub(&Vector::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert!((outer - 0.5f64.to_degrees()).abs() < 1e-9);
+      }
+      _ => panic!("expected a spot light"),
+    }
+  }
+
+  #[test]
+  fn gltf_with_data_uri() {
+    let uri = format!("\"uri\": \"data:application/octet-stream;base64,{}\",", base64(&triangle()));
+    assert_eq!(decode_base64(&base64(&triangle())).unwrap(), triangle());
+    let scene = load(document(&uri, "").as_bytes(), |_| None).unwrap();
+    check_scene(&scene);
+
+    // Without a camera or lights it's framed from the front and lit from the eye
+    let bare = document(&uri, "").replace("\"nodes\": [0, 1]", "\"nodes\": [4]");
+    let scene = load(bare.as_bytes(), |_| None).unwrap();
+    assert_eq!(scene.objs.len(), 1);
+    assert!(scene.lights[0].c.equals(&scene.eye.c));
+    let ray = Ray::new(scene.eye.c, Vector::new(0.0, 0.0, 1.0));
+    assert!(scene.objs[0].intersect(&ray).unwrap().front_face);
+  }
+
+  #[test]
+  fn gltf_glb() {
+    let mut text = document("", "").into_bytes();
+    while !text.len().is_multiple_of(4) {
+      text.push(b' ');
+    }
+    let bin = triangle();
+    let mut glb = vec![];
+    glb.extend_from_slice(b"glTF");
+    glb.extend_from_slice(&2u32.to_le_bytes());
+    glb.extend_from_slice(&((12 + 8 + text.len() + 8 + bin.len()) as u32).to_le_bytes());
+    glb.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"JSON");
+    glb.extend_from_slice(&text);
+    glb.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+    glb.extend_from_slice(b"BIN\0");
+    glb.extend_from_slice(&bin);
+    check_scene(&load(&glb, |_| None).unwrap());
+
+    // External buffers come from the resolver
+    let scene = load(document("\"uri\": \"tri.bin\",", "").as_bytes(), |uri| {
+      assert_eq!(uri, "tri.bin");
+      Some(triangle())
+    });
+    check_scene(&scene.unwrap());
+  }
+
+  #[test]
+  fn gltf_errors() {
+    let message = |data: &[u8]| match load(data, |_| None) {
+      Ok(_) => panic!("expected an error"),
+      Err(e) => e.message,
+    };
+    let with_extra = |extra: &str| message(document("\"uri\": \"missing.bin\",", extra).as_bytes());
+    assert_eq!(with_extra(""), "can't load buffers[0] from missing.bin");
+    assert_eq!(
+      with_extra(", \"extensionsRequired\": [\"KHR_draco_mesh_compression\"]"),
+      "required extension KHR_draco_mesh_compression isn't supported"
+    );
+    assert_eq!(message(b"{ \"asset\": { \"version\": \"1.0\" } }"), "version 1.0 isn't supported, only 2.x");
+    assert!(message(b"{ \"asset\": ").starts_with("bad JSON"));
+    assert_eq!(message(b"glTF\x01\0\0\0"), "not a version 2 .glb");
+
+    let lines = r#"{ "asset": { "version": "2.0" }, "scenes": [{ "nodes": [0] }], "nodes": [{ "mesh": 0 }],
+      "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "mode": 1 }] }] }"#;
+    assert_eq!(message(lines.as_bytes()), "meshes[0] has a primitive of mode 1, only triangles (4) are supported");
+    let ortho = r#"{ "asset": { "version": "2.0" }, "scenes": [{ "nodes": [0] }], "nodes": [{ "camera": 0 }],
+      "cameras": [{ "type": "orthographic" }] }"#;
+    assert_eq!(message(ortho.as_bytes()), "orthographic cameras aren't supported");
+
+    let uri = format!("\"uri\": \"data:application/octet-stream;base64,{}\",", base64(&triangle()));
+    // Changes to the good document
+    let changed = |from: &str, to: &str| message(document(&uri, "").replace(from, to).as_bytes());
+    assert_eq!(
+      message(document(&uri, ", \"animations\": [{ \"channels\": [], \"samplers\": [] }]").as_bytes()),
+      "animations aren't supported"
+    );
+    assert_eq!(
+      changed("\"metallicFactor\": 0", "\"baseColorTexture\": { \"index\": 0 }"),
+      "materials[0] has a baseColorTexture, textures aren't supported"
+    );
+    assert_eq!(
+      changed("\"nodes\": [0, 1]", "\"nodes\": [0, 1, 4]"),
+      "nodes[4] has more than one parent"
+    );
+    assert_eq!(
+      changed("{ \"mesh\": 0, \"translation\"", "{ \"children\": [3], \"mesh\": 0, \"translation\""),
+      "nodes[3] has more than one parent"
+    );
+    assert_eq!(
+      changed("\"bufferView\": 0, \"componentType\": 5126, \"count\": 3", "\"componentType\": 5126, \"count\": 4e9"),
+      "accessors[0] has more than 16777216 elements"
+    );
+    assert_eq!(
+      changed("\"count\": 3, \"type\": \"SCALAR\"", "\"count\": 3, \"byteOffset\": 1.8e19, \"type\": \"SCALAR\""),
+      "accessors[1] reads past the end of its buffer"
+    );
+  }
+}
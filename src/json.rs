@@ -0,0 +1,292 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Just enough JSON to read scene files from other programs.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  // The value of `key` in an object, None if it's not there or this isn't an object.
+  pub fn get(&self, key: &str) -> Option<&Json> {
+    match self {
+      Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Json::Number(n) => Some(*n),
+      _ => None,
+    }
+  }
+
+  pub fn as_usize(&self) -> Option<usize> {
+    match self {
+      Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(s) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&[Json]> {
+    match self {
+      Json::Array(a) => Some(a),
+      _ => None,
+    }
+  }
+
+  // An array of numbers.
+  pub fn as_f64s(&self) -> Option<Vec<f64>> {
+    self.as_array()?.iter().map(Json::as_f64).collect()
+  }
+}
+
+/**
+ * Parse a JSON document.  The error says what went wrong and where.
+ */
+pub fn parse(text: &str) -> Result<Json, String> {
+  let mut parser = Parser {
+    bytes: text.as_bytes(),
+    pos: 0,
+  };
+  let value = parser.value()?;
+  parser.skip_space();
+  if parser.pos < parser.bytes.len() {
+    return Err(parser.error("unexpected text after the JSON"));
+  }
+  Ok(value)
+}
+
+struct Parser<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn error(&self, message: &str) -> String {
+    format!("{} at byte {}", message, self.pos)
+  }
+
+  fn skip_space(&mut self) {
+    while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&mut self) -> Option<u8> {
+    self.skip_space();
+    self.bytes.get(self.pos).copied()
+  }
+
+  fn expect(&mut self, c: u8) -> Result<(), String> {
+    if self.peek() == Some(c) {
+      self.pos += 1;
+      Ok(())
+    } else {
+      Err(self.error(&format!("expected '{}'", c as char)))
+    }
+  }
+
+  fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+    if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+      self.pos += word.len();
+      Ok(value)
+    } else {
+      Err(self.error("unexpected character"))
+    }
+  }
+
+  fn value(&mut self) -> Result<Json, String> {
+    match self.peek() {
+      None => Err(self.error("unexpected end")),
+      Some(b'{') => self.object(),
+      Some(b'[') => self.array(),
+      Some(b'"') => self.string().map(Json::String),
+      Some(b't') => self.literal("true", Json::Bool(true)),
+      Some(b'f') => self.literal("false", Json::Bool(false)),
+      Some(b'n') => self.literal("null", Json::Null),
+      Some(_) => self.number(),
+    }
+  }
+
+  fn object(&mut self) -> Result<Json, String> {
+    self.expect(b'{')?;
+    let mut members = vec![];
+    if self.peek() == Some(b'}') {
+      self.pos += 1;
+      return Ok(Json::Object(members));
+    }
+    loop {
+      if self.peek() != Some(b'"') {
+        return Err(self.error("expected a key"));
+      }
+      let key = self.string()?;
+      self.expect(b':')?;
+      members.push((key, self.value()?));
+      match self.peek() {
+        Some(b',') => self.pos += 1,
+        Some(b'}') => {
+          self.pos += 1;
+          return Ok(Json::Object(members));
+        }
+        _ => return Err(self.error("expected ',' or '}'")),
+      }
+    }
+  }
+
+  fn array(&mut self) -> Result<Json, String> {
+    self.expect(b'[')?;
+    let mut items = vec![];
+    if self.peek() == Some(b']') {
+      self.pos += 1;
+      return Ok(Json::Array(items));
+    }
+    loop {
+      items.push(self.value()?);
+      match self.peek() {
+        Some(b',') => self.pos += 1,
+        Some(b']') => {
+          self.pos += 1;
+          return Ok(Json::Array(items));
+        }
+        _ => return Err(self.error("expected ',' or ']'")),
+      }
+    }
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    self.expect(b'"')?;
+    let mut s = String::new();
+    loop {
+      let start = self.pos;
+      while self.pos < self.bytes.len() && self.bytes[self.pos] != b'"' && self.bytes[self.pos] != b'\\' {
+        self.pos += 1;
+      }
+      s.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| self.error("bad UTF-8"))?);
+      match self.bytes.get(self.pos) {
+        None => return Err(self.error("unterminated string")),
+        Some(b'"') => {
+          self.pos += 1;
+          return Ok(s);
+        }
+        _ => {
+          self.pos += 1;
+          let c = match self.bytes.get(self.pos) {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+              let code = self.hex4()?;
+              // A surrogate pair makes one character
+              let code = if (0xd800..0xdc00).contains(&code) && self.bytes[self.pos + 1..].starts_with(b"\\u") {
+                self.pos += 2;
+                let low = self.hex4()?;
+                0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+              } else {
+                code
+              };
+              char::from_u32(code).unwrap_or('\u{fffd}')
+            }
+            _ => return Err(self.error("bad escape")),
+          };
+          s.push(c);
+          self.pos += 1;
+        }
+      }
+    }
+  }
+
+  // The four hex digits after the current position, leaving pos on the last.
+  fn hex4(&mut self) -> Result<u32, String> {
+    let digits = self
+      .bytes
+      .get(self.pos + 1..self.pos + 5)
+      .and_then(|d| std::str::from_utf8(d).ok())
+      .and_then(|d| u32::from_str_radix(d, 16).ok());
+    match digits {
+      Some(code) => {
+        self.pos += 4;
+        Ok(code)
+      }
+      None => Err(self.error("bad \\u escape")),
+    }
+  }
+
+  fn number(&mut self) -> Result<Json, String> {
+    let start = self.pos;
+    while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+      self.pos += 1;
+    }
+    std::str::from_utf8(&self.bytes[start..self.pos])
+      .ok()
+      .and_then(|s| s.parse::<f64>().ok())
+      .map(Json::Number)
+      .ok_or_else(|| {
+        self.pos = start;
+        self.error("unexpected character")
+      })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::json::{parse, Json};
+
+  #[test]
+  fn json_parse() {
+    let json = parse(r#" { "a": [1, -2.5e1, true, null], "b": { "c": "x\"é\n" }, "d": [] } "#).unwrap();
+    assert_eq!(json.get("a").unwrap().as_array().unwrap().len(), 4);
+    assert_eq!(json.get("a").unwrap().as_array().unwrap()[1], Json::Number(-25.0));
+    assert_eq!(json.get("b").unwrap().get("c").unwrap().as_str(), Some("x\"é\n"));
+    assert_eq!(json.get("d").unwrap().as_f64s(), Some(vec![]));
+    assert!(json.get("e").is_none());
+  }
+
+  #[test]
+  fn json_errors() {
+    assert!(parse("{ \"a\": 1, }").is_err());
+    assert!(parse("[1 2]").is_err());
+    assert!(parse("\"abc").is_err());
+    assert_eq!(parse("[1] x").unwrap_err(), "unexpected text after the JSON at byte 4");
+  }
+}
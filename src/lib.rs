@@ -22,8 +22,27 @@
  *********************************************************************/
 extern crate wasm_bindgen;
 
+pub mod animation;
 pub mod constants;
+pub mod csg;
+pub mod denoise;
+pub mod dirty;
+pub mod error;
+pub mod gltf;
+pub mod json;
+pub mod mesh;
+pub mod mesh_file;
 pub mod objects;
 mod physics;
+pub mod post;
 pub mod raytracer;
+pub mod scene_file;
+pub mod scenes;
+pub mod sdf;
+pub mod stats;
+pub mod temporal;
+pub mod transform;
+#[cfg(feature = "threads")]
+pub mod shared;
 pub mod vector;
+pub mod volume;
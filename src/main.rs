@@ -21,21 +21,50 @@
  *                                                                   *
  *********************************************************************/
 
-extern crate time;
 extern crate wasm_bindgen;
 
+pub mod animation;
 pub mod constants;
+pub mod csg;
+pub mod denoise;
+pub mod dirty;
+pub mod error;
+pub mod gltf;
+pub mod json;
+pub mod mesh;
+pub mod mesh_file;
 pub mod objects;
 mod physics;
+pub mod post;
 pub mod raytracer;
+pub mod scene_file;
+pub mod scenes;
+pub mod sdf;
+pub mod stats;
+pub mod temporal;
+pub mod transform;
 pub mod vector;
+pub mod volume;
 
-use crate::constants::{NUM_COLS, SQUARE_SIZE};
+use crate::constants::{NUM_COLS, NUM_STRIPS, SQUARE_SIZE};
 use crate::raytracer::RayTracer;
+use crate::stats::RayStats;
 
 fn main() {
   let mut rt = RayTracer::new(5);
   rt.increment(10.0);
-  let mut strip_data = vec![0; NUM_COLS * SQUARE_SIZE];
-  rt.render(110, &mut strip_data);
+  let mut strip_data = vec![0; NUM_COLS * SQUARE_SIZE * 4];
+  rt.render(55, &mut strip_data).unwrap();
+  let strip_stats = rt.take_stats();
+
+  let mut frame_stats = RayStats::default();
+  for strip_id in 0..NUM_STRIPS {
+    rt.render(strip_id as u32, &mut strip_data).unwrap();
+    frame_stats.add_in_place(&rt.take_stats());
+  }
+
+  if cfg!(feature = "stats") {
+    println!("strip 55: {}", strip_stats);
+    println!("frame:    {}", frame_stats);
+  }
 }
@@ -0,0 +1,454 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Triangle meshes with a bounding volume hierarchy.  A mesh is shared
+// between objects with an `Arc`, so it can be instanced many times with
+// different transforms and materials for the cost of one copy.
+
+use std::f64;
+
+use crate::objects::Ray;
+use crate::vector::Vector;
+
+// Stop splitting BVH nodes at this many triangles.
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+  pub min: Vector,
+  pub max: Vector,
+}
+
+impl Aabb {
+  pub fn empty() -> Aabb {
+    Aabb {
+      min: Vector::new(f64::MAX, f64::MAX, f64::MAX),
+      max: Vector::new(-f64::MAX, -f64::MAX, -f64::MAX),
+    }
+  }
+
+  pub fn grow(&mut self, p: &Vector) {
+    self.min = Vector::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+    self.max = Vector::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+  }
+
+  pub fn centre(&self) -> Vector {
+    self.min.add(&self.max).scale(0.5)
+  }
+
+  /**
+   * Slab test, true if the ray hits the box somewhere in (0, t_max).
+   */
+  pub fn hit(&self, ray: &Ray, inv_dir: &Vector, t_max: f64) -> bool {
+    let mut t0 = 0.0;
+    let mut t1 = t_max;
+    for (o, d, lo, hi) in [
+      (ray.origin.x, inv_dir.x, self.min.x, self.max.x),
+      (ray.origin.y, inv_dir.y, self.min.y, self.max.y),
+      (ray.origin.z, inv_dir.z, self.min.z, self.max.z),
+    ] {
+      let mut near = (lo - o) * d;
+      let mut far = (hi - o) * d;
+      if near > far {
+        std::mem::swap(&mut near, &mut far);
+      }
+      // NaN (0 * inf) falls through both and leaves the interval as is
+      if near > t0 {
+        t0 = near;
+      }
+      if far < t1 {
+        t1 = far;
+      }
+      if t0 > t1 {
+        return false;
+      }
+    }
+    true
+  }
+}
+
+// A node is a leaf when `count` > 0, otherwise its children are at
+// `index + 1` and `right`.
+#[derive(Clone)]
+struct BvhNode {
+  bounds: Aabb,
+  start: usize, // First triangle of a leaf
+  count: usize,
+  right: usize,
+}
+
+// Where a ray hit the mesh.
+#[derive(Clone, Copy)]
+pub struct MeshHit {
+  pub t: f64,
+  pub triangle: usize,
+  // Barycentric coordinates of the hit
+  pub u: f64,
+  pub v: f64,
+}
+
+pub struct Mesh {
+  pub vertices: Vec<Vector>,
+  pub normals: Vec<Vector>, // One per vertex, or empty for flat shading
+  pub colours: Vec<Vector>, // One per vertex, or empty to use the object's colour
+  pub triangles: Vec<[usize; 3]>,
+  nodes: Vec<BvhNode>,
+}
+
+impl Mesh {
+  /**
+   * Make a mesh and build its BVH.  The triangles are reordered.
+   */
+  pub fn new(vertices: Vec<Vector>, normals: Vec<Vector>, triangles: Vec<[usize; 3]>) -> Mesh {
+    let mut mesh = Mesh {
+      vertices,
+      normals,
+      colours: vec![],
+      triangles,
+      nodes: vec![],
+    };
+    if !mesh.triangles.is_empty() {
+      let count = mesh.triangles.len();
+      mesh.build(0, count);
+    }
+    mesh
+  }
+
+  // Give each vertex a colour, there must be one for every vertex.
+  pub fn with_colours(mut self, colours: Vec<Vector>) -> Mesh {
+    assert_eq!(colours.len(), self.vertices.len());
+    self.colours = colours;
+    self
+  }
+
+  pub fn bounds(&self) -> Aabb {
+    self.nodes.first().map_or_else(Aabb::empty, |n| n.bounds)
+  }
+
+  fn triangle_bounds(&self, tri: usize) -> Aabb {
+    let mut bounds = Aabb::empty();
+    for &i in &self.triangles[tri] {
+      bounds.grow(&self.vertices[i]);
+    }
+    bounds
+  }
+
+  // Build the node for triangles start..start + count, returns its index.
+  fn build(&mut self, start: usize, count: usize) -> usize {
+    let mut bounds = Aabb::empty();
+    let mut centres = Aabb::empty();
+    for tri in start..start + count {
+      let tb = self.triangle_bounds(tri);
+      bounds.grow(&tb.min);
+      bounds.grow(&tb.max);
+      centres.grow(&tb.centre());
+    }
+
+    let index = self.nodes.len();
+    self.nodes.push(BvhNode {
+      bounds,
+      start,
+      count,
+      right: 0,
+    });
+    if count <= MAX_LEAF_TRIANGLES {
+      return index;
+    }
+
+    // Split on the median of the longest axis of the centres
+    let extent = centres.max.sub(&centres.min);
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+      0
+    } else if extent.y >= extent.z {
+      1
+    } else {
+      2
+    };
+    let key = |mesh: &Mesh, tri: &[usize; 3]| {
+      let c = tri
+        .iter()
+        .fold(Vector::new(0.0, 0.0, 0.0), |acc, &i| acc.add(&mesh.vertices[i]));
+      match axis {
+        0 => c.x,
+        1 => c.y,
+        _ => c.z,
+      }
+    };
+    let mut tris = self.triangles[start..start + count].to_vec();
+    tris.sort_by(|a, b| key(self, a).total_cmp(&key(self, b)));
+    self.triangles[start..start + count].copy_from_slice(&tris);
+
+    let half = count / 2;
+    self.nodes[index].count = 0;
+    self.build(start, half);
+    let right = self.build(start + half, count - half);
+    self.nodes[index].right = right;
+    index
+  }
+
+  /**
+   * The nearest hit in front of the ray origin.  The direction doesn't need
+   * to be normalised.
+   */
+  pub fn intersect(&self, ray: &Ray) -> Option<MeshHit> {
+    if self.nodes.is_empty() {
+      return None;
+    }
+    let inv_dir = Vector::new(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+    let mut closest: Option<MeshHit> = None;
+    // The tree is balanced, so this is plenty deep
+    let mut stack = [0; 64];
+    let mut top = 1;
+    while top > 0 {
+      top -= 1;
+      let index = stack[top];
+      let node = &self.nodes[index];
+      let t_max = closest.map_or(f64::MAX, |h| h.t);
+      if !node.bounds.hit(ray, &inv_dir, t_max) {
+        continue;
+      }
+      if node.count > 0 {
+        for tri in node.start..node.start + node.count {
+          if let Some(hit) = self.intersect_triangle(tri, ray) {
+            if hit.t < t_max && closest.is_none_or(|h| hit.t < h.t) {
+              closest = Some(hit);
+            }
+          }
+        }
+      } else {
+        stack[top] = node.right;
+        stack[top + 1] = index + 1;
+        top += 2;
+      }
+    }
+    closest
+  }
+
+  /**
+   * Every hit in front of the ray origin, nearest first.
+   */
+  pub fn intersect_all(&self, ray: &Ray) -> Vec<MeshHit> {
+    let mut hits = vec![];
+    if self.nodes.is_empty() {
+      return hits;
+    }
+    let inv_dir = Vector::new(1.0 / ray.direction.x, 1.0 / ray.direction.y, 1.0 / ray.direction.z);
+    let mut stack = [0; 64];
+    let mut top = 1;
+    while top > 0 {
+      top -= 1;
+      let index = stack[top];
+      let node = &self.nodes[index];
+      if !node.bounds.hit(ray, &inv_dir, f64::MAX) {
+        continue;
+      }
+      if node.count > 0 {
+        hits.extend((node.start..node.start + node.count).filter_map(|tri| self.intersect_triangle(tri, ray)));
+      } else {
+        stack[top] = node.right;
+        stack[top + 1] = index + 1;
+        top += 2;
+      }
+    }
+    hits.sort_by(|a, b| a.t.total_cmp(&b.t));
+    hits
+  }
+
+  // Möller-Trumbore
+  fn intersect_triangle(&self, tri: usize, ray: &Ray) -> Option<MeshHit> {
+    let [i0, i1, i2] = self.triangles[tri];
+    let p0 = &self.vertices[i0];
+    let e1 = self.vertices[i1].sub(p0);
+    let e2 = self.vertices[i2].sub(p0);
+    let p = ray.direction.cross(&e2);
+    let det = e1.dot(&p);
+    if det.abs() < 1e-12 {
+      return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin.sub(p0);
+    let u = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+      return None;
+    }
+    let q = s.cross(&e1);
+    let v = ray.direction.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+      return None;
+    }
+    let t = e2.dot(&q) * inv_det;
+    if t > 0.0 {
+      Some(MeshHit {
+        t,
+        triangle: tri,
+        u,
+        v,
+      })
+    } else {
+      None
+    }
+  }
+
+  // The (normalised) normal of the plane of a triangle.
+  pub fn face_normal(&self, tri: usize) -> Vector {
+    let [i0, i1, i2] = self.triangles[tri];
+    let p0 = &self.vertices[i0];
+    self.vertices[i1]
+      .sub(p0)
+      .cross(&self.vertices[i2].sub(p0))
+      .normalise()
+  }
+
+  /**
+   * The (normalised) normal at a hit, smooth if the mesh has vertex normals.
+   */
+  pub fn normal(&self, hit: &MeshHit) -> Vector {
+    let [i0, i1, i2] = self.triangles[hit.triangle];
+    if self.normals.len() == self.vertices.len() {
+      let w = 1.0 - hit.u - hit.v;
+      self.normals[i0]
+        .scale(w)
+        .add(&self.normals[i1].scale(hit.u))
+        .add(&self.normals[i2].scale(hit.v))
+        .normalise()
+    } else {
+      self.face_normal(hit.triangle)
+    }
+  }
+
+  // The colour at a hit, blended from the vertex colours.  None without them.
+  pub fn colour(&self, hit: &MeshHit) -> Option<Vector> {
+    if self.colours.is_empty() {
+      return None;
+    }
+    let [i0, i1, i2] = self.triangles[hit.triangle];
+    let w = 1.0 - hit.u - hit.v;
+    Some(
+      self.colours[i0]
+        .scale(w)
+        .add(&self.colours[i1].scale(hit.u))
+        .add(&self.colours[i2].scale(hit.v)),
+    )
+  }
+
+  /**
+   * A torus around the Y axis, centred on the origin.
+   */
+  pub fn torus(major_radius: f64, minor_radius: f64, rings: usize, sides: usize) -> Mesh {
+    let mut vertices = vec![];
+    let mut normals = vec![];
+    let mut triangles = vec![];
+    for i in 0..rings {
+      let (sin_u, cos_u) = (2.0 * f64::consts::PI * i as f64 / rings as f64).sin_cos();
+      for j in 0..sides {
+        let (sin_v, cos_v) = (2.0 * f64::consts::PI * j as f64 / sides as f64).sin_cos();
+        let n = Vector::new(cos_v * cos_u, sin_v, cos_v * sin_u);
+        let c = Vector::new(major_radius * cos_u, 0.0, major_radius * sin_u);
+        vertices.push(c.add(&n.scale(minor_radius)));
+        normals.push(n);
+
+        // Two triangles to the next ring and side, wound to face outwards
+        let a = i * sides + j;
+        let b = ((i + 1) % rings) * sides + j;
+        let c = ((i + 1) % rings) * sides + (j + 1) % sides;
+        let d = i * sides + (j + 1) % sides;
+        triangles.push([a, d, b]);
+        triangles.push([b, d, c]);
+      }
+    }
+    Mesh::new(vertices, normals, triangles)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::mesh::Mesh;
+  use crate::objects::Ray;
+  use crate::vector::Vector;
+
+  fn quad() -> Mesh {
+    let vertices = vec![
+      Vector::new(-1.0, -1.0, 0.0),
+      Vector::new(1.0, -1.0, 0.0),
+      Vector::new(1.0, 1.0, 0.0),
+      Vector::new(-1.0, 1.0, 0.0),
+    ];
+    Mesh::new(vertices, vec![], vec![[0, 1, 2], [0, 2, 3]])
+  }
+
+  #[test]
+  fn mesh_hits_quad() {
+    let mesh = quad();
+    let hit = mesh
+      .intersect(&Ray::new(Vector::new(0.5, 0.5, -2.0), Vector::new(0.0, 0.0, 1.0)))
+      .unwrap();
+    assert!((hit.t - 2.0).abs() < 1e-9);
+    assert!(mesh.normal(&hit).equals(&Vector::new(0.0, 0.0, 1.0)));
+
+    assert!(mesh
+      .intersect(&Ray::new(Vector::new(1.5, 0.5, -2.0), Vector::new(0.0, 0.0, 1.0)))
+      .is_none());
+    assert!(mesh
+      .intersect(&Ray::new(Vector::new(0.5, 0.5, -2.0), Vector::new(0.0, 0.0, -1.0)))
+      .is_none());
+  }
+
+  #[test]
+  fn mesh_bvh_matches_brute_force() {
+    let mesh = Mesh::torus(2.0, 0.5, 24, 12);
+    for i in 0..50 {
+      let a = i as f64 * 0.37;
+      let ray = Ray::new(
+        Vector::new(6.0 * a.cos(), 1.0 - (i % 3) as f64, 6.0 * a.sin()),
+        Vector::new(-a.cos(), 0.05 * (i % 5) as f64 - 0.1, -a.sin() + 0.1),
+      );
+      let brute = (0..mesh.triangles.len())
+        .filter_map(|tri| mesh.intersect_triangle(tri, &ray))
+        .map(|h| h.t)
+        .fold(f64::MAX, f64::min);
+      let bvh = mesh.intersect(&ray).map_or(f64::MAX, |h| h.t);
+      assert!((brute - bvh).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn mesh_torus_faces_out() {
+    let mesh = Mesh::torus(2.0, 0.5, 24, 12);
+    let hit = mesh
+      .intersect(&Ray::new(Vector::new(10.0, 0.0, 0.0), Vector::new(-1.0, 0.0, 0.0)))
+      .unwrap();
+    assert!((hit.t - 7.5).abs() < 0.05);
+    assert!(mesh.normal(&hit).x > 0.9);
+  }
+
+  #[test]
+  fn mesh_with_nan_vertex_builds() {
+    let torus = Mesh::torus(2.0, 0.5, 24, 12);
+    let mut vertices = torus.vertices.clone();
+    vertices[5].y = f64::NAN;
+    let mesh = Mesh::new(vertices, vec![], torus.triangles.clone());
+    assert!(mesh
+      .intersect(&Ray::new(Vector::new(10.0, 0.0, 0.0), Vector::new(-1.0, 0.0, 0.0)))
+      .is_some());
+  }
+}
@@ -0,0 +1,570 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Load triangle meshes from PLY (ASCII or binary) and STL (ASCII or binary)
+// files, as scanners and CAD programs write them.
+//
+// From a PLY we take the vertex positions, normals and colours, and the
+// faces, splitting polygons into triangles.  Other elements and properties
+// are skipped.  STL has no shared vertices, normals or colours, so its
+// triangles are flat shaded.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::mesh::Mesh;
+use crate::vector::Vector;
+
+#[derive(Debug, PartialEq)]
+pub struct MeshFileError {
+  pub message: String,
+}
+
+impl fmt::Display for MeshFileError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+fn error<T>(message: String) -> Result<T, MeshFileError> {
+  Err(MeshFileError { message })
+}
+
+pub fn load_file(path: &Path) -> Result<Mesh, MeshFileError> {
+  match std::fs::read(path) {
+    Ok(data) => load(&data),
+    Err(e) => error(format!("can't read {}: {}", path.display(), e)),
+  }
+}
+
+/**
+ * Load a PLY or STL mesh, telling which from the contents.
+ */
+pub fn load(data: &[u8]) -> Result<Mesh, MeshFileError> {
+  if data.starts_with(b"ply") {
+    load_ply(data)
+  } else {
+    load_stl(data)
+  }
+}
+
+// A mesh, if every vertex is somewhere.  NaN or infinite ones can't be built into a BVH.
+fn new_mesh(vertices: Vec<Vector>, normals: Vec<Vector>, triangles: Vec<[usize; 3]>) -> Result<Mesh, MeshFileError> {
+  if let Some(i) = vertices.iter().position(|v| !(v.x.is_finite() && v.y.is_finite() && v.z.is_finite())) {
+    return error(format!("vertex {} isn't finite", i));
+  }
+  Ok(Mesh::new(vertices, normals, triangles))
+}
+
+/*************************************************************************
+ *                                 PLY                                   *
+ *************************************************************************/
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+  Ascii,
+  LittleEndian,
+  BigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+  I8,
+  U8,
+  I16,
+  U16,
+  I32,
+  U32,
+  F32,
+  F64,
+}
+
+impl Scalar {
+  fn parse(name: &str) -> Option<Scalar> {
+    Some(match name {
+      "char" | "int8" => Scalar::I8,
+      "uchar" | "uint8" => Scalar::U8,
+      "short" | "int16" => Scalar::I16,
+      "ushort" | "uint16" => Scalar::U16,
+      "int" | "int32" => Scalar::I32,
+      "uint" | "uint32" => Scalar::U32,
+      "float" | "float32" => Scalar::F32,
+      "double" | "float64" => Scalar::F64,
+      _ => return None,
+    })
+  }
+
+  fn size(self) -> usize {
+    match self {
+      Scalar::I8 | Scalar::U8 => 1,
+      Scalar::I16 | Scalar::U16 => 2,
+      Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+      Scalar::F64 => 8,
+    }
+  }
+
+  // The most an integer colour channel can be.
+  fn colour_max(self) -> f64 {
+    match self {
+      Scalar::I8 => 127.0,
+      Scalar::U8 => 255.0,
+      Scalar::I16 => 32767.0,
+      Scalar::U16 => 65535.0,
+      Scalar::I32 => 2_147_483_647.0,
+      Scalar::U32 => 4_294_967_295.0,
+      Scalar::F32 | Scalar::F64 => 1.0,
+    }
+  }
+}
+
+struct Property {
+  name: String,
+  typ: Scalar,
+  count: Option<Scalar>, // The type of the length of a list
+}
+
+struct Element {
+  name: String,
+  count: usize,
+  properties: Vec<Property>,
+}
+
+// Reads values from the body of a PLY file.
+struct Reader<'a> {
+  format: Format,
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn read(&mut self, typ: Scalar) -> Result<f64, MeshFileError> {
+    if self.format == Format::Ascii {
+      while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+        self.pos += 1;
+      }
+      let start = self.pos;
+      while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+        self.pos += 1;
+      }
+      let word = std::str::from_utf8(&self.data[start..self.pos]).unwrap_or("");
+      return match word.parse::<f64>() {
+        Ok(v) => Ok(v),
+        Err(_) if word.is_empty() => error("the PLY file ends early".to_string()),
+        Err(_) => error(format!("bad number {} in the PLY file", word)),
+      };
+    }
+
+    let size = typ.size();
+    let mut b = [0u8; 8];
+    match self.data.get(self.pos..self.pos + size) {
+      Some(bytes) => b[..size].copy_from_slice(bytes),
+      None => return error("the PLY file ends early".to_string()),
+    }
+    self.pos += size;
+    if self.format == Format::BigEndian {
+      b[..size].reverse();
+    }
+    Ok(match typ {
+      Scalar::I8 => b[0] as i8 as f64,
+      Scalar::U8 => b[0] as f64,
+      Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+      Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+      Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+      Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+      Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+      Scalar::F64 => f64::from_le_bytes(b),
+    })
+  }
+}
+
+fn load_ply(data: &[u8]) -> Result<Mesh, MeshFileError> {
+  let (format, elements, body) = ply_header(data)?;
+  let mut reader = Reader {
+    format,
+    data: &data[body..],
+    pos: 0,
+  };
+
+  let (mut vertices, mut normals, mut colours, mut triangles) = (vec![], vec![], vec![], vec![]);
+  for element in &elements {
+    let find = |name: &str| element.properties.iter().position(|p| p.name == name && p.count.is_none());
+    let position = [find("x"), find("y"), find("z")];
+    let normal = [find("nx"), find("ny"), find("nz")];
+    let colour = [find("red"), find("green"), find("blue")];
+    let face = element
+      .properties
+      .iter()
+      .position(|p| p.count.is_some() && (p.name == "vertex_indices" || p.name == "vertex_index"));
+    if element.name == "vertex" && position.iter().any(Option::is_none) {
+      return error("PLY vertices need x, y and z".to_string());
+    }
+    if element.name == "face" && face.is_none() {
+      return error("PLY faces need vertex_indices".to_string());
+    }
+
+    // Nothing to read, however many there are
+    if element.properties.is_empty() {
+      continue;
+    }
+    let mut values = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+      for (i, property) in element.properties.iter().enumerate() {
+        match property.count {
+          None => values[i] = reader.read(property.typ)?,
+          Some(count_type) => {
+            let count = reader.read(count_type)? as usize;
+            // Not preallocated, the count may be nonsense, and then the file ends early
+            let mut list = vec![];
+            for _ in 0..count {
+              list.push(reader.read(property.typ)? as usize);
+            }
+            // Split polygons into a fan of triangles
+            if element.name == "face" && Some(i) == face {
+              for k in 1..count.saturating_sub(1) {
+                triangles.push([list[0], list[k], list[k + 1]]);
+              }
+            }
+          }
+        }
+      }
+      if element.name == "vertex" {
+        let vector = |p: [Option<usize>; 3], scale: f64| {
+          Vector::new(values[p[0].unwrap()], values[p[1].unwrap()], values[p[2].unwrap()]).scale(scale)
+        };
+        vertices.push(vector(position, 1.0));
+        if normal.iter().all(Option::is_some) {
+          let normal = vector(normal, 1.0);
+          let length = normal.length();
+          if length == 0.0 || !length.is_finite() {
+            return error(format!("the normal of PLY vertex {} is zero length or not finite", vertices.len() - 1));
+          }
+          normals.push(normal.normalise());
+        }
+        if colour.iter().all(Option::is_some) {
+          let max = element.properties[colour[0].unwrap()].typ.colour_max();
+          colours.push(vector(colour, 1.0 / max));
+        }
+      }
+    }
+  }
+
+  if triangles.iter().flatten().any(|&i| i >= vertices.len()) {
+    return error("a PLY face uses a vertex that isn't there".to_string());
+  }
+  let mesh = new_mesh(vertices, normals, triangles)?;
+  Ok(if colours.is_empty() { mesh } else { mesh.with_colours(colours) })
+}
+
+// The format, the elements and where the body starts.
+fn ply_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize), MeshFileError> {
+  let end = match data.windows(10).position(|w| w == b"end_header") {
+    Some(end) => end,
+    None => return error("the PLY file has no end_header".to_string()),
+  };
+  // The body starts on the line after end_header
+  let body = match data[end..].iter().position(|&b| b == b'\n') {
+    Some(newline) => end + newline + 1,
+    None => data.len(),
+  };
+  let header = String::from_utf8_lossy(&data[..end]);
+
+  let mut format = None;
+  let mut elements: Vec<Element> = vec![];
+  for line in header.lines().skip(1) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+      [] | ["comment", ..] | ["obj_info", ..] => (),
+      ["format", name, _] => {
+        format = Some(match *name {
+          "ascii" => Format::Ascii,
+          "binary_little_endian" => Format::LittleEndian,
+          "binary_big_endian" => Format::BigEndian,
+          _ => return error(format!("unknown PLY format {}", name)),
+        })
+      }
+      ["element", name, count] => match count.parse() {
+        Ok(count) => elements.push(Element {
+          name: name.to_string(),
+          count,
+          properties: vec![],
+        }),
+        Err(_) => return error(format!("bad PLY element count {}", count)),
+      },
+      ["property", rest @ ..] => {
+        let property = match rest {
+          [typ, name] => Scalar::parse(typ).map(|typ| Property {
+            name: name.to_string(),
+            typ,
+            count: None,
+          }),
+          ["list", count, typ, name] => match (Scalar::parse(count), Scalar::parse(typ)) {
+            (Some(count), Some(typ)) => Some(Property {
+              name: name.to_string(),
+              typ,
+              count: Some(count),
+            }),
+            _ => None,
+          },
+          _ => None,
+        };
+        match (property, elements.last_mut()) {
+          (Some(property), Some(element)) => element.properties.push(property),
+          _ => return error(format!("bad PLY property: {}", line)),
+        }
+      }
+      _ => return error(format!("bad PLY header line: {}", line)),
+    }
+  }
+  match format {
+    Some(format) => Ok((format, elements, body)),
+    None => error("the PLY header has no format".to_string()),
+  }
+}
+
+/*************************************************************************
+ *                                 STL                                   *
+ *************************************************************************/
+
+fn load_stl(data: &[u8]) -> Result<Mesh, MeshFileError> {
+  // ASCII files start with "solid", but so do some binary ones, so check
+  // the size matches the triangle count first.
+  if data.len() >= 84 {
+    let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    if count.checked_mul(50).and_then(|n| n.checked_add(84)) == Some(data.len()) {
+      return load_binary_stl(&data[84..], count);
+    }
+  }
+  if data.starts_with(b"solid") {
+    load_ascii_stl(&String::from_utf8_lossy(data))
+  } else {
+    error("not a PLY or STL file".to_string())
+  }
+}
+
+// Each triangle is a normal, three vertices and two bytes nobody agrees on.
+fn load_binary_stl(data: &[u8], count: usize) -> Result<Mesh, MeshFileError> {
+  let float = |at: usize| f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as f64;
+  let mut vertices = Vec::with_capacity(count * 3);
+  for tri in 0..count {
+    for corner in 0..3 {
+      let at = tri * 50 + 12 + corner * 12;
+      vertices.push(Vector::new(float(at), float(at + 4), float(at + 8)));
+    }
+  }
+  let triangles = (0..count).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+  new_mesh(vertices, vec![], triangles)
+}
+
+fn load_ascii_stl(text: &str) -> Result<Mesh, MeshFileError> {
+  let mut vertices = vec![];
+  let mut words = text.split_whitespace();
+  while let Some(word) = words.next() {
+    if word != "vertex" {
+      continue;
+    }
+    let mut v = [0.0; 3];
+    for x in &mut v {
+      *x = match words.next().map(str::parse::<f64>) {
+        Some(Ok(x)) => x,
+        _ => return error("an STL vertex needs 3 numbers".to_string()),
+      };
+    }
+    vertices.push(Vector::new(v[0], v[1], v[2]));
+  }
+  if vertices.len() % 3 != 0 {
+    return error("an STL facet needs 3 vertices".to_string());
+  }
+  let triangles = (0..vertices.len() / 3).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+  new_mesh(vertices, vec![], triangles)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::{COL_WHITE, ORIGIN};
+  use crate::mesh_file::load;
+  use crate::objects::{Eye, Object, Ray, Scene};
+  use crate::post::PostProcess;
+  use crate::scene_file;
+  use crate::vector::Vector;
+  use std::sync::Arc;
+
+  // A unit square in z = 0 as one quad, red on the left and blue on the right.
+  const ASCII_PLY: &str = "ply
+format ascii 1.0
+comment made by hand
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+element edge 1
+property int vertex1
+property int vertex2
+end_header
+0 0 0 0 0 -2 255 0 0
+1 0 0 0 0 -2 0 0 255
+1 1 0 0 0 -2 0 0 255
+0 1 0 0 0 -2 255 0 0
+4 0 1 2 3
+0 1
+";
+
+  fn check_square(data: &[u8]) {
+    let mesh = load(data).unwrap();
+    assert_eq!(mesh.triangles.len(), 2);
+    assert!(mesh.normals[0].equals(&Vector::new(0.0, 0.0, -1.0)));
+
+    // Vertex colours are what the surface is shaded with
+    let obj = Object::new_mesh(Arc::new(mesh), Vector::new(0.0, 0.0, 1.0), COL_WHITE, 0.0, 0.0, 0.1);
+    let ray = Ray::new(Vector::new(0.25, 0.5, 0.0), Vector::new(0.0, 0.0, 1.0));
+    let hit = obj.intersect(&ray).unwrap();
+    assert!(obj.surface_colour(&hit).equals(&Vector::new(0.75, 0.0, 0.25)));
+  }
+
+  // The same square in binary
+  fn binary_ply(big_endian: bool) -> Vec<u8> {
+    let header = ASCII_PLY.split("end_header\n").next().unwrap();
+    let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+    let mut data = header.replace("ascii", format).into_bytes();
+    data.extend_from_slice(b"end_header\n");
+    for line in ASCII_PLY.split("end_header\n").nth(1).unwrap().lines().take(4) {
+      let v: Vec<f32> = line.split(' ').map(|w| w.parse().unwrap()).collect();
+      for x in &v[..6] {
+        data.extend_from_slice(&if big_endian { x.to_be_bytes() } else { x.to_le_bytes() });
+      }
+      data.extend(v[6..].iter().map(|&c| c as u8));
+    }
+    data.push(4);
+    for i in &[0i32, 1, 2, 3, 0, 1] {
+      data.extend_from_slice(&if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+    }
+    data
+  }
+
+  #[test]
+  fn ply_ascii_and_binary() {
+    check_square(ASCII_PLY.as_bytes());
+    check_square(&binary_ply(false));
+    check_square(&binary_ply(true));
+
+    // The colours are saved with the scene
+    let mesh = Arc::new(load(ASCII_PLY.as_bytes()).unwrap());
+    let mut scene = Scene {
+      eye: Eye {
+        c: ORIGIN,
+        w: 1.0,
+        h: 1.0,
+        d: 1.0,
+      },
+      lights: vec![],
+      objs: vec![],
+      fog: None,
+      volumes: vec![],
+      caustics: false,
+      post: PostProcess::default(),
+    };
+    scene.add_object(Object::new_mesh(mesh.clone(), ORIGIN, COL_WHITE, 0.0, 0.0, 0.1));
+    let loaded = scene_file::load(&scene_file::save(&scene)).unwrap();
+    let loaded_mesh = loaded.objs[0].mesh.as_ref().unwrap();
+    assert!(loaded_mesh.colours.iter().zip(&mesh.colours).all(|(a, b)| a.equals(b)));
+  }
+
+  #[test]
+  fn stl_ascii_and_binary() {
+    let ascii = "solid square
+  facet normal 0 0 -1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 1 0
+    endloop
+  endfacet
+  facet normal 0 0 -1
+    outer loop
+      vertex 0 0 0
+      vertex 1 1 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid square
+";
+    let mesh = load(ascii.as_bytes()).unwrap();
+    assert_eq!(mesh.triangles.len(), 2);
+    assert!(mesh.colours.is_empty());
+
+    // A binary file whose header starts with "solid" too
+    let mut binary = b"solid but binary".to_vec();
+    binary.resize(80, 0);
+    binary.extend_from_slice(&2u32.to_le_bytes());
+    for tri in &[[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0]] {
+      for x in [0.0f32, 0.0, -1.0].iter().chain(tri) {
+        binary.extend_from_slice(&x.to_le_bytes());
+      }
+      binary.extend_from_slice(&[0, 0]);
+    }
+    let mesh = load(&binary).unwrap();
+    assert_eq!(mesh.triangles.len(), 2);
+    let ray = Ray::new(Vector::new(0.25, 0.75, -1.0), Vector::new(0.0, 0.0, 1.0));
+    assert!((mesh.intersect(&ray).unwrap().t - 1.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn mesh_file_errors() {
+    let message = |data: &[u8]| match load(data) {
+      Ok(_) => panic!("expected an error"),
+      Err(e) => e.message,
+    };
+    assert_eq!(message(b"hello"), "not a PLY or STL file");
+    assert_eq!(message(b"ply\nformat ascii 1.0\n"), "the PLY file has no end_header");
+    assert_eq!(
+      message(ASCII_PLY.replace("4 0 1 2 3", "4 0 1 2 7").as_bytes()),
+      "a PLY face uses a vertex that isn't there"
+    );
+    assert_eq!(message(&binary_ply(false)[..400]), "the PLY file ends early");
+    assert_eq!(message(b"solid x\nfacet\nvertex 0 0 0\nvertex 1 0\nendsolid"), "an STL vertex needs 3 numbers");
+    assert_eq!(
+      message(b"solid x\nfacet\nvertex 0 0 0\nvertex 1 0 0\nvertex nan 1 0\nendsolid"),
+      "vertex 2 isn't finite"
+    );
+    assert_eq!(
+      message(ASCII_PLY.replace("1 1 0 0 0 -2", "1 1 inf 0 0 -2").as_bytes()),
+      "vertex 2 isn't finite"
+    );
+    assert_eq!(
+      message(ASCII_PLY.replace("1 0 0 0 0 -2", "1 0 0 0 0 0").as_bytes()),
+      "the normal of PLY vertex 1 is zero length or not finite"
+    );
+    // A list that claims to be enormous just runs out of file
+    let mut huge = binary_ply(false);
+    let face = huge.len() - 6 * 4 - 1;
+    huge[face] = 255;
+    assert_eq!(message(&huge), "the PLY file ends early");
+  }
+}
@@ -22,25 +22,82 @@
  *********************************************************************/
 #![allow(clippy::many_single_char_names)]
 use std::f64;
+use std::sync::Arc;
 
-use crate::constants::{COL_SQUARE_1, COL_SQUARE_2, COL_WHITE, ORIGIN};
+use crate::animation::Animation;
+use crate::constants::{COL_BLACK, COL_SQUARE_1, COL_SQUARE_2, COL_WHITE, NUM_COLS, NUM_ROWS, OBJECT_ID_NONE, ORIGIN};
+use crate::csg::{intersect_csg, Csg, CsgOp};
+use crate::mesh::{Aabb, Mesh};
+use crate::post::PostProcess;
+use crate::sdf::{intersect_sdf, Sdf};
+use crate::transform::Transform;
 use crate::vector::Vector;
 use crate::vector::Vector as Colour;
+use crate::volume::{Medium, Volume};
 
-pub enum HasIntersection {
-  Yes { col: Colour, t: f64, pi: Vector },
-  No,
+/**
+ * Where a ray hits an object.  Both normals are normalised and face back
+ * along the ray, `front_face` says whether the ray hit the outside.  The
+ * geometric normal is that of the true surface, the shading normal may be
+ * smoothed (as on a mesh).  `u` and `v` are surface coordinates, their
+ * meaning depends on the primitive.
+ */
+#[derive(Clone, Copy)]
+pub struct Hit {
+  pub t: f64,
+  pub pi: Vector,
+  pub geom_norm: Vector,
+  pub norm: Vector,
+  pub front_face: bool,
+  pub u: f64,
+  pub v: f64,
+  pub col: Colour,
+  pub obj_id: usize, // Filled in by the scene, OBJECT_ID_NONE until then
+}
+
+impl Hit {
+  /**
+   * A hit at `t` along `ray`, with normals pointing out of the object.  Call
+   * face() to turn them towards the ray.
+   */
+  pub fn new(ray: &Ray, t: f64, geom_norm: Vector, norm: Vector, col: Colour, u: f64, v: f64) -> Hit {
+    Hit {
+      t,
+      pi: ray.origin.add(&ray.direction.scale(t)),
+      geom_norm,
+      norm,
+      front_face: true,
+      u,
+      v,
+      col,
+      obj_id: OBJECT_ID_NONE,
+    }
+  }
+
+  // Turn the outward normals to face back along `ray`.
+  pub fn face(mut self, ray: &Ray) -> Hit {
+    self.front_face = ray.direction.dot(&self.geom_norm) < 0.0;
+    if !self.front_face {
+      self.geom_norm = self.geom_norm.scale(-1.0);
+      self.norm = self.norm.scale(-1.0);
+    }
+    self
+  }
 }
 
 #[derive(Clone, PartialEq)]
 pub enum ObjectType {
   Sphere,
   Disc,
+  Mesh,
+  Csg,
+  Sdf,
 }
 
 /**
  * Make a sphere.
  */
+#[derive(Clone)]
 pub struct Object {
   pub typ: ObjectType,
   pub c: Vector,   // Center position Vector
@@ -58,54 +115,53 @@ pub struct Object {
   pub d: f64,   // like .n above.
   pub rendered: bool,
   pub velocity: Vector,
+  pub dynamic: bool, // Moved by the physics simulation
+  pub animation: Animation,
+  // Object space to world space, on top of `c` and `n`.  None is the identity.
+  pub transform: Option<Transform>,
+  pub mesh: Option<Arc<Mesh>>, // Shared between instances
+  pub csg: Option<Arc<Csg>>,
+  pub sdf: Option<Arc<Sdf>>,
+  pub emission: Colour,       // Colour of the light it gives off
+  pub emission_strength: f64, // 0.0 doesn't glow
+}
+
+// A point on a glowing object that lights the scene as if it were a patch of
+// `area`.  `norm` is the way the patch faces, None if it faces every way.
+pub struct EmitterSample {
+  pub pi: Vector,
+  pub norm: Option<Vector>,
+  pub area: f64,
 }
 
 // Intersection with a disc from a ray coming from [px, py, pz] with direction Vector [vx, vy, vz].
-fn intersect_disc(
-  dist_c: &Vector,
-  disc_radius: f64,
-  _col: &Colour,
-  disc_n: &Vector,
-  disc_d: f64,
-  ray: &Ray,
-) -> HasIntersection {
+// Both sides of the disc can be hit.
+fn intersect_disc(disc_c: &Vector, disc_radius: f64, disc_n: &Vector, disc_d: f64, ray: &Ray) -> Option<Hit> {
   let d = disc_n.dot(&ray.direction);
   let t = (disc_d - disc_n.dot(&ray.origin)) / d;
   if t > 0.0 {
-    let pi = ray.origin.add(&ray.direction.scale(t)).sub(&dist_c);
-    let pi_sub_c = pi.length();
-    if pi_sub_c < disc_radius {
+    let pi = ray.origin.add(&ray.direction.scale(t));
+    let local = pi.sub(disc_c);
+    if local.length() < disc_radius {
       let which_colour =
-        ((pi.x + 100.0).abs() as u8 & 255 % 2) ^ ((pi.z + 100.0).abs() as u8 & 255 % 2) != 0;
-
-      HasIntersection::Yes {
-        col: if which_colour {
-          COL_SQUARE_1
-        } else {
-          COL_SQUARE_2
-        },
-        t,
-        pi,
-      }
+        ((local.x + 100.0).abs() as u8 & 1) ^ ((local.z + 100.0).abs() as u8 & 1) != 0;
+      let col = if which_colour {
+        COL_SQUARE_1
+      } else {
+        COL_SQUARE_2
+      };
+      Some(Hit::new(ray, t, *disc_n, *disc_n, col, local.x, local.z).face(ray))
     } else {
-      HasIntersection::No
+      None
     }
   } else {
-    HasIntersection::No
+    None
   }
 }
 
-// Sphere intersection
-fn intersect_sphere(
-  sphere_c: &Vector,
-  r: f64,
-  col: &Colour,
-  _n: &Vector,
-  _d: f64,
-  ray: &Ray,
-) -> HasIntersection {
-  // Intersection with a circle from a ray coming from [px, py, pz] direction [vx, vy, vz]
-
+// Sphere intersection, from outside or inside.  `u` goes around the Y axis,
+// `v` from the top to the bottom.
+fn intersect_sphere(sphere_c: &Vector, r: f64, col: &Colour, ray: &Ray) -> Option<Hit> {
   // Transform to local coordinates
   let local_p1 = ray.origin.sub(sphere_c);
 
@@ -113,24 +169,34 @@ fn intersect_sphere(
   let b = 2.0 * ray.direction.dot(&local_p1);
   let c = local_p1.dot(&local_p1) - (r * r);
 
-  // , or ray is in wrong direction (when t < zero)
-  if b <= 0.0 || c <= 0.0 {
-    let d = b * b - (4.0 * a * c);
-    if d >= 0.0 {
-      let sqrt_d = d.sqrt();
-      if -b - sqrt_d > 0.0 {
-        let t = (-b - sqrt_d) / (2.0 * a);
-        let pi = ray.origin.add(&ray.direction.scale(t));
-        HasIntersection::Yes { col: *col, t, pi }
-      } else {
-        HasIntersection::No
-      }
-    } else {
-      HasIntersection::No
-    }
-  } else {
-    HasIntersection::No
+  let d = b * b - (4.0 * a * c);
+  if d < 0.0 {
+    return None;
   }
+  // The near root, or the far one when the origin is inside
+  let sqrt_d = d.sqrt();
+  let t = if -b - sqrt_d > 0.0 {
+    (-b - sqrt_d) / (2.0 * a)
+  } else if -b + sqrt_d > 0.0 {
+    (-b + sqrt_d) / (2.0 * a)
+  } else {
+    return None;
+  };
+  let norm = ray.origin.add(&ray.direction.scale(t)).sub(sphere_c).normalise();
+  let u = 0.5 + norm.z.atan2(norm.x) / (2.0 * f64::consts::PI);
+  let v = norm.y.clamp(-1.0, 1.0).acos() / f64::consts::PI;
+  Some(Hit::new(ray, t, norm, norm, *col, u, v).face(ray))
+}
+
+// Mesh intersection, the mesh sits at `mesh_c`.  `u` and `v` are the
+// barycentric coordinates in the triangle.
+fn intersect_mesh(mesh: &Mesh, mesh_c: &Vector, col: &Colour, ray: &Ray) -> Option<Hit> {
+  let local_ray = Ray::new(ray.origin.sub(mesh_c), ray.direction);
+  mesh.intersect(&local_ray).map(|hit| {
+    let geom_norm = mesh.face_normal(hit.triangle);
+    let col = mesh.colour(&hit).map_or(*col, |c| c.product(col));
+    Hit::new(ray, hit.t, geom_norm, mesh.normal(&hit), col, hit.u, hit.v).face(ray)
+  })
 }
 
 impl Object {
@@ -142,6 +208,9 @@ impl Object {
     rfr: f64,
     ambient_light: f64,
   ) -> Object {
+    // A zero normal stays as it is, for `validate()` to turn down
+    let length = n.length();
+    let n = if length > 0.0 { n.scale(1.0 / length) } else { n };
     Object {
       typ: ObjectType::Disc,
       c,
@@ -157,6 +226,14 @@ impl Object {
       spec: 0.0,
       rendered: false,
       velocity: Vector::new(0.0, 0.0, 0.0),
+      dynamic: false,
+      animation: Animation::Static,
+      transform: None,
+      mesh: None,
+      csg: None,
+      sdf: None,
+      emission: COL_BLACK,
+      emission_strength: 0.0,
       ambient_light,
     }
   }
@@ -183,38 +260,406 @@ impl Object {
       can_receive_shadow: false,
       rendered: false,
       velocity: Vector::new(0.0, 0.0, 0.0),
+      dynamic: false,
+      animation: Animation::Static,
+      transform: None,
+      mesh: None,
+      csg: None,
+      sdf: None,
+      emission: COL_BLACK,
+      emission_strength: 0.0,
       ambient_light,
     }
   }
 
-  pub fn intersect(&self, ray: &Ray) -> HasIntersection {
-    if let ObjectType::Disc = self.typ {
-      intersect_disc(&self.c, self.radius, &self.col, &self.n, self.d, ray)
-    } else {
-      intersect_sphere(&self.c, self.radius, &self.col, &self.n, self.d, ray)
+  /**
+   * Make an object from a shared mesh, placed at `c`.  Clone the object to
+   * make another instance of the same mesh.
+   */
+  pub fn new_mesh(
+    mesh: Arc<Mesh>,
+    c: Vector,
+    col: Colour,
+    rfl: f64,
+    rfr: f64,
+    ambient_light: f64,
+  ) -> Object {
+    let mut obj = Object::new_sphere(c, 0.0, col, rfl, rfr, ambient_light);
+    obj.typ = ObjectType::Mesh;
+    obj.can_receive_shadow = true;
+    obj.mesh = Some(mesh);
+    obj
+  }
+
+  /**
+   * Combine two solids, placed at `c`.  The colour of a hit comes from the
+   * child that was hit, the rest of the material from this object.
+   */
+  pub fn new_csg(op: CsgOp, left: Object, right: Object, c: Vector) -> Object {
+    let mut obj = Object::new_sphere(c, 0.0, COL_WHITE, 0.0, 0.0, 0.2);
+    obj.typ = ObjectType::Csg;
+    obj.csg = Some(Arc::new(Csg { op, left, right }));
+    obj
+  }
+
+  /**
+   * Make an implicit surface, rendered by sphere tracing, placed at `c`.
+   */
+  pub fn new_sdf(sdf: Sdf, c: Vector, col: Colour, rfl: f64, rfr: f64, ambient_light: f64) -> Object {
+    let mut obj = Object::new_sphere(c, 0.0, col, rfl, rfr, ambient_light);
+    obj.typ = ObjectType::Sdf;
+    obj.can_receive_shadow = true;
+    obj.sdf = Some(Arc::new(sdf));
+    obj
+  }
+
+  /**
+   * Another instance of this object with its own transform.  Any mesh is
+   * shared, not copied.  Change the material of the copy as needed.
+   */
+  pub fn instance(&self, transform: Transform) -> Object {
+    let mut obj = self.clone();
+    obj.transform = Some(transform);
+    obj
+  }
+
+  /**
+   * The nearest hit in front of the ray origin, which may be inside the
+   * object.
+   */
+  pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    match &self.transform {
+      None => self.intersect_local(ray),
+      Some(transform) => self.intersect_local(&transform.ray_to_object(ray)).map(|hit| {
+        // t is the same in both spaces, and the normals still face the ray
+        Hit {
+          pi: ray.origin.add(&ray.direction.scale(hit.t)),
+          geom_norm: transform.normal_to_world(&hit.geom_norm),
+          norm: transform.normal_to_world(&hit.norm),
+          ..hit
+        }
+      }),
     }
   }
-  pub fn get_norm(&self, p: &Vector) -> Vector {
-    if let ObjectType::Disc = self.typ {
-      self.n
-    } else {
-      p.sub(&self.c)
+
+  // Intersect a ray that is already in object space.
+  fn intersect_local(&self, ray: &Ray) -> Option<Hit> {
+    match self.typ {
+      ObjectType::Disc => intersect_disc(&self.c, self.radius, &self.n, self.d, ray),
+      ObjectType::Sphere => intersect_sphere(&self.c, self.radius, &self.col, ray),
+      ObjectType::Mesh => self.mesh.as_ref().and_then(|mesh| intersect_mesh(mesh, &self.c, &self.col, ray)),
+      ObjectType::Csg => self.csg.as_ref().and_then(|csg| intersect_csg(csg, &self.c, ray)),
+      ObjectType::Sdf => self.sdf.as_ref().and_then(|sdf| intersect_sdf(sdf, &self.c, &self.col, ray)),
     }
   }
 
+  pub fn set_transform(&mut self, transform: Transform) {
+    self.transform = Some(transform);
+  }
+
+  // Move the object, keeping the plane of a disc in step.
+  pub fn set_position(&mut self, c: Vector) {
+    self.c = c;
+    self.d = c.dot(&self.n);
+  }
+
   pub fn set_diffuse(&mut self, diff: f64) {
     self.diff = diff;
     self.spec = 1.0 - diff;
   }
+
+  // Make the object glow, and light the scene around it.
+  pub fn set_emission(&mut self, col: Colour, strength: f64) {
+    self.emission = col;
+    self.emission_strength = strength;
+  }
+
+  /**
+   * Check the object can be rendered: a positive radius, a normal that
+   * isn't zero length, and finite, non-negative colours and materials.
+   */
+  pub fn validate(&self) -> Result<(), String> {
+    if !finite(&self.c) {
+      return Err("the position isn't finite".to_string());
+    }
+    match self.typ {
+      ObjectType::Sphere | ObjectType::Disc if !(self.radius > 0.0 && self.radius.is_finite()) => {
+        return Err(format!("the radius must be positive, not {}", self.radius));
+      }
+      ObjectType::Disc if !finite(&self.n) || self.n.length() < 0.5 => {
+        return Err("the normal can't be zero length".to_string());
+      }
+      // Hits, the physics and the dirty squares all take it as unit length
+      ObjectType::Disc if (self.n.length() - 1.0).abs() > 1e-9 => {
+        return Err("the normal needs to be unit length".to_string());
+      }
+      _ => (),
+    }
+    if !non_negative(&self.col) || !non_negative(&self.emission) {
+      return Err("colours can't be negative".to_string());
+    }
+    let material = [self.rfl, self.rfr, self.diff, self.spec, self.ambient_light, self.emission_strength];
+    if material.iter().any(|m| !(*m >= 0.0 && m.is_finite())) {
+      return Err("material values can't be negative".to_string());
+    }
+    if let Some(transform) = &self.transform {
+      if transform.matrix.m.iter().chain(&transform.inverse.m).flatten().any(|m| !m.is_finite()) {
+        return Err("the transform isn't finite".to_string());
+      }
+    }
+    if let Some(mesh) = &self.mesh {
+      if !mesh.vertices.iter().chain(&mesh.normals).chain(&mesh.colours).all(finite) {
+        return Err("the mesh has a vertex that isn't finite".to_string());
+      }
+    }
+    if let Some(sdf) = &self.sdf {
+      sdf.validate()?;
+    }
+    if let Some(csg) = &self.csg {
+      csg.left.validate()?;
+      csg.right.validate()?;
+    }
+    Ok(())
+  }
+
+  /**
+   * The colour the surface is lit with at a hit: the vertex colours of a mesh
+   * that has them, tinted by the object's colour, otherwise the object's.
+   */
+  pub fn surface_colour(&self, hit: &Hit) -> Colour {
+    match &self.mesh {
+      Some(mesh) if !mesh.colours.is_empty() => hit.col,
+      _ => self.col,
+    }
+  }
+
+  pub fn is_emissive(&self) -> bool {
+    self.emission_strength > 0.0 && self.emission.sum_elements() > 0.0
+  }
+
+  // The light given off by each unit of surface.
+  pub fn emitted(&self) -> Colour {
+    self.emission.scale(self.emission_strength)
+  }
+
+  /**
+   * A box around the object in world space.  A disc's box is as deep as it
+   * is wide, and an object without its mesh, CSG or SDF is empty.
+   */
+  pub fn bounds(&self) -> Aabb {
+    let mut local = Aabb::empty();
+    let mut grow_sphere = |c: &Vector, r: f64| {
+      local.grow(&c.sub(&Vector::new(r, r, r)));
+      local.grow(&c.add(&Vector::new(r, r, r)));
+    };
+    match self.typ {
+      ObjectType::Sphere => grow_sphere(&self.c, self.radius),
+      // Flat across its normal
+      ObjectType::Disc => {
+        let n = self.n.scale(1.0 / self.n.length());
+        let extent = |n: f64| self.radius * (1.0 - n * n).max(0.0).sqrt();
+        let e = Vector::new(extent(n.x), extent(n.y), extent(n.z));
+        local.grow(&self.c.sub(&e));
+        local.grow(&self.c.add(&e));
+      }
+      ObjectType::Sdf => {
+        if let Some(sdf) = &self.sdf {
+          grow_sphere(&self.c, sdf.bound());
+        }
+      }
+      ObjectType::Mesh => {
+        if let Some(mesh) = &self.mesh {
+          let b = mesh.bounds();
+          local.grow(&b.min.add(&self.c));
+          local.grow(&b.max.add(&self.c));
+        }
+      }
+      // Every child, whatever the operation
+      ObjectType::Csg => {
+        if let Some(csg) = &self.csg {
+          for child in [&csg.left, &csg.right] {
+            let b = child.bounds();
+            local.grow(&b.min.add(&self.c));
+            local.grow(&b.max.add(&self.c));
+          }
+        }
+      }
+    }
+    match &self.transform {
+      Some(transform) if local.min.x <= local.max.x => {
+        let mut world = Aabb::empty();
+        for corner in 0..8 {
+          let pick = |bit: usize, min: f64, max: f64| if corner & bit == 0 { min } else { max };
+          let p = Vector::new(
+            pick(1, local.min.x, local.max.x),
+            pick(2, local.min.y, local.max.y),
+            pick(4, local.min.z, local.max.z),
+          );
+          world.grow(&transform.point_to_world(&p));
+        }
+        world
+      }
+      _ => local,
+    }
+  }
+
+  /**
+   * Where to sample the light of a glowing object: a sphere is lit from its
+   * centre as a patch the size of its cross section, a mesh from the middle
+   * of each triangle.  Other objects don't light the scene, though they
+   * still glow.  Scaling a sphere with its transform doesn't change its
+   * light.
+   */
+  pub fn emitter_samples(&self) -> Vec<EmitterSample> {
+    let to_world = |p: &Vector| match &self.transform {
+      Some(transform) => transform.point_to_world(p),
+      None => *p,
+    };
+    match (&self.typ, &self.mesh) {
+      (ObjectType::Sphere, _) => vec![EmitterSample {
+        pi: to_world(&self.c),
+        norm: None,
+        area: f64::consts::PI * self.radius * self.radius,
+      }],
+      (ObjectType::Mesh, Some(mesh)) => mesh
+        .triangles
+        .iter()
+        .map(|tri| {
+          let [a, b, c] = tri.map(|i| to_world(&mesh.vertices[i].add(&self.c)));
+          let cross = b.sub(&a).cross(&c.sub(&a));
+          EmitterSample {
+            pi: a.add(&b).add(&c).scale(1.0 / 3.0),
+            norm: Some(cross.normalise()),
+            area: 0.5 * cross.length(),
+          }
+        })
+        .collect(),
+      _ => vec![],
+    }
+  }
+}
+
+// How far away a directional light is taken to be, for shadows
+const SUN_DISTANCE: f64 = 1.0e5;
+
+#[derive(Clone, Copy)]
+pub enum LightKind {
+  // Shines every way from `c`.
+  Point,
+  // Sunlight, shining along `direction` from far away.  `c` isn't used.
+  Directional { direction: Vector },
+  // Shines from `c` along `direction`, at full strength within `inner`
+  // degrees of it, fading out smoothly by `outer` degrees.
+  Spot { direction: Vector, inner: f64, outer: f64 },
 }
 
 // Light class, can have position and colour.
 pub struct Light {
   pub c: Vector,
   pub col: Colour,
+  pub kind: LightKind,
+}
+
+impl Light {
+  pub fn point(c: Vector, col: Colour) -> Light {
+    Light {
+      c,
+      col,
+      kind: LightKind::Point,
+    }
+  }
+
+  pub fn directional(direction: Vector, col: Colour) -> Light {
+    Light {
+      c: ORIGIN,
+      col,
+      kind: LightKind::Directional {
+        direction: direction.normalise(),
+      },
+    }
+  }
+
+  pub fn spot(c: Vector, direction: Vector, inner: f64, outer: f64, col: Colour) -> Light {
+    Light {
+      c,
+      col,
+      kind: LightKind::Spot {
+        direction: direction.normalise(),
+        inner,
+        outer,
+      },
+    }
+  }
+
+  // Check the light can be rendered, see `Object::validate()`.
+  pub fn validate(&self) -> Result<(), String> {
+    if !finite(&self.c) {
+      return Err("the position isn't finite".to_string());
+    }
+    if !non_negative(&self.col) {
+      return Err("colours can't be negative".to_string());
+    }
+    match self.kind {
+      LightKind::Point => Ok(()),
+      LightKind::Directional { direction } | LightKind::Spot { direction, .. }
+        if !finite(&direction) || direction.length() < 0.5 =>
+      {
+        Err("the direction can't be zero length".to_string())
+      }
+      LightKind::Spot { inner, outer, .. } if !(0.0 <= inner && inner <= outer && outer <= 180.0) => {
+        Err(format!("the cone angles must be 0 <= inner <= outer <= 180, not {} and {}", inner, outer))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /**
+   * The vector from `p` to the light, not normalised, and how strongly the
+   * light falls on `p` (0.0 to 1.0).  Anything along the vector is in the
+   * way of the light.
+   */
+  pub fn towards(&self, p: &Vector) -> (Vector, f64) {
+    match self.kind {
+      LightKind::Point => (self.c.sub(p), 1.0),
+      LightKind::Directional { direction } => (direction.scale(-SUN_DISTANCE), 1.0),
+      LightKind::Spot {
+        direction,
+        inner,
+        outer,
+      } => {
+        let l = self.c.sub(p);
+        let length = l.length();
+        // At the light itself there's no angle to it, so no light
+        if length == 0.0 {
+          return (l, 0.0);
+        }
+        let cos_angle = -l.dot(&direction) / length;
+        let cos_inner = inner.to_radians().cos();
+        let cos_outer = outer.to_radians().cos();
+        (l, smoothstep(cos_outer, cos_inner, cos_angle))
+      }
+    }
+  }
+}
+
+pub(crate) fn finite(v: &Vector) -> bool {
+  v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+fn non_negative(col: &Colour) -> bool {
+  finite(col) && col.x >= 0.0 && col.y >= 0.0 && col.z >= 0.0
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+  if edge1 <= edge0 {
+    return if x >= edge1 { 1.0 } else { 0.0 };
+  }
+  let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+  t * t * (3.0 - 2.0 * t)
 }
 
 // Make an eye, the observer. There can only be one observer.
+#[derive(Clone, Copy)]
 pub struct Eye {
   pub c: Vector, // center
   pub w: f64,    // width
@@ -222,11 +667,37 @@ pub struct Eye {
   pub d: f64,    // depth
 }
 
+// Anything closer to the eye than this in z isn't on the image.
+const EYE_NEAR: f64 = 1e-3;
+
+impl Eye {
+  /**
+   * Where `p` is on the image, as (col, row) in pixels between pixel
+   * centres.  None if it's not in front of the eye.
+   */
+  pub fn pixel_at(&self, p: &Vector) -> Option<(f64, f64)> {
+    // Pixel [row, col] looks along (-w/2 + (col + 1) * dx, h/2 - row * dy, d)
+    let v = p.sub(&self.c);
+    if v.z < EYE_NEAR {
+      return None;
+    }
+    let dx = self.w / (NUM_COLS as f64 - 1.0);
+    let dy = self.h / (NUM_ROWS as f64 - 1.0);
+    let col = (v.x / v.z * self.d + self.w / 2.0) / dx - 1.0;
+    let row = (self.h / 2.0 - v.y / v.z * self.d) / dy;
+    Some((col, row))
+  }
+}
+
 // Class to make the scene, can add objects, lights.  Requires an eye for constructor.
 pub struct Scene {
   pub eye: Eye,
-  pub lights: Vec<Light>, // The list of lights for the scene
-  pub objs: Vec<Object>,  // The list of objects in the scene
+  pub lights: Vec<Light>,   // The list of lights for the scene
+  pub objs: Vec<Object>,    // The list of objects in the scene
+  pub fog: Option<Medium>,  // Fills the whole scene
+  pub volumes: Vec<Volume>, // Bounded media
+  pub caustics: bool,       // Brighten light focused through glass
+  pub post: PostProcess,    // Applied to HDR frames before they're clamped
 }
 
 impl Scene {
@@ -236,6 +707,105 @@ impl Scene {
   pub fn add_object(&mut self, obj: Object) {
     self.objs.push(obj);
   }
+  pub fn add_volume(&mut self, volume: Volume) {
+    self.volumes.push(volume);
+  }
+
+  // Check everything in the scene can be rendered, the error says what can't.
+  pub fn validate(&self) -> Result<(), String> {
+    let eye = &self.eye;
+    if !finite(&eye.c) || [eye.w, eye.h, eye.d].iter().any(|x| !(*x > 0.0 && x.is_finite())) {
+      return Err("the eye needs a finite position and a positive size".to_string());
+    }
+    for (id, obj) in self.objs.iter().enumerate() {
+      obj.validate().map_err(|e| format!("object {}: {}", id, e))?;
+    }
+    for (id, light) in self.lights.iter().enumerate() {
+      light.validate().map_err(|e| format!("light {}: {}", id, e))?;
+    }
+    if let Some(fog) = &self.fog {
+      fog.validate().map_err(|e| format!("fog: {}", e))?;
+    }
+    for (id, volume) in self.volumes.iter().enumerate() {
+      let ok = volume.radius > 0.0 && volume.radius.is_finite() && finite(&volume.c);
+      if !ok {
+        return Err(format!("volume {}: needs a finite position and a positive radius", id));
+      }
+      volume.medium.validate().map_err(|e| format!("volume {}: {}", id, e))?;
+    }
+    self.post.validate().map_err(|e| format!("post-processing: {}", e))
+  }
+
+  /**
+   * The objects and lights as JSON, each with its id: its index in the list.
+   */
+  pub fn to_json(&self) -> String {
+    let objs: Vec<String> = self
+      .objs
+      .iter()
+      .enumerate()
+      .map(|(id, obj)| {
+        let typ = match obj.typ {
+          ObjectType::Sphere => "sphere",
+          ObjectType::Disc => "disc",
+          ObjectType::Mesh => "mesh",
+          ObjectType::Csg => "csg",
+          ObjectType::Sdf => "sdf",
+        };
+        format!(
+          "{{ \"id\": {}, \"type\": \"{}\", \"c\": {}, \"radius\": {}, \"col\": {}, \"rfl\": {}, \"rfr\": {}, \"diff\": {}, \"ambient\": {} }}",
+          id,
+          typ,
+          json_vector(&obj.c),
+          obj.radius,
+          json_vector(&obj.col),
+          obj.rfl,
+          obj.rfr,
+          obj.diff,
+          obj.ambient_light
+        )
+      })
+      .collect();
+    let lights: Vec<String> = self
+      .lights
+      .iter()
+      .enumerate()
+      .map(|(id, light)| {
+        let kind = match light.kind {
+          LightKind::Point => "\"type\": \"point\"".to_string(),
+          LightKind::Directional { direction } => {
+            format!("\"type\": \"directional\", \"direction\": {}", json_vector(&direction))
+          }
+          LightKind::Spot {
+            direction,
+            inner,
+            outer,
+          } => format!(
+            "\"type\": \"spot\", \"direction\": {}, \"inner\": {}, \"outer\": {}",
+            json_vector(&direction),
+            inner,
+            outer
+          ),
+        };
+        format!(
+          "{{ \"id\": {}, {}, \"c\": {}, \"col\": {} }}",
+          id,
+          kind,
+          json_vector(&light.c),
+          json_vector(&light.col)
+        )
+      })
+      .collect();
+    format!(
+      "{{ \"objects\": [{}], \"lights\": [{}] }}",
+      objs.join(", "),
+      lights.join(", ")
+    )
+  }
+}
+
+fn json_vector(v: &Vector) -> String {
+  format!("[{}, {}, {}]", v.x, v.y, v.z)
 }
 
 // A ray that gets cast.
@@ -255,3 +825,166 @@ impl Ray {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::COL_WHITE;
+  use crate::mesh::Mesh;
+  use crate::objects::{Light, LightKind, Object, Ray};
+  use crate::scenes::by_name;
+  use crate::sdf::Sdf;
+  use crate::transform::Transform;
+  use crate::vector::Vector;
+  use crate::volume::Medium;
+  use std::sync::Arc;
+
+  #[test]
+  fn sphere_hit_from_inside() {
+    let sphere = Object::new_sphere(Vector::new(0.0, 0.0, 0.0), 2.0, COL_WHITE, 0.0, 0.0, 0.2);
+    let ray = Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let hit = sphere.intersect(&ray).unwrap();
+    assert!((hit.t - 2.0).abs() < 1e-9);
+    assert!(!hit.front_face);
+    // Normalised, and facing back towards the centre
+    assert!(hit.norm.equals(&Vector::new(-1.0, 0.0, 0.0)));
+
+    let outside = Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let hit = sphere.intersect(&outside).unwrap();
+    assert!((hit.t - 3.0).abs() < 1e-9);
+    assert!(hit.front_face);
+    assert!(hit.geom_norm.equals(&Vector::new(-1.0, 0.0, 0.0)));
+  }
+
+  #[test]
+  fn disc_hit_is_in_world_space() {
+    let disc = Object::new_disc(
+      Vector::new(3.0, 1.0, 0.0),
+      Vector::new(0.0, 1.0, 0.0),
+      5.0,
+      0.0,
+      0.0,
+      0.2,
+    );
+    let ray = Ray::new(Vector::new(4.0, 3.0, 0.5), Vector::new(0.0, -1.0, 0.0));
+    let hit = disc.intersect(&ray).unwrap();
+    assert!(hit.pi.equals(&Vector::new(4.0, 1.0, 0.5)));
+    assert!(hit.front_face);
+
+    // From below the disc faces the other way
+    let below = Ray::new(Vector::new(4.0, -3.0, 0.5), Vector::new(0.0, 1.0, 0.0));
+    let hit = disc.intersect(&below).unwrap();
+    assert!(!hit.front_face);
+    assert!(hit.norm.equals(&Vector::new(0.0, -1.0, 0.0)));
+  }
+
+  #[test]
+  fn validate_scene_inputs() {
+    for name in &["default", "csg", "sdf", "fog", "emissive", "spotlights", "instances"] {
+      assert_eq!(by_name(name).unwrap().validate(), Ok(()), "{}", name);
+    }
+
+    let mut light = Light::directional(Vector::new(0.0, -1.0, 0.0), COL_WHITE);
+    assert_eq!(light.validate(), Ok(()));
+    light.kind = LightKind::Directional {
+      direction: Vector::new(0.0, 0.0, 0.0),
+    };
+    assert_eq!(light.validate(), Err("the direction can't be zero length".to_string()));
+
+    let c = Vector::new(0.0, 0.0, 0.0);
+    let sdf = |sdf: Sdf| Object::new_sdf(sdf, c, COL_WHITE, 0.0, 0.0, 0.2).validate();
+    assert!(sdf(Sdf::Sphere { radius: 1.0 }).is_ok());
+    assert!(sdf(Sdf::Sphere { radius: -1.0 }).is_err());
+    assert!(sdf(Sdf::Metaballs {
+      balls: vec![(c, f64::NAN)],
+      smoothness: 0.5
+    })
+    .is_err());
+    assert!(sdf(Sdf::Mandelbulb {
+      power: 8.0,
+      iterations: 1_000_000
+    })
+    .is_err());
+
+    let vertices = vec![c, Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, f64::INFINITY, 0.0)];
+    let mesh = Mesh::new(vertices, vec![], vec![[0, 1, 2]]);
+    let obj = Object::new_mesh(Arc::new(mesh), c, COL_WHITE, 0.0, 0.0, 0.2);
+    assert_eq!(obj.validate(), Err("the mesh has a vertex that isn't finite".to_string()));
+
+    // Discs are built with a unit normal, and it has to stay one
+    let mut disc = Object::new_disc(c, Vector::new(0.0, 2.0, 0.0), 5.0, 0.0, 0.0, 0.2);
+    assert!(disc.n.equals(&Vector::new(0.0, 1.0, 0.0)));
+    assert_eq!(disc.validate(), Ok(()));
+    disc.n = Vector::new(0.0, 2.0, 0.0);
+    assert_eq!(disc.validate(), Err("the normal needs to be unit length".to_string()));
+
+    let mut scene = by_name("fog").unwrap();
+    scene.fog = Some(Medium::new(-0.1, 0.1, 0.0));
+    assert_eq!(scene.validate(), Err("fog: absorption and scattering can't be negative".to_string()));
+    scene.fog = Some(Medium::new(0.1, 0.1, 1.0));
+    assert_eq!(scene.validate(), Err("fog: g must be between -1 and 1, not 1".to_string()));
+  }
+
+  #[test]
+  fn object_bounds() {
+    let disc = Object::new_disc(Vector::new(3.0, 1.0, 0.0), Vector::new(0.0, 1.0, 0.0), 5.0, 0.0, 0.0, 0.2);
+    let b = disc.bounds();
+    assert!(b.min.equals(&Vector::new(-2.0, 1.0, -5.0)));
+    assert!(b.max.equals(&Vector::new(8.0, 1.0, 5.0)));
+
+    let mut sphere = Object::new_sphere(Vector::new(0.0, 1.0, 0.0), 1.0, COL_WHITE, 0.0, 0.0, 0.2);
+    sphere.transform = Some(Transform::scale(&Vector::new(2.0, 1.0, 1.0)));
+    let b = sphere.bounds();
+    assert!(b.min.equals(&Vector::new(-2.0, 0.0, -1.0)));
+    assert!(b.max.equals(&Vector::new(2.0, 2.0, 1.0)));
+  }
+
+  #[test]
+  fn mesh_light_samples() {
+    let quad = Mesh::new(
+      vec![
+        Vector::new(0.0, 0.0, 0.0),
+        Vector::new(2.0, 0.0, 0.0),
+        Vector::new(2.0, 0.0, 3.0),
+      ],
+      vec![],
+      vec![[0, 1, 2]],
+    );
+    let mut panel = Object::new_mesh(Arc::new(quad), Vector::new(0.0, 5.0, 0.0), COL_WHITE, 0.0, 0.0, 0.2);
+    assert!(!panel.is_emissive());
+    panel.set_emission(COL_WHITE, 2.0);
+    assert!(panel.is_emissive());
+    let samples = panel.emitter_samples();
+    assert_eq!(samples.len(), 1);
+    assert!((samples[0].area - 3.0).abs() < 1e-9);
+    assert!(samples[0].pi.equals(&Vector::new(4.0 / 3.0, 5.0, 1.0)));
+    assert!(samples[0].norm.unwrap().equals(&Vector::new(0.0, -1.0, 0.0)));
+  }
+
+  #[test]
+  fn spot_light_falloff() {
+    let spot = Light::spot(
+      Vector::new(0.0, 10.0, 0.0),
+      Vector::new(0.0, -1.0, 0.0),
+      10.0,
+      20.0,
+      COL_WHITE,
+    );
+    let (l, strength) = spot.towards(&Vector::new(0.0, 0.0, 0.0));
+    assert!(l.equals(&Vector::new(0.0, 10.0, 0.0)));
+    assert!((strength - 1.0).abs() < 1e-9);
+    // 15 degrees off, half way through the falloff
+    let off = 10.0 * 15f64.to_radians().tan();
+    let (_, strength) = spot.towards(&Vector::new(off, 0.0, 0.0));
+    assert!(strength > 0.3 && strength < 0.7);
+    let (_, strength) = spot.towards(&Vector::new(10.0, 0.0, 0.0));
+    assert!(strength == 0.0);
+    // Right at the light there's no angle, and no NaN
+    let (_, strength) = spot.towards(&Vector::new(0.0, 10.0, 0.0));
+    assert!(strength == 0.0);
+
+    let sun = Light::directional(Vector::new(0.0, -2.0, 0.0), COL_WHITE);
+    let (l, strength) = sun.towards(&Vector::new(3.0, 0.0, 0.0));
+    assert!(l.normalise().equals(&Vector::new(0.0, 1.0, 0.0)));
+    assert!((strength - 1.0).abs() < 1e-9);
+  }
+}
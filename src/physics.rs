@@ -23,14 +23,233 @@
 
 use std::f64;
 
-use crate::objects::Object;
+use crate::objects::{Object, ObjectType};
+use crate::vector::Vector;
 
-// A **very** simple physics implimentation.
+// A simple rigid body simulation of the dynamic spheres: gravity, velocity
+// integration and collisions with restitution.  It runs with a fixed time
+// step, always counted from time zero, so the state at time `t` is the same
+// however it was reached.  Snapshots taken along the way let it go back in
+// time without starting again from zero.
+
+// Simulation time step in seconds
+pub const FIXED_DT: f64 = 1.0 / 120.0;
+
+pub const GRAVITY: Vector = Vector {
+  x: 0.0,
+  y: -9.81,
+  z: 0.0,
+};
+
+// Below this closing speed a contact doesn't bounce, so objects can come to rest.
+const REST_SPEED: f64 = 0.05;
+
+// The most steps `RayTracer::catch_up_to()` runs at once, so a big jump in
+// time can't freeze the page.  The following calls run the rest.
+pub const MAX_STEPS_PER_CALL: u64 = 1200;
+
+// Steps between snapshots to start from, doubled whenever there are too many
+const SNAPSHOT_STEPS: u64 = 120;
+const MAX_SNAPSHOTS: usize = 64;
 
 #[derive(Clone)]
-pub struct Physics {}
+pub struct Physics {
+  pub gravity: Vector,
+  pub restitution: f64, // 0.0 (dead) to 1.0 (perfectly elastic)
+  steps: u64,
+  start: Vec<(usize, Vector, Vector)>, // Id, position and velocity at time zero
+  snapshots: Vec<(u64, Vec<(Vector, Vector)>)>, // Steps, and the position and velocity of each of `start`
+  snapshot_steps: u64,
+}
 
 impl Physics {
+  /**
+   * Remember where the dynamic objects start, so we can go back in time.
+   */
+  pub fn new(objs: &[Object]) -> Physics {
+    Physics {
+      gravity: GRAVITY,
+      restitution: 0.8,
+      steps: 0,
+      snapshots: vec![],
+      snapshot_steps: SNAPSHOT_STEPS,
+      start: objs
+        .iter()
+        .enumerate()
+        .filter(|(_, obj)| obj.dynamic)
+        .map(|(id, obj)| (id, obj.c, obj.velocity))
+        .collect(),
+    }
+  }
+
+  /**
+   * Run the simulation up to time `t`, in whole steps.  Going backwards
+   * starts again from the last snapshot before `t`.
+   */
+  pub fn advance_to(&mut self, objs: &mut [Object], t: f64) {
+    self.advance_towards(objs, t, u64::MAX);
+  }
+
+  // Like `advance_to()`, but run at most `max_steps` steps.  Returns whether it got to `t`.
+  pub fn advance_towards(&mut self, objs: &mut [Object], t: f64, max_steps: u64) -> bool {
+    if self.start.is_empty() {
+      return true;
+    }
+    let target = (t.max(0.0) / FIXED_DT).floor() as u64;
+    if target < self.steps {
+      self.restart(objs, target);
+    }
+    let end = target.min(self.steps.saturating_add(max_steps));
+    while self.steps < end {
+      self.step(objs, FIXED_DT);
+      self.steps += 1;
+      if self.steps.is_multiple_of(self.snapshot_steps) {
+        self.save(objs);
+      }
+    }
+    self.steps == target
+  }
+
+  // Forget object `id`, the objects after it move down one, as in the scene.
+  pub fn remove(&mut self, id: usize) {
+    self.snapshots.clear();
+    self.start.retain(|&(i, _, _)| i != id);
+    for start in &mut self.start {
+      if start.0 > id {
+        start.0 -= 1;
+      }
+    }
+  }
+
+  // Object `id` was put at `c` by hand, from now on it starts there at rest.
+  pub fn moved(&mut self, id: usize, c: Vector) {
+    self.snapshots.clear();
+    for start in self.start.iter_mut().filter(|start| start.0 == id) {
+      start.1 = c;
+      start.2 = Vector::new(0.0, 0.0, 0.0);
+    }
+  }
+
+  // Go back to the last snapshot at or before `target` steps, or to the start.
+  fn restart(&mut self, objs: &mut [Object], target: u64) {
+    while self.snapshots.last().is_some_and(|s| s.0 > target) {
+      self.snapshots.pop();
+    }
+    match self.snapshots.last() {
+      Some((steps, state)) => {
+        for (&(id, _, _), &(c, velocity)) in self.start.iter().zip(state) {
+          objs[id].set_position(c);
+          objs[id].velocity = velocity;
+        }
+        self.steps = *steps;
+      }
+      None => {
+        for &(id, c, velocity) in &self.start {
+          objs[id].set_position(c);
+          objs[id].velocity = velocity;
+        }
+        self.steps = 0;
+      }
+    }
+  }
+
+  fn save(&mut self, objs: &[Object]) {
+    let state = self.start.iter().map(|&(id, _, _)| (objs[id].c, objs[id].velocity)).collect();
+    self.snapshots.push((self.steps, state));
+    if self.snapshots.len() > MAX_SNAPSHOTS {
+      // Keep every other one, and take them half as often
+      self.snapshot_steps *= 2;
+      let every = self.snapshot_steps;
+      self.snapshots.retain(|s| s.0.is_multiple_of(every));
+    }
+  }
+
+  fn step(&self, objs: &mut [Object], dt: f64) {
+    // Semi-implicit Euler
+    for obj in objs.iter_mut().filter(|obj| obj.dynamic) {
+      obj.velocity.add_in_place(&self.gravity.scale(dt));
+      let c = obj.c.add(&obj.velocity.scale(dt));
+      obj.set_position(c);
+    }
+
+    for i in 0..objs.len() {
+      if !objs[i].dynamic || objs[i].typ != ObjectType::Sphere {
+        continue;
+      }
+      for j in 0..objs.len() {
+        if i == j {
+          continue;
+        }
+        match objs[j].typ {
+          ObjectType::Disc => self.collide_disc(objs, i, j),
+          // Only handle each dynamic pair once
+          ObjectType::Sphere if !objs[j].dynamic || j > i => self.collide_spheres(objs, i, j),
+          _ => {}
+        }
+      }
+    }
+  }
+
+  // Sphere `a` against the disc `b`.
+  fn collide_disc(&self, objs: &mut [Object], a: usize, b: usize) {
+    let (n, disc_c, disc_r) = (objs[b].n, objs[b].c, objs[b].radius);
+    let sphere = &mut objs[a];
+    let dist = sphere.c.sub(&disc_c).dot(&n);
+    if dist.abs() >= sphere.radius {
+      return;
+    }
+    // Only when the centre is over the disc
+    let on_plane = sphere.c.sub(&n.scale(dist));
+    if on_plane.sub(&disc_c).length() > disc_r {
+      return;
+    }
+
+    // Push out of the side we're on and bounce
+    let side = if dist >= 0.0 { 1.0 } else { -1.0 };
+    let c = sphere.c.add(&n.scale(side * sphere.radius - dist));
+    sphere.set_position(c);
+    let vn = sphere.velocity.dot(&n) * side;
+    if vn < 0.0 {
+      let e = if -vn < REST_SPEED { 0.0 } else { self.restitution };
+      sphere.velocity.sub_in_place(&n.scale(side * (1.0 + e) * vn));
+    }
+  }
+
+  // Sphere `a` (dynamic) against sphere `b`.
+  fn collide_spheres(&self, objs: &mut [Object], a: usize, b: usize) {
+    let d = objs[a].c.sub(&objs[b].c);
+    let dist = d.length();
+    let min_dist = objs[a].radius + objs[b].radius;
+    if dist >= min_dist || dist <= 0.0 {
+      return;
+    }
+    let n = d.scale(1.0 / dist);
+
+    // Heavier spheres move less, static ones don't move at all
+    let inv_a = 1.0 / mass(&objs[a]);
+    let inv_b = if objs[b].dynamic {
+      1.0 / mass(&objs[b])
+    } else {
+      0.0
+    };
+    let inv_sum = inv_a + inv_b;
+
+    let overlap = min_dist - dist;
+    let ca = objs[a].c.add(&n.scale(overlap * inv_a / inv_sum));
+    let cb = objs[b].c.sub(&n.scale(overlap * inv_b / inv_sum));
+    objs[a].set_position(ca);
+    objs[b].set_position(cb);
+
+    let vn = objs[a].velocity.sub(&objs[b].velocity).dot(&n);
+    if vn < 0.0 {
+      let e = if -vn < REST_SPEED { 0.0 } else { self.restitution };
+      let j = -(1.0 + e) * vn / inv_sum;
+      objs[a].velocity.add_in_place(&n.scale(j * inv_a));
+      objs[b].velocity.sub_in_place(&n.scale(j * inv_b));
+    }
+  }
+
+  // The old "physics", circle the Y axis by `angle` degrees.
   pub fn apply_forces(obj: &mut Object, mut angle: f64) {
     angle *= f64::consts::PI / 180.0;
     let sin_t = f64::sin(angle);
@@ -42,3 +261,105 @@ impl Physics {
     obj.c.z = z * cos_t + x * sin_t;
   }
 }
+
+// All spheres have the same density.
+fn mass(obj: &Object) -> f64 {
+  obj.radius * obj.radius * obj.radius
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::COL_WHITE;
+  use crate::objects::Object;
+  use crate::physics::{Physics, MAX_STEPS_PER_CALL};
+  use crate::vector::Vector;
+
+  fn ball(c: Vector, velocity: Vector) -> Object {
+    let mut obj = Object::new_sphere(c, 0.5, COL_WHITE, 0.0, 0.0, 0.2);
+    obj.dynamic = true;
+    obj.velocity = velocity;
+    obj
+  }
+
+  fn ground() -> Object {
+    Object::new_disc(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 6.0, 0.0, 0.0, 0.2)
+  }
+
+  #[test]
+  fn physics_ball_bounces_on_disc() {
+    let mut objs = vec![ground(), ball(Vector::new(0.0, 3.0, 0.0), Vector::new(0.0, 0.0, 0.0))];
+    let mut physics = Physics::new(&objs);
+    let mut lowest = f64::MAX;
+    let mut bounced = false;
+    for i in 1..300 {
+      physics.advance_to(&mut objs, i as f64 / 100.0);
+      lowest = lowest.min(objs[1].c.y);
+      bounced |= objs[1].velocity.y > 0.0;
+    }
+    assert!(bounced);
+    assert!(lowest > 0.5 - 0.1);
+  }
+
+  #[test]
+  fn physics_ball_falls_off_the_edge() {
+    let mut objs = vec![ground(), ball(Vector::new(8.0, 1.0, 0.0), Vector::new(0.0, 0.0, 0.0))];
+    let mut physics = Physics::new(&objs);
+    physics.advance_to(&mut objs, 1.0);
+    assert!(objs[1].c.y < -1.0);
+  }
+
+  #[test]
+  fn physics_spheres_exchange_momentum() {
+    let mut objs = vec![
+      ball(Vector::new(-1.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0)),
+      ball(Vector::new(1.0, 0.0, 0.0), Vector::new(-1.0, 0.0, 0.0)),
+    ];
+    let mut physics = Physics::new(&objs);
+    physics.gravity = Vector::new(0.0, 0.0, 0.0);
+    physics.restitution = 1.0;
+    physics.advance_to(&mut objs, 2.0);
+    assert!(objs[0].velocity.x < -0.99 && objs[1].velocity.x > 0.99);
+    assert!(objs[0].c.x < objs[1].c.x - 0.99);
+  }
+
+  #[test]
+  fn physics_is_reproducible() {
+    let start = vec![
+      ground(),
+      ball(Vector::new(0.2, 3.0, 0.0), Vector::new(0.3, 0.0, 0.0)),
+      ball(Vector::new(0.0, 1.0, 0.1), Vector::new(0.0, 0.0, 0.0)),
+    ];
+    let mut objs_a = start.clone();
+    let mut physics_a = Physics::new(&objs_a);
+    physics_a.advance_to(&mut objs_a, 2.5);
+
+    let mut objs_b = start;
+    let mut physics_b = Physics::new(&objs_b);
+    for i in 0..40 {
+      physics_b.advance_to(&mut objs_b, i as f64 * 0.13);
+    }
+    physics_b.advance_to(&mut objs_b, 2.5);
+
+    for (a, b) in objs_a.iter().zip(&objs_b) {
+      assert!(a.c.x == b.c.x && a.c.y == b.c.y && a.c.z == b.c.z);
+    }
+  }
+
+  #[test]
+  fn physics_big_jumps_are_spread_over_calls() {
+    let start = vec![ground(), ball(Vector::new(0.2, 3.0, 0.0), Vector::new(0.3, 0.0, 0.0))];
+    let mut objs_a = start.clone();
+    let mut physics_a = Physics::new(&objs_a);
+    assert!(!physics_a.advance_towards(&mut objs_a, 1e6, MAX_STEPS_PER_CALL));
+    assert_eq!(physics_a.steps, MAX_STEPS_PER_CALL);
+
+    // Going back starts from a snapshot, and gets the same as going forwards
+    let mut objs_b = start;
+    let mut physics_b = Physics::new(&objs_b);
+    physics_b.advance_to(&mut objs_b, 7.3);
+    physics_a.advance_to(&mut objs_a, 7.3);
+    for (a, b) in objs_a.iter().zip(&objs_b) {
+      assert!(a.c.x == b.c.x && a.c.y == b.c.y && a.c.z == b.c.z);
+    }
+  }
+}
@@ -0,0 +1,354 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+//
+// Post-processing of an HDR frame before it's clamped to 8 bits, in order:
+//
+//   bloom       bright highlights glow into the pixels around them
+//   lens        barrel or pincushion distortion, and chromatic aberration
+//               pulling red and blue apart towards the edges
+//   vignette    the corners darken
+//   grading     colours are mapped through a 3D lookup table
+//
+// Each is off until it's set, in the scene file or with
+// `RayTracer::set_post_process()`.
+//
+
+use std::f64;
+
+use crate::constants::NUM_COLS;
+use crate::temporal::HdrFrame;
+use crate::vector::Vector as Colour;
+
+// The widest bloom, to keep the cost of the blur bounded
+pub const MAX_BLOOM_RADIUS: f64 = 64.0;
+
+// The biggest colour grading table, 64^3 colours
+pub const MAX_LUT_SIZE: usize = 64;
+
+/**
+ * Light above `threshold` in any colour is blurred over `radius` pixels and
+ * added back, times `strength`.
+ */
+#[derive(Clone, Copy)]
+pub struct Bloom {
+  pub threshold: f64,
+  pub strength: f64,
+  pub radius: f64,
+}
+
+/**
+ * A `size` x `size` x `size` colour lookup table, for colours from 0 to 1.
+ * `colours` has red changing fastest, then green, then blue.
+ */
+#[derive(Clone)]
+pub struct Lut {
+  pub size: usize,
+  pub colours: Vec<Colour>,
+}
+
+impl Lut {
+  // The table that leaves every colour as it is.
+  pub fn identity(size: usize) -> Lut {
+    let step = 1.0 / (size as f64 - 1.0);
+    let mut colours = vec![];
+    for b in 0..size {
+      for g in 0..size {
+        for r in 0..size {
+          colours.push(Colour::new(r as f64 * step, g as f64 * step, b as f64 * step));
+        }
+      }
+    }
+    Lut { size, colours }
+  }
+
+  // Look `colour` up, between the entries of the table.
+  pub fn apply(&self, colour: &Colour) -> Colour {
+    let n = self.size - 1;
+    let at = |v: f64| {
+      let v = v.clamp(0.0, 1.0) * n as f64;
+      let i = (v.floor() as usize).min(n - 1);
+      (i, v - i as f64)
+    };
+    let ((r, fr), (g, fg), (b, fb)) = (at(colour.x), at(colour.y), at(colour.z));
+    let mut out = Colour::new(0.0, 0.0, 0.0);
+    for (db, wb) in [(0, 1.0 - fb), (1, fb)] {
+      for (dg, wg) in [(0, 1.0 - fg), (1, fg)] {
+        for (dr, wr) in [(0, 1.0 - fr), (1, fr)] {
+          let i = ((b + db) * self.size + g + dg) * self.size + r + dr;
+          out.add_in_place(&self.colours[i].scale(wr * wg * wb));
+        }
+      }
+    }
+    out
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct PostProcess {
+  pub bloom: Option<Bloom>,
+  pub distortion: f64,           // Barrel above 0, pincushion below
+  pub chromatic_aberration: f64, // How far red and blue are apart in the corners, in pixels
+  pub vignette: f64,             // How much darker the corners are, from 0 to 1
+  pub grading: Option<Lut>,
+}
+
+impl PostProcess {
+  // Whether there's anything to do.
+  pub fn is_on(&self) -> bool {
+    self.bloom.is_some()
+      || self.distortion != 0.0
+      || self.chromatic_aberration != 0.0
+      || self.vignette > 0.0
+      || self.grading.is_some()
+  }
+
+  // Why the settings can't be used, if they can't.
+  pub fn validate(&self) -> Result<(), String> {
+    if let Some(bloom) = &self.bloom {
+      if !(bloom.threshold >= 0.0 && bloom.strength >= 0.0 && bloom.threshold.is_finite() && bloom.strength.is_finite()) {
+        return Err("bloom threshold and strength can't be negative".to_string());
+      }
+      if !(0.0..=MAX_BLOOM_RADIUS).contains(&bloom.radius) {
+        return Err(format!("bloom radius needs to be from 0 to {}", MAX_BLOOM_RADIUS));
+      }
+    }
+    if !(-1.0..=1.0).contains(&self.distortion) {
+      return Err("lens distortion needs to be from -1 to 1".to_string());
+    }
+    if !(-64.0..=64.0).contains(&self.chromatic_aberration) {
+      return Err("chromatic aberration needs to be from -64 to 64 pixels".to_string());
+    }
+    if !(0.0..=1.0).contains(&self.vignette) {
+      return Err("vignette needs to be from 0 to 1".to_string());
+    }
+    if let Some(lut) = &self.grading {
+      if !(2..=MAX_LUT_SIZE).contains(&lut.size) || lut.colours.len() != lut.size * lut.size * lut.size {
+        return Err(format!(
+          "a colour grading table needs size^3 colours, and a size from 2 to {}",
+          MAX_LUT_SIZE
+        ));
+      }
+      if lut.colours.iter().any(|c| !(c.x.is_finite() && c.y.is_finite() && c.z.is_finite())) {
+        return Err("colour grading colours need to be finite".to_string());
+      }
+    }
+    Ok(())
+  }
+
+  // Post-process the colours of `frame`.
+  pub fn apply(&self, frame: &mut HdrFrame) {
+    let mut colours: Vec<Colour> = frame.pixels.iter().map(|pxl| pxl.colour).collect();
+    self.apply_to(&mut colours, NUM_COLS);
+    for (pxl, colour) in frame.pixels.iter_mut().zip(colours) {
+      pxl.colour = colour;
+    }
+  }
+
+  // Post-process an image `width` pixels wide.
+  fn apply_to(&self, colours: &mut Vec<Colour>, width: usize) {
+    if let Some(bloom) = &self.bloom {
+      add_bloom(bloom, colours, width);
+    }
+    if self.distortion != 0.0 || self.chromatic_aberration != 0.0 {
+      *colours = self.lens(colours, width);
+    }
+    if self.vignette > 0.0 {
+      let height = colours.len() / width;
+      for (i, colour) in colours.iter_mut().enumerate() {
+        let r = radius(i % width, i / width, width, height);
+        colour.scale_in_place((1.0 - self.vignette * r * r).max(0.0));
+      }
+    }
+    if let Some(lut) = &self.grading {
+      for colour in colours.iter_mut() {
+        *colour = lut.apply(colour);
+      }
+    }
+  }
+
+  // Each colour comes from a bit further out, or in, the further it is from the middle.
+  fn lens(&self, colours: &[Colour], width: usize) -> Vec<Colour> {
+    let height = colours.len() / width;
+    let (mid_x, mid_y) = ((width - 1) as f64 / 2.0, (height - 1) as f64 / 2.0);
+    let corner = (mid_x * mid_x + mid_y * mid_y).sqrt();
+    let shift = self.chromatic_aberration / 2.0 / corner;
+    (0..colours.len())
+      .map(|i| {
+        let (dx, dy) = ((i % width) as f64 - mid_x, (i / width) as f64 - mid_y);
+        let r = radius(i % width, i / width, width, height);
+        let scale = 1.0 + self.distortion * r * r;
+        let at = |s: f64| bilinear(colours, width, mid_x + dx * s, mid_y + dy * s);
+        Colour::new(at(scale + shift).x, at(scale).y, at(scale - shift).z)
+      })
+      .collect()
+  }
+}
+
+// How far [col, row] is from the middle of the image, 1 in the corners.
+fn radius(col: usize, row: usize, width: usize, height: usize) -> f64 {
+  let (mid_x, mid_y) = ((width - 1) as f64 / 2.0, (height - 1) as f64 / 2.0);
+  let (dx, dy) = (col as f64 - mid_x, row as f64 - mid_y);
+  ((dx * dx + dy * dy) / (mid_x * mid_x + mid_y * mid_y)).sqrt()
+}
+
+// The colour at [x, y], between pixels, clamped to the edges of the image.
+fn bilinear(colours: &[Colour], width: usize, x: f64, y: f64) -> Colour {
+  let height = colours.len() / width;
+  let x = x.clamp(0.0, (width - 1) as f64);
+  let y = y.clamp(0.0, (height - 1) as f64);
+  let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+  let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+  let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+  let top = colours[y0 * width + x0].scale(1.0 - fx).add(&colours[y0 * width + x1].scale(fx));
+  let bottom = colours[y1 * width + x0].scale(1.0 - fx).add(&colours[y1 * width + x1].scale(fx));
+  top.scale(1.0 - fy).add(&bottom.scale(fy))
+}
+
+// Blur what's above the threshold with a gaussian, across then down, and add it.
+fn add_bloom(bloom: &Bloom, colours: &mut [Colour], width: usize) {
+  let height = colours.len() / width;
+  let reach = bloom.radius.ceil() as i64;
+  let sigma = (bloom.radius / 2.0).max(0.5);
+  let kernel: Vec<f64> = (-reach..=reach).map(|d| (-((d * d) as f64) / (2.0 * sigma * sigma)).exp()).collect();
+  let total: f64 = kernel.iter().sum();
+
+  let bright: Vec<Colour> = colours
+    .iter()
+    .map(|c| {
+      Colour::new(
+        (c.x - bloom.threshold).max(0.0),
+        (c.y - bloom.threshold).max(0.0),
+        (c.z - bloom.threshold).max(0.0),
+      )
+    })
+    .collect();
+  let blur = |from: &[Colour], across: bool| -> Vec<Colour> {
+    (0..from.len())
+      .map(|i| {
+        let (col, row) = ((i % width) as i64, (i / width) as i64);
+        let mut sum = Colour::new(0.0, 0.0, 0.0);
+        for (k, d) in kernel.iter().zip(-reach..=reach) {
+          let (c, r) = if across { (col + d, row) } else { (col, row + d) };
+          if c >= 0 && r >= 0 && c < width as i64 && r < height as i64 {
+            sum.add_in_place(&from[r as usize * width + c as usize].scale(*k));
+          }
+        }
+        sum.scale(1.0 / total)
+      })
+      .collect()
+  };
+  let glow = blur(&blur(&bright, true), false);
+  for (colour, glow) in colours.iter_mut().zip(glow) {
+    colour.add_in_place(&glow.scale(bloom.strength));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::post::{Bloom, Lut, PostProcess};
+  use crate::vector::Vector as Colour;
+
+  const SIZE: usize = 15;
+
+  fn grey() -> Vec<Colour> {
+    vec![Colour::new(0.5, 0.5, 0.5); SIZE * SIZE]
+  }
+
+  #[test]
+  fn post_off_does_nothing() {
+    let post = PostProcess::default();
+    assert!(!post.is_on());
+    let mut colours = grey();
+    colours[17] = Colour::new(3.0, 0.0, 1.0);
+    let before = colours.clone();
+    post.apply_to(&mut colours, SIZE);
+    assert!(colours.iter().zip(before).all(|(a, b)| a.equals(&b)));
+  }
+
+  #[test]
+  fn bloom_spreads_highlights() {
+    let post = PostProcess {
+      bloom: Some(Bloom {
+        threshold: 1.0,
+        strength: 1.0,
+        radius: 3.0,
+      }),
+      ..PostProcess::default()
+    };
+    let mut colours = grey();
+    let middle = SIZE * SIZE / 2;
+    colours[middle] = Colour::new(5.0, 5.0, 5.0);
+    post.apply_to(&mut colours, SIZE);
+    // The pixels next to the highlight glow, far away nothing changes
+    assert!(colours[middle + 1].x > 0.6 && colours[middle + SIZE].x > 0.6);
+    assert!((colours[middle + 1].x - colours[middle - 1].x).abs() < 1e-9);
+    assert!(colours[0].equals(&Colour::new(0.5, 0.5, 0.5)));
+  }
+
+  #[test]
+  fn vignette_and_lens() {
+    let post = PostProcess {
+      vignette: 0.5,
+      ..PostProcess::default()
+    };
+    let mut colours = grey();
+    post.apply_to(&mut colours, SIZE);
+    assert!(colours[SIZE * SIZE / 2].equals(&Colour::new(0.5, 0.5, 0.5)));
+    assert!((colours[0].x - 0.25).abs() < 1e-9);
+
+    // Red comes from further out than green, and blue from further in
+    let post = PostProcess {
+      chromatic_aberration: 4.0,
+      ..PostProcess::default()
+    };
+    let mut colours: Vec<Colour> = (0..SIZE * SIZE).map(|i| Colour::new(1.0, 1.0, 1.0).scale((i % SIZE) as f64)).collect();
+    post.apply_to(&mut colours, SIZE);
+    let right = SIZE * SIZE / 2 + 5;
+    assert!(colours[right].x > colours[right].y && colours[right].y > colours[right].z);
+    assert!((colours[right].y - 12.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn colour_grading() {
+    let colour = Colour::new(0.2, 0.7, 0.4);
+    assert!(Lut::identity(5).apply(&colour).equals(&colour));
+
+    // A table that swaps red and blue
+    let mut lut = Lut::identity(2);
+    for c in lut.colours.iter_mut() {
+      *c = Colour::new(c.z, c.y, c.x);
+    }
+    assert!(lut.apply(&colour).equals(&Colour::new(0.4, 0.7, 0.2)));
+    // Colours past 1 are looked up as 1
+    assert!(lut.apply(&Colour::new(2.0, 0.0, 0.0)).equals(&Colour::new(0.0, 0.0, 1.0)));
+
+    let post = PostProcess {
+      grading: Some(Lut {
+        size: 2,
+        colours: vec![],
+      }),
+      ..PostProcess::default()
+    };
+    assert!(post.validate().is_err());
+  }
+}
@@ -29,12 +29,27 @@
 #![allow(clippy::many_single_char_names)]
 
 use std::f64;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
+use crate::animation::Animation;
 use crate::constants::*;
+use crate::denoise::Denoiser;
+use crate::dirty::{changed_tiles, DirtyTiles, Snapshot};
+use crate::error::RayTracerError;
+use crate::gltf;
+use crate::mesh_file;
 use crate::objects::*;
+use crate::physics::{Physics, MAX_STEPS_PER_CALL};
+use crate::post::PostProcess;
+use crate::scene_file;
+use crate::scenes::{by_name, default_scene};
+use crate::stats::{Counter, RayStats, Stats};
+use crate::temporal::{jitter, HdrFrame, HdrPixel, HdrStrip, STRIP_PIXELS};
+use crate::transform::Transform;
 use crate::vector::Vector;
 use crate::vector::Vector as Colour;
+use crate::volume::{henyey_greenstein, Medium, FOG_FAR, VOLUME_STEPS};
 
 struct OriginPixel {
   first_ray: Ray,
@@ -46,161 +61,35 @@ pub struct RayTracer {
   scene: Scene,
   origin_pix: Vec<Vec<OriginPixel>>,
   strip_map: [usize; NUM_STRIPS],
+  stats: Stats,
+  physics: Physics,
+  time: f64,
+  snapshot: Option<Snapshot>, // The scene at the last `begin_frame()`, None after an edit
+  dirty: DirtyTiles,
+  previous: Option<Snapshot>, // The scene at the `begin_frame()` before that
+  frame: u32,                 // Counts `begin_frame()` calls, for the jitter
+  emitters: Vec<(usize, Vec<EmitterSample>)>, // Id and light samples of each glowing object, kept up to date as they move
+  jitter: bool,               // Whether `render_hdr()` jitters, see `set_jitter()`
+  denoiser: Option<Denoiser>,
+  hdr: Option<HdrFrame>, // Kept between `render_frame()` calls once it's needed
 }
 
 #[wasm_bindgen]
 impl RayTracer {
   #[wasm_bindgen(constructor)]
   pub fn new(depth: i32) -> Self {
-    let mut scene;
-
-    /**************************************/
-    /* Create the scene, add our objects. */
-    /**************************************/
-    {
-      // Init the eye and scene
-      scene = Scene {
-        eye: Eye {
-          c: Vector {
-            x: 0.0,
-            y: 2.0,
-            z: -15.0,
-          },
-          w: 0.75,
-          h: 0.75,
-          d: 2.0,
-        },
-        lights: vec![],
-        objs: vec![],
-      };
-      //
-      // Add the disc
-      //
-
-      let disc_norm = Vector {
-        x: 0.0,
-        y: 1.0,
-        z: 0.0,
-      };
-      let disc_centre = Vector {
-        x: 0.0,
-        y: 0.0,
-        z: 0.0,
-      };
-      let mut disc = Object::new_disc(
-        disc_centre,
-        disc_norm,
-        6.0,
-        0.7, // Reflectivity -> 0.0 to 1.0
-        0.0, // Refractivity
-        0.6, // Ambient light
-      );
-      disc.set_diffuse(0.2);
-      disc.can_create_shadow = false;
-      disc.can_receive_shadow = true;
-      scene.add_object(disc);
-
-      //
-      // Add a sphere
-      //
-      let mut obj = Object::new_sphere(
-        Vector::new(0.7, 1.2, 0.4),
-        1.0,     // Radius
-        COL_RED, // Colour of sphere
-        0.9,     // Reflectivity -> 0.0 to 1.0
-        0.0,     // Refractivity
-        0.2,     // Ambient light
-      );
-      obj.set_diffuse(0.2);
-      obj.can_create_shadow = true;
-      obj.can_receive_shadow = false;
-      scene.add_object(obj);
-
-      //
-      // ... and another sphere
-      //
-      let mut obj = Object::new_sphere(
-        Vector::new(-1.5, 1.6, 0.4),
-        0.8,       // Radius
-        COL_WHITE, // Colour of sphere
-        0.6,       // Reflectivity -> 0.0 to 1.0
-        0.0,       // Refractivity
-        0.2,       // Ambient light
-      );
-      obj.set_diffuse(0.7);
-      obj.can_create_shadow = true;
-      obj.can_receive_shadow = false;
-      scene.add_object(obj);
-
-      //
-      // ... and another sphere
-      //
-      let mut obj = Object::new_sphere(
-        Vector::new(1.2, 0.8, -1.8),
-        0.8,       // Radius
-        COL_WHITE, // Colour of sphere
-        0.4,       // Reflectivity -> 0.0 to 1.0
-        1.12,      // Refractivity
-        0.05,      // Ambient light
-      );
-      obj.set_diffuse(0.0);
-      obj.can_create_shadow = true;
-      obj.can_receive_shadow = false;
-      scene.add_object(obj);
-
-      //
-      // Add a light
-      //
-      scene.add_light(Light {
-        c: Vector::new(5.0, 7.5, -2.0),
-        col: COL_WHITE,
-      });
-    }
-    /**************************************/
-    /*     Do some pre-calculations.      */
-    /**************************************/
-
-    let mut origin_pix = vec![];
-    let mut strip_map = [0; NUM_STRIPS];
-
-    // Start in the top left
-    let x_direction_start = -scene.eye.w / 2.0;
-    let y_direction_start = scene.eye.h / 2.0;
-    let origin = scene.eye.c;
-    let dnx = Vector::new(scene.eye.w / (NUM_COLS as f64 - 1.0), 0.0, 0.0);
-    let dny = Vector::new(0.0, scene.eye.h / (NUM_ROWS as f64 - 1.0), 0.0);
-    let mut direction = Vector::new(x_direction_start, y_direction_start, scene.eye.d);
-
-    // Prepare the strips
-    let mut strip_id = 0;
-    for row in 0..NUM_ROWS {
-      if row % SQUARE_SIZE == 0 {
-        strip_map[strip_id] = row;
-        strip_id += 1;
-      }
-
-      origin_pix.push(vec![]);
-      for _ in 0..NUM_COLS {
-        direction.add_in_place(&dnx);
-        let dir_norm = direction.normalise();
-        origin_pix[row].push(OriginPixel {
-          first_ray: Ray {
-            origin,
-            direction: dir_norm,
-            dot_dd: dir_norm.dot(&dir_norm),
-          },
-        });
-      }
-
-      direction.x = x_direction_start;
-      direction.sub_in_place(&dny);
-    }
+    RayTracer::with_scene(depth, default_scene())
+  }
 
-    RayTracer {
-      depth,
-      scene,
-      origin_pix,
-      strip_map,
+  /**
+   * Create a ray tracer for one of the canonical scenes ("default",
+   * "many_spheres", "bouncing_balls", "transformed", "instances", "csg", "sdf",
+   * "fog", "emissive" or "spotlights").
+   */
+  pub fn with_scene_name(depth: i32, name: &str) -> Result<RayTracer, RayTracerError> {
+    match by_name(name) {
+      Some(scene) => Ok(RayTracer::with_scene(depth, scene)),
+      None => Err(RayTracerError::UnknownScene(name.to_string())),
     }
   }
 
@@ -220,122 +109,139 @@ impl RayTracer {
     NUM_STRIPS as u32
   }
 
+  /**
+   * Place every animated object where it is at time `t` (in seconds), and
+   * run the physics up to `t`.  This doesn't depend on any earlier calls, so
+   * a frame can be reproduced exactly.
+   */
+  pub fn set_time(&mut self, t: f64) {
+    self.place_at(t, None);
+  }
+
+  /**
+   * Like `set_time()`, but run at most `physics::MAX_STEPS_PER_CALL` steps of
+   * the physics, so a big jump in time (say the page was hidden) can't
+   * freeze an interactive page.  The following calls run the rest.  Returns
+   * whether the physics got to `t`.
+   */
+  pub fn catch_up_to(&mut self, t: f64) -> bool {
+    self.place_at(t, Some(MAX_STEPS_PER_CALL))
+  }
+
+  // Move time on by `dt` seconds, see `set_time()`.
+  pub fn step(&mut self, dt: f64) {
+    self.set_time(self.time + dt);
+  }
+
+  // Rotate the spheres by `angle` degrees from wherever they are now.
   pub fn increment(&mut self, angle: f64) {
     for obj in &mut self.scene.objs {
       if obj.typ == ObjectType::Sphere {
-        crate::physics::Physics::apply_forces(obj, angle);
+        Physics::apply_forces(obj, angle);
       }
     }
+    self.update_emitters();
   }
 
   /**
-   * Render the scene.  self will update the data object that was provided.
+   * The ray and intersection counts since the last call, then reset them.
+   * Only counted when built with the `stats` feature.
    */
-  pub fn render(&mut self, strip_id: u32, strip_data: &mut [u8]) {
-    // The "main loop"
-
-    let mut col = 0;
-    let row = self.strip_map[strip_id as usize];
-
-    let mut static_colour = COL_BACKGROUND;
-
-    // Bounds of the square in pixels
-    let row_top = row;
-    let row_bot = row + SQUARE_SIZE - 1;
-    let mut col_lhs = 0;
-    let mut col_rhs = SQUARE_SIZE - 1;
-
-    let mut pxl_tl = COL_BACKGROUND;
-    let mut pxl_tr = COL_BACKGROUND;
-    let mut pxl_bl = COL_BACKGROUND;
-    let mut pxl_br = COL_BACKGROUND;
-
-    // For Each Square
-    while col_lhs < NUM_COLS {
-      // Top Left
-      self.raytrace(
-        &mut pxl_tl,
-        self.depth,
-        &self.origin_pix[row_top][col_lhs].first_ray.clone(),
-        OBJECT_ID_NONE,
-        1.0,
-      );
-
-      // Top Right
-      self.raytrace(
-        &mut pxl_tr,
-        self.depth,
-        &self.origin_pix[row_top][col_rhs].first_ray.clone(),
-        OBJECT_ID_NONE,
-        1.0,
-      );
-
-      // Bottom Left
-      self.raytrace(
-        &mut pxl_bl,
-        self.depth,
-        &self.origin_pix[row_bot][col_lhs].first_ray.clone(),
-        OBJECT_ID_NONE,
-        1.0,
-      );
-
-      // Bottom Right
-      self.raytrace(
-        &mut pxl_br,
-        self.depth,
-        &self.origin_pix[row_bot][col_rhs].first_ray.clone(),
-        OBJECT_ID_NONE,
-        1.0,
-      );
-
-      // Check to see if we can fill the square with black
-      let all_elements_are_zero =
-        pxl_tl.add(&pxl_tr).add(&pxl_bl).add(&pxl_br).sum_elements() <= 0.0001;
-
-      // Fill the square with colour (or black)
-      for r in 0..SQUARE_SIZE {
-        for c in 0..SQUARE_SIZE {
-          if all_elements_are_zero {
-            static_colour = COL_BACKGROUND;
-          } else {
-            // Don't need to calculate those that have already be calculated
-            if r == 0 && c == 0 {
-              // Top Left
-              static_colour = pxl_tl;
-            } else if r == 0 && c == SQUARE_SIZE - 1 {
-              // Top Right
-              static_colour = pxl_tr;
-            } else if r == SQUARE_SIZE - 1 && c == 0 {
-              // Bottom Left
-              static_colour = pxl_bl;
-            } else if r == SQUARE_SIZE - 1 && c == SQUARE_SIZE - 1 {
-              // Bottom Right
-              static_colour = pxl_br;
-            } else {
-              self.raytrace(
-                &mut static_colour,
-                self.depth,
-                &self.origin_pix[row + r][col + c].first_ray.clone(),
-                OBJECT_ID_NONE,
-                1.0,
-              );
-            }
-            static_colour.scale_in_place(255.0);
-            static_colour.max_val_in_place(255.0);
-          }
+  pub fn take_stats(&self) -> RayStats {
+    self.stats.take()
+  }
 
-          let pnt = (r * NUM_COLS + c + col) * 4;
-          strip_data[pnt] = static_colour.x as u8;
-          strip_data[pnt + 1] = static_colour.y as u8;
-          strip_data[pnt + 2] = static_colour.z as u8;
-          strip_data[pnt + 3] = 255u8;
-        }
+  /**
+   * Start a frame, after moving things for it with `set_time()` or
+   * `increment()`.  Works out which squares can look different from the
+   * last frame, for `render_changed()`, and returns how many there are.
+   * After an edit, or the first time, that's all of them.  Also moves
+   * `render_hdr()` on to the next jitter and motion vectors.
+   */
+  pub fn begin_frame(&mut self) -> u32 {
+    let previous = self.snapshot.take();
+    self.dirty = changed_tiles(previous.as_ref(), &self.scene, &self.emitters);
+    self.snapshot = Some(Snapshot::of(&self.scene));
+    self.previous = previous;
+    self.frame = self.frame.wrapping_add(1);
+    self.dirty.count() as u32
+  }
+
+  /**
+   * Render only the squares of a strip that have changed since the last
+   * frame, see `begin_frame()`.  The rest of `strip_data` isn't touched.
+   * Returns the columns of the squares that were rendered, counted in
+   * squares.
+   */
+  pub fn render_changed(&self, strip_id: u32, strip_data: &mut [u8]) -> Result<Vec<u32>, RayTracerError> {
+    let row = self.strip_row(strip_id, strip_data)?;
+    let mut squares = vec![];
+    for square in 0..NUM_COLS / SQUARE_SIZE {
+      if self.dirty.is_dirty(strip_id as usize, square) {
+        let x = square * SQUARE_SIZE;
+        let size = SQUARE_SIZE as u32;
+        let stride = (NUM_COLS * 4) as u32;
+        self.render_region(x as u32, row as u32, size, size, &mut strip_data[x * 4..], stride)?;
+        squares.push(square as u32);
       }
+    }
+    Ok(squares)
+  }
+
+  /**
+   * Render the scene.  self will update the data object that was provided,
+   * which needs room for a strip: width() * square_size() RGBA pixels.
+   * The denoiser and post-processing aren't applied to strips, see
+   * `render_frame()`.
+   */
+  pub fn render(&self, strip_id: u32, strip_data: &mut [u8]) -> Result<(), RayTracerError> {
+    self.render_strip(strip_id, strip_data).map(|_| ())
+  }
 
-      col_lhs += SQUARE_SIZE;
-      col_rhs += SQUARE_SIZE;
-      col += SQUARE_SIZE;
+  /**
+   * Turn the edge-aware denoiser on or off, see `denoise.rs`.  It needs the
+   * whole image, so only `render_frame()` uses it, before any
+   * post-processing.  It starts off.
+   */
+  pub fn set_denoise(&mut self, on: bool) {
+    self.denoiser = if on { Some(Denoiser::default()) } else { None };
+    // Every square looks different
+    self.snapshot = None;
+  }
+
+  // Whether `render_frame()` filters the image, so it can't be rendered in strips.
+  pub fn filters_frames(&self) -> bool {
+    self.denoiser.is_some() || self.scene.post.is_on()
+  }
+
+  /**
+   * Render the whole image into `frame_data`, which needs room for width() *
+   * height() RGBA pixels.  With the denoiser or post-processing on every
+   * pixel is rendered in HDR, with its albedo, normal and depth, and filtered
+   * before it's clamped to 8 bits.  Otherwise it's the same as rendering
+   * each strip.
+   */
+  pub fn render_frame(&mut self, frame_data: &mut [u8]) -> Result<(), RayTracerError> {
+    let needed = NUM_COLS * NUM_ROWS * 4;
+    if frame_data.len() < needed {
+      return Err(RayTracerError::BufferTooSmall {
+        needed,
+        len: frame_data.len(),
+      });
     }
+    if !self.filters_frames() {
+      for (strip_id, strip_data) in frame_data[..needed].chunks_exact_mut(STRIP_PIXELS * 4).enumerate() {
+        self.render_strip(strip_id as u32, strip_data)?;
+      }
+      return Ok(());
+    }
+
+    let mut hdr = self.hdr.take().unwrap_or_default();
+    self.render_hdr_frame(&mut hdr);
+    self.filter_frame(&mut hdr);
+    hdr.to_rgba(frame_data);
+    self.hdr = Some(hdr);
+    Ok(())
   }
 
   /**
@@ -343,158 +249,1169 @@ impl RayTracer {
    * @param {Object} colour    The colour - self value gets changed in place
    * @param {number} depth     How many iterations left
    * @param {Ray} ray          The ray
-   * @param {number} this_obj_id  The ID of the object the ray comes from
    * @param {number} rindex    Refractivity
    */
-  fn raytrace(&self, colour: &mut Colour, depth: i32, ray: &Ray, this_obj_id: usize, rindex: f64) {
+  fn raytrace(&self, colour: &mut Colour, depth: i32, ray: &Ray, rindex: f64) {
     if depth == 0 {
       colour.set(&COL_BACKGROUND);
       return;
     }
 
-    let mut closest_obj_id: usize = OBJECT_ID_NONE;
-    let mut closest_obj_t = f64::MAX;
-    let mut closest_obj_colour = COL_BACKGROUND;
-    let mut closest_obj_pi = ORIGIN;
+    let closest = self.closest_hit(ray);
+    self.shade_hit(colour, depth, ray, closest, rindex);
+  }
+
+  // The first object `ray` hits.
+  fn closest_hit(&self, ray: &Ray) -> Option<Hit> {
+    let mut closest: Option<Hit> = None;
 
     for (obj_id, obj) in self.scene.objs.iter().enumerate() {
-      // Don't intersect object with itself
-      if obj_id != this_obj_id {
-        let intersection = obj.intersect(&ray);
-        if let HasIntersection::Yes { col, t, pi } = intersection {
-          if closest_obj_id == OBJECT_ID_NONE || t < closest_obj_t {
-            closest_obj_t = t;
-            closest_obj_id = obj_id;
-            closest_obj_colour = col;
-            closest_obj_pi = pi
-          }
+      self.stats.add(Counter::IntersectCalls, 1);
+      if let Some(mut hit) = obj.intersect(ray) {
+        if closest.is_none_or(|c| hit.t < c.t) {
+          hit.obj_id = obj_id;
+          closest = Some(hit);
         }
       }
     }
+    closest
+  }
 
-    if closest_obj_id == OBJECT_ID_NONE {
-      colour.set(&COL_BACKGROUND);
-    } else {
-      colour.set(&closest_obj_colour);
-      let closest_obj = &self.scene.objs[closest_obj_id as usize];
-      // If we found an object, get the shade for the object.  Otherwise return the background
-      self.get_shade_at_point(
-        colour,
-        depth,
-        &ray,
-        closest_obj,
-        closest_obj_id,
-        closest_obj_pi,
-        rindex,
-      );
+  // The colour seen along `ray`, which hits `closest` first.
+  fn shade_hit(&self, colour: &mut Colour, depth: i32, ray: &Ray, closest: Option<Hit>, rindex: f64) {
+    match closest {
+      None => colour.set(&COL_BACKGROUND),
+      Some(hit) => {
+        // If we found an object, get the shade for the object.  Otherwise return the background
+        colour.set(&hit.col);
+        self.get_shade_at_point(colour, depth, ray, &hit, rindex);
+      }
+    }
+
+    if self.scene.fog.is_some() || !self.scene.volumes.is_empty() {
+      self.apply_media(colour, ray, closest.map_or(FOG_FAR, |hit| hit.t));
+    }
+  }
+
+  /**
+   * Fade `colour`, seen at `t_max` along the ray, through the media in front
+   * of it and add the light they scatter towards the eye.  The furthest
+   * medium goes first so the nearer ones dim its light.  Overlapping media
+   * are only roughly right.
+   */
+  fn apply_media(&self, colour: &mut Colour, ray: &Ray, t_max: f64) {
+    let mut segments: Vec<(Medium, f64, f64)> = vec![];
+    if let Some(fog) = self.scene.fog {
+      segments.push((fog, 0.0, t_max));
+    }
+    for volume in &self.scene.volumes {
+      if let Some((t0, t1)) = volume.clip(ray, t_max) {
+        segments.push((volume.medium, t0, t1));
+      }
+    }
+    segments.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (medium, t0, t1) in segments {
+      let (transmittance, scattered) = self.march(&medium, ray, t0, t1);
+      colour.product_in_place(&transmittance);
+      colour.add_in_place(&scattered);
     }
   }
 
+  /**
+   * Ray march from `t0` to `t1` through a medium.  Returns the fraction of
+   * light that gets through, and the light scattered towards the ray origin.
+   */
+  fn march(&self, medium: &Medium, ray: &Ray, t0: f64, t1: f64) -> (Colour, Colour) {
+    let len = ray.direction.length();
+    let dt = (t1 - t0) / VOLUME_STEPS as f64;
+    let step_transmittance = medium.transmittance(dt * len);
+    let mut transmittance = COL_WHITE;
+    let mut scattered = COL_BLACK;
+    for i in 0..VOLUME_STEPS {
+      let p = ray.origin.add(&ray.direction.scale(t0 + (i as f64 + 0.5) * dt));
+      for light in &self.scene.lights {
+        let (to_light, strength) = light.towards(&p);
+        if strength > 0.0 && self.light_visible(&p, &to_light, OBJECT_ID_NONE) {
+          let cos_theta = ray.direction.dot(&to_light) / (len * to_light.length());
+          let phase = henyey_greenstein(cos_theta, medium.g);
+          let s = light.col.product(&medium.scattering).scale(strength * phase * dt * len);
+          scattered.add_in_place(&s.product(&transmittance));
+        }
+      }
+      transmittance.product_in_place(&step_transmittance);
+    }
+    (transmittance, scattered)
+  }
+
+  // Whether anything, other than the object `light_id`, is between `p` and
+  // the light at `p + to_light`.
+  fn light_visible(&self, p: &Vector, to_light: &Vector, light_id: usize) -> bool {
+    self.stats.add(Counter::ShadowRays, 1);
+    let r = Ray::new(*p, *to_light);
+    !self.scene.objs.iter().enumerate().any(|(obj_id, obj)| {
+      obj_id != light_id && obj.can_create_shadow && {
+        self.stats.add(Counter::IntersectCalls, 1);
+        obj.intersect(&r).is_some_and(|hit| hit.t < 1.0)
+      }
+    })
+  }
+
   /**
    * Get the shade of the pixel - where the work is done
    * @param colour    The colour - self value gets changed in place
    * @param depth     How many iterations left
    * @param ray       The ray
-   * @param obj_id    The ID of the object the ray just hit
-   * @param pi        The intersection point
+   * @param hit       Where the ray hit the object
    * @param rindex    Refractivity
    */
-  fn get_shade_at_point(
-    &self,
-    colour: &mut Colour,
-    depth: i32,
-    ray: &Ray,
-    obj: &Object,
-    obj_id: usize,
-    pi: Vector,
-    rindex: f64,
-  ) {
+  fn get_shade_at_point(&self, colour: &mut Colour, depth: i32, ray: &Ray, hit: &Hit, rindex: f64) {
+    let obj = &self.scene.objs[hit.obj_id];
+    let pi = hit.pi;
+    let norm = hit.norm;
+    let col = obj.surface_colour(hit);
     colour.scale_in_place(obj.ambient_light);
 
-    let light = &self.scene.lights[0];
-
-    // handle point light source -
-    let mut l = light.c.sub(&pi);
-    let shade = self.get_shading(&l, &pi, obj, obj_id);
+    // glowing objects give off their own light, and light the objects around them
+    colour.add_in_place(&obj.emitted());
+    if obj.diff > 0.0 {
+      self.add_emitted_light(colour, obj, hit);
+    }
 
-    // calculate diffuse shading
-    l.normalise_in_place();
     let v = ray.direction;
-    let norm = obj.get_norm(&pi);
-    let dot_ln = l.dot(&norm);
     let dot_vn = ray.direction.dot(&norm);
-    if obj.diff > 0.0 && dot_ln > 0.0 {
-      let diff = dot_ln * obj.diff * shade;
-      // add diffuse component to ray color
-      colour.add_in_place(&light.col.product(&obj.col).scale(diff));
-    }
-
-    // determine specular component
-    if obj.spec > 0.0001 {
-      // point light source: sample once for specular highlight
-
-      let mut r = l; // NOTE: don't use L after self;
-      r.sub_in_place(&norm.scale(2.0 * dot_ln));
-      let dot_vr = v.dot(&r);
-      if dot_vr > 0.0001 {
-        let spec = dot_vr.powf(20.0) * obj.spec * shade;
-        // add specular component to ray color
-        colour.add_in_place(&light.col.scale(spec));
+    for light in &self.scene.lights {
+      let (mut l, strength) = light.towards(&pi);
+      if strength <= 0.0 {
+        continue;
+      }
+      let shade = self.get_shading(&l, &pi, obj, hit.obj_id).product(&light.col).scale(strength);
+
+      // calculate diffuse shading
+      l.normalise_in_place();
+      let dot_ln = l.dot(&norm);
+      if obj.diff > 0.0 && dot_ln > 0.0 {
+        let diff = dot_ln * obj.diff;
+        // add diffuse component to ray color
+        colour.add_in_place(&shade.product(&col).scale(diff));
+      }
+
+      // determine specular component
+      if obj.spec > 0.0001 {
+        // sample once for specular highlight
+
+        let mut r = l; // NOTE: don't use L after self;
+        r.sub_in_place(&norm.scale(2.0 * dot_ln));
+        let dot_vr = v.dot(&r);
+        if dot_vr > 0.0001 {
+          let spec = dot_vr.powf(20.0) * obj.spec;
+          // add specular component to ray color
+          colour.add_in_place(&shade.scale(spec));
+        }
       }
     }
 
-    // calculate reflection
+    // calculate reflection, starting just off the surface on the side the ray came from
     if obj.rfl > 0.0 && depth > 0 {
       let r = ray.direction.sub(&norm.scale(2.0 * dot_vn));
-      let new_ray = Ray::new(pi.add(&r.scale(EPSILON)), r);
+      let new_ray = Ray::new(pi.add(&hit.geom_norm.scale(EPSILON)), r);
 
       let mut rcol = COL_BACKGROUND;
-      self.raytrace(&mut rcol, depth - 1, &new_ray, obj_id, 1.0);
-      rcol.product_in_place(&obj.col);
+      self.stats.add(Counter::ReflectionRays, 1);
+      self.raytrace(&mut rcol, depth - 1, &new_ray, 1.0);
+      rcol.product_in_place(&col);
       rcol.scale_in_place(obj.rfl);
       colour.add_in_place(&rcol);
     }
 
-    // calculate refraction
+    // calculate refraction, into the object or back out into the air
     if obj.rfr > 0.0 {
-      let n = rindex / obj.rfr;
-      let result = if (rindex - 1.0).abs() < EPSILON {
-        1.0
+      let (next_rindex, n) = if hit.front_face {
+        (obj.rfr, rindex / obj.rfr)
       } else {
-        -1.0
+        (1.0, rindex)
       };
-      let mut r_norm = norm;
-      r_norm.scale_in_place(result); // NOTE: Don't use N after self point
       let cos_i = -dot_vn;
       let cos_t2 = 1.0 - n * n * (1.0 - cos_i * cos_i);
       if cos_t2 > 0.0 {
-        r_norm.scale_in_place(n * cos_i - cos_t2.sqrt());
-        let mut t = ray.direction;
-        t = t.scale(n);
-        t.add_in_place(&r_norm);
-        let refr_ray = Ray::new(pi.add(&t.scale(EPSILON)), t);
+        let t = ray.direction.scale(n).add(&norm.scale(n * cos_i - cos_t2.sqrt()));
+        let refr_ray = Ray::new(pi.sub(&hit.geom_norm.scale(EPSILON)), t);
         let mut rfr_colour = COL_BACKGROUND;
-        self.raytrace(&mut rfr_colour, depth - 1, &refr_ray, obj_id, obj.rfr);
+        self.stats.add(Counter::RefractionRays, 1);
+        self.raytrace(&mut rfr_colour, depth - 1, &refr_ray, next_rindex);
         colour.add_in_place(&rfr_colour);
       }
     }
   }
 
-  fn get_shading(&self, l: &Vector, pi: &Vector, that_obj: &Object, that_obj_id: usize) -> f64 {
+  // Add the diffuse light falling on `hit` from every glowing object.
+  fn add_emitted_light(&self, colour: &mut Colour, obj: &Object, hit: &Hit) {
+    let origin = hit.pi.add(&hit.geom_norm.scale(EPSILON));
+    for &(emitter_id, ref samples) in &self.emitters {
+      if emitter_id == hit.obj_id {
+        continue;
+      }
+      let emitted = self.scene.objs[emitter_id].emitted().product(&obj.surface_colour(hit));
+      for sample in samples {
+        let to_light = sample.pi.sub(&origin);
+        let dist2 = to_light.dot(&to_light);
+        let l = to_light.scale(1.0 / dist2.sqrt());
+        let dot_ln = l.dot(&hit.norm);
+        if dot_ln <= 0.0 {
+          continue;
+        }
+        let cos_e = sample.norm.map_or(1.0, |n| n.dot(&l).abs());
+        if obj.can_receive_shadow && !self.light_visible(&origin, &to_light, emitter_id) {
+          continue;
+        }
+        colour.add_in_place(&emitted.scale(obj.diff * dot_ln * cos_e * sample.area / dist2));
+      }
+    }
+  }
+
+  /**
+   * How much of the light along `l` reaches `pi` on `that_obj`: black when
+   * something opaque is in the way, tinted by the colour of any glass.  The
+   * glass also reflects some of the light away, going in and out, more the
+   * higher its refractive index and the more glancing the ray (Schlick's
+   * approximation of Fresnel).  With caustics on, light passing straight
+   * through the middle of the glass is brightened, as if focused, by more
+   * the more strongly the glass bends light.
+   */
+  fn get_shading(&self, l: &Vector, pi: &Vector, that_obj: &Object, that_obj_id: usize) -> Colour {
     let tdist = l.length();
     let lt = l.scale(1.0 / tdist);
     let r = Ray::new(pi.add(&lt.scale(EPSILON)), lt);
+    let mut transmitted = COL_WHITE;
+    if that_obj.can_receive_shadow {
+      self.stats.add(Counter::ShadowRays, 1);
+    }
     for (this_obj_id, this_obj) in self.scene.objs.iter().enumerate() {
       // Don't intersect with self...
       // ... and check if an object is in the way of the light source
       if that_obj_id != this_obj_id && that_obj.can_receive_shadow && this_obj.can_create_shadow {
-        match this_obj.intersect(&r) {
-          HasIntersection::No => {}
-          _ => return 0.0,
+        self.stats.add(Counter::IntersectCalls, 1);
+        if let Some(hit) = this_obj.intersect(&r).filter(|hit| hit.t < tdist) {
+          if this_obj.rfr <= 0.0 {
+            return COL_BLACK;
+          }
+          let cos_i = lt.dot(&hit.geom_norm).abs();
+          let r0 = ((this_obj.rfr - 1.0) / (this_obj.rfr + 1.0)).powi(2);
+          let reflected = r0 + (1.0 - r0) * (1.0 - cos_i).powi(5);
+          let mut through = this_obj.col.scale((1.0 - this_obj.rfl) * (1.0 - reflected).powi(2));
+          if self.scene.caustics {
+            let bend = (this_obj.rfr - 1.0).abs().min(1.0);
+            through.scale_in_place(1.0 + CAUSTIC_GAIN * bend * cos_i.powi(8));
+          }
+          transmitted.product_in_place(&through);
         }
       }
     }
 
-    1.0
+    transmitted
+  }
+}
+
+// Scene editing, between frames.  Objects and lights are known by their id,
+// which is their index in the scene: removing one moves the ids of those
+// after it down by one.  An edit that would leave something that can't be
+// rendered, like a negative radius, is an error and changes nothing.  The
+// frame after an edit is rendered in full.
+#[wasm_bindgen]
+impl RayTracer {
+  // Add a sphere, returns its id.
+  #[allow(clippy::too_many_arguments)]
+  pub fn add_sphere(
+    &mut self,
+    x: f64,
+    y: f64,
+    z: f64,
+    radius: f64,
+    r: f64,
+    g: f64,
+    b: f64,
+    rfl: f64,
+    rfr: f64,
+    ambient_light: f64,
+  ) -> Result<u32, RayTracerError> {
+    let obj = Object::new_sphere(Vector::new(x, y, z), radius, Colour::new(r, g, b), rfl, rfr, ambient_light);
+    self.add_object(obj)
+  }
+
+  // Add a checkered disc facing along [nx, ny, nz], returns its id.
+  #[allow(clippy::too_many_arguments)]
+  pub fn add_disc(
+    &mut self,
+    x: f64,
+    y: f64,
+    z: f64,
+    nx: f64,
+    ny: f64,
+    nz: f64,
+    radius: f64,
+    rfl: f64,
+    rfr: f64,
+    ambient_light: f64,
+  ) -> Result<u32, RayTracerError> {
+    let n = Vector::new(nx, ny, nz).normalise();
+    let obj = Object::new_disc(Vector::new(x, y, z), n, radius, rfl, rfr, ambient_light);
+    self.add_object(obj)
+  }
+
+  /**
+   * Add a mesh from a PLY or STL file, scaled by `scale` and with its origin
+   * moved to [x, y, z].  A PLY's vertex colours are kept.  Returns its id.
+   */
+  #[allow(clippy::too_many_arguments)]
+  pub fn add_mesh_file(
+    &mut self,
+    data: &[u8],
+    x: f64,
+    y: f64,
+    z: f64,
+    scale: f64,
+    rfl: f64,
+    rfr: f64,
+    ambient_light: f64,
+  ) -> Result<u32, RayTracerError> {
+    if !(scale > 0.0 && scale.is_finite()) {
+      return Err(RayTracerError::InvalidScene(format!("the scale must be positive, not {}", scale)));
+    }
+    let mesh = mesh_file::load(data)?;
+    let mut obj = Object::new_mesh(Arc::new(mesh), ORIGIN, COL_WHITE, rfl, rfr, ambient_light);
+    let placed = Transform::scale(&Vector::new(scale, scale, scale)).then(&Transform::translate(&Vector::new(x, y, z)));
+    obj.set_transform(placed);
+    self.add_object(obj)
+  }
+
+  // Add a point light, returns its id.
+  pub fn add_point_light(&mut self, x: f64, y: f64, z: f64, r: f64, g: f64, b: f64) -> Result<u32, RayTracerError> {
+    self.add_light(Light::point(Vector::new(x, y, z), Colour::new(r, g, b)))
+  }
+
+  // Add a light shining along [dx, dy, dz] from far away, returns its id.
+  pub fn add_directional_light(
+    &mut self,
+    dx: f64,
+    dy: f64,
+    dz: f64,
+    r: f64,
+    g: f64,
+    b: f64,
+  ) -> Result<u32, RayTracerError> {
+    self.add_light(Light::directional(Vector::new(dx, dy, dz), Colour::new(r, g, b)))
+  }
+
+  // Add a spotlight, the cone angles are in degrees.  Returns its id.
+  #[allow(clippy::too_many_arguments)]
+  pub fn add_spot_light(
+    &mut self,
+    x: f64,
+    y: f64,
+    z: f64,
+    dx: f64,
+    dy: f64,
+    dz: f64,
+    inner: f64,
+    outer: f64,
+    r: f64,
+    g: f64,
+    b: f64,
+  ) -> Result<u32, RayTracerError> {
+    let light = Light::spot(
+      Vector::new(x, y, z),
+      Vector::new(dx, dy, dz),
+      inner,
+      outer,
+      Colour::new(r, g, b),
+    );
+    self.add_light(light)
+  }
+
+  /**
+   * Move an object.  An animated object stops moving, a dynamic one comes
+   * to rest there.
+   */
+  pub fn set_object_position(&mut self, id: u32, x: f64, y: f64, z: f64) -> Result<(), RayTracerError> {
+    let c = Vector::new(x, y, z);
+    self.edit_object(id, |obj| {
+      obj.set_position(c);
+      obj.animation = Animation::Static;
+      obj.velocity = Vector::new(0.0, 0.0, 0.0);
+    })?;
+    self.physics.moved(id as usize, c);
+    Ok(())
+  }
+
+  pub fn set_object_colour(&mut self, id: u32, r: f64, g: f64, b: f64) -> Result<(), RayTracerError> {
+    self.edit_object(id, |obj| obj.col = Colour::new(r, g, b))
+  }
+
+  /**
+   * Change the material of an object: reflectivity, refractive index (0.0
+   * for opaque), diffuse share (the rest is specular) and ambient light.
+   */
+  pub fn set_object_material(
+    &mut self,
+    id: u32,
+    rfl: f64,
+    rfr: f64,
+    diff: f64,
+    ambient_light: f64,
+  ) -> Result<(), RayTracerError> {
+    if !(0.0..=1.0).contains(&diff) {
+      return Err(RayTracerError::InvalidScene(format!("the diffuse share must be 0 to 1, not {}", diff)));
+    }
+    self.edit_object(id, |obj| {
+      obj.rfl = rfl;
+      obj.rfr = rfr;
+      obj.set_diffuse(diff);
+      obj.ambient_light = ambient_light;
+    })
+  }
+
+  pub fn remove_object(&mut self, id: u32) -> Result<(), RayTracerError> {
+    if id as usize >= self.scene.objs.len() {
+      return Err(RayTracerError::NoSuchObject(id));
+    }
+    self.scene.objs.remove(id as usize);
+    self.physics.remove(id as usize);
+    self.snapshot = None;
+    self.update_emitters();
+    Ok(())
+  }
+
+  pub fn remove_light(&mut self, id: u32) -> Result<(), RayTracerError> {
+    if id as usize >= self.scene.lights.len() {
+      return Err(RayTracerError::NoSuchLight(id));
+    }
+    self.scene.lights.remove(id as usize);
+    self.snapshot = None;
+    Ok(())
+  }
+
+  // The objects and lights in the scene, as JSON.
+  pub fn list_scene(&self) -> String {
+    self.scene.to_json()
+  }
+
+  /**
+   * The scene as it is now, in the scene file format.  Objects moved by the
+   * physics are saved where they are, with their velocity.
+   */
+  pub fn save_scene(&self) -> String {
+    scene_file::save(&self.scene)
+  }
+
+  /**
+   * Set the post-processing for `render_frame()`, in the scene file format,
+   * e.g. "bloom 1 0.5 8\nvignette 0.3".  Anything not given is turned off.
+   */
+  pub fn set_post_process(&mut self, text: &str) -> Result<(), RayTracerError> {
+    let post = scene_file::load_post_process(text)?;
+    post.validate().map_err(RayTracerError::InvalidScene)?;
+    self.scene.post = post;
+    // Every square looks different
+    self.snapshot = None;
+    Ok(())
+  }
+
+  // Create a ray tracer for a scene in the scene file format.
+  pub fn from_scene_text(depth: i32, text: &str) -> Result<RayTracer, RayTracerError> {
+    RayTracer::with_valid_scene(depth, scene_file::load(text)?)
+  }
+
+  // Create a ray tracer for a `.glb`, or a `.gltf` with its buffers in data uris.
+  pub fn from_gltf(depth: i32, data: &[u8]) -> Result<RayTracer, RayTracerError> {
+    RayTracer::with_valid_scene(depth, gltf::load(data, |_| None)?)
+  }
+}
+
+impl RayTracer {
+  // Like `with_scene()`, for a scene that might not be renderable.
+  pub fn with_valid_scene(depth: i32, scene: Scene) -> Result<RayTracer, RayTracerError> {
+    scene.validate().map_err(RayTracerError::InvalidScene)?;
+    Ok(RayTracer::with_scene(depth, scene))
+  }
+
+  // See `set_time()`, with at most `max_steps` steps of the physics if there's a limit.
+  fn place_at(&mut self, t: f64, max_steps: Option<u64>) -> bool {
+    for obj in &mut self.scene.objs {
+      if let Some(c) = obj.animation.position_at(t) {
+        obj.set_position(c);
+      }
+    }
+    let done = match max_steps {
+      Some(max_steps) => self.physics.advance_towards(&mut self.scene.objs, t, max_steps),
+      None => {
+        self.physics.advance_to(&mut self.scene.objs, t);
+        true
+      }
+    };
+    self.time = t;
+    self.update_emitters();
+    done
+  }
+
+  fn add_object(&mut self, obj: Object) -> Result<u32, RayTracerError> {
+    obj.validate().map_err(RayTracerError::InvalidScene)?;
+    self.snapshot = None;
+    self.scene.add_object(obj);
+    self.update_emitters();
+    Ok((self.scene.objs.len() - 1) as u32)
+  }
+
+  fn add_light(&mut self, light: Light) -> Result<u32, RayTracerError> {
+    light.validate().map_err(RayTracerError::InvalidScene)?;
+    self.snapshot = None;
+    self.scene.add_light(light);
+    Ok((self.scene.lights.len() - 1) as u32)
+  }
+
+  // Make `edit` to a copy of an object, and keep it if it's still valid.
+  fn edit_object(&mut self, id: u32, edit: impl FnOnce(&mut Object)) -> Result<(), RayTracerError> {
+    let obj = match self.scene.objs.get_mut(id as usize) {
+      Some(obj) => obj,
+      None => return Err(RayTracerError::NoSuchObject(id)),
+    };
+    let mut edited = obj.clone();
+    edit(&mut edited);
+    edited.validate().map_err(RayTracerError::InvalidScene)?;
+    *obj = edited;
+    self.snapshot = None;
+    self.update_emitters();
+    Ok(())
+  }
+
+  // Sample the glowing objects where they are now, once rather than at every hit.
+  fn update_emitters(&mut self) {
+    self.emitters = self
+      .scene
+      .objs
+      .iter()
+      .enumerate()
+      .filter(|(_, obj)| obj.is_emissive())
+      .map(|(id, obj)| (id, obj.emitter_samples()))
+      .collect();
+  }
+}
+
+impl RayTracer {
+  /**
+   * Create a ray tracer for the given scene.
+   */
+  pub fn with_scene(depth: i32, scene: Scene) -> Self {
+    /**************************************/
+    /*     Do some pre-calculations.      */
+    /**************************************/
+
+    let mut origin_pix = vec![];
+    let mut strip_map = [0; NUM_STRIPS];
+
+    // Start in the top left
+    let x_direction_start = -scene.eye.w / 2.0;
+    let y_direction_start = scene.eye.h / 2.0;
+    let origin = scene.eye.c;
+    let dnx = Vector::new(scene.eye.w / (NUM_COLS as f64 - 1.0), 0.0, 0.0);
+    let dny = Vector::new(0.0, scene.eye.h / (NUM_ROWS as f64 - 1.0), 0.0);
+    let mut direction = Vector::new(x_direction_start, y_direction_start, scene.eye.d);
+
+    // Prepare the strips
+    let mut strip_id = 0;
+    for row in 0..NUM_ROWS {
+      if row % SQUARE_SIZE == 0 {
+        strip_map[strip_id] = row;
+        strip_id += 1;
+      }
+
+      origin_pix.push(vec![]);
+      for _ in 0..NUM_COLS {
+        direction.add_in_place(&dnx);
+        let dir_norm = direction.normalise();
+        origin_pix[row].push(OriginPixel {
+          first_ray: Ray {
+            origin,
+            direction: dir_norm,
+            dot_dd: dir_norm.dot(&dir_norm),
+          },
+        });
+      }
+
+      direction.x = x_direction_start;
+      direction.sub_in_place(&dny);
+    }
+
+    let mut rt = RayTracer {
+      depth,
+      physics: Physics::new(&scene.objs),
+      time: 0.0,
+      scene,
+      origin_pix,
+      strip_map,
+      stats: Stats::default(),
+      snapshot: None,
+      dirty: DirtyTiles::all(),
+      previous: None,
+      frame: 0,
+      emitters: vec![],
+      jitter: false,
+      denoiser: None,
+      hdr: None,
+    };
+    rt.update_emitters();
+    rt
+  }
+
+  /**
+   * Render a strip of the scene into `strip_data`, returns the number of
+   * primary rays that were traced.
+   */
+  pub fn render_strip(&self, strip_id: u32, strip_data: &mut [u8]) -> Result<usize, RayTracerError> {
+    let row = self.strip_row(strip_id, strip_data)?;
+    let stride = (NUM_COLS * 4) as u32;
+    self.render_region(0, row as u32, NUM_COLS as u32, SQUARE_SIZE as u32, strip_data, stride)
+  }
+
+  // The first row of a strip, checking `strip_data` has room for it.
+  fn strip_row(&self, strip_id: u32, strip_data: &[u8]) -> Result<usize, RayTracerError> {
+    let row = match self.strip_map.get(strip_id as usize) {
+      Some(row) => *row,
+      None => {
+        return Err(RayTracerError::NoSuchStrip {
+          strip_id,
+          num_strips: NUM_STRIPS,
+        })
+      }
+    };
+    let needed = NUM_COLS * SQUARE_SIZE * 4;
+    if strip_data.len() < needed {
+      return Err(RayTracerError::BufferTooSmall {
+        needed,
+        len: strip_data.len(),
+      });
+    }
+    Ok(row)
+  }
+
+  /**
+   * Jitter the point `render_hdr()` traces through each pixel from frame to
+   * frame, for `Temporal::resolve()` to accumulate.  Without that the edges
+   * would only flicker, so it starts off.
+   */
+  pub fn set_jitter(&mut self, on: bool) {
+    self.jitter = on;
+  }
+
+  // Run the denoiser and then the post-processing over `frame`, if they're on.
+  pub fn filter_frame(&self, frame: &mut HdrFrame) {
+    if let Some(denoiser) = &self.denoiser {
+      denoiser.apply(frame);
+    }
+    if self.scene.post.is_on() {
+      self.scene.post.apply(frame);
+    }
+  }
+
+  // The post-processing `set_post_process()` or the scene file asked for.
+  pub fn post_process(&self) -> &PostProcess {
+    &self.scene.post
+  }
+
+  // Trace the primary ray through pixel [row, col].
+  fn trace_pixel(&self, row: usize, col: usize) -> Colour {
+    let mut colour = COL_BACKGROUND;
+    self.raytrace(&mut colour, self.depth, &self.origin_pix[row][col].first_ray, 1.0);
+    colour
+  }
+
+  /**
+   * Render a strip in HDR for `Temporal::resolve()` and `Denoiser::apply()`:
+   * each pixel traced once, jittered within the pixel by the frame number if
+   * `set_jitter()` is on, with what it hit, the surface's albedo, normal and
+   * depth, and its motion since the frame before.  Call `begin_frame()` first.  Returns the
+   * number of primary rays traced.
+   */
+  pub fn render_hdr(&self, strip_id: u32, strip: &mut HdrStrip) -> Result<usize, RayTracerError> {
+    let row = match self.strip_map.get(strip_id as usize) {
+      Some(row) => *row,
+      None => {
+        return Err(RayTracerError::NoSuchStrip {
+          strip_id,
+          num_strips: NUM_STRIPS,
+        })
+      }
+    };
+    let jitter = if self.jitter { jitter(self.frame) } else { (0.0, 0.0) };
+    for (i, pxl) in strip.pixels.iter_mut().enumerate() {
+      *pxl = self.trace_hdr(row + i / NUM_COLS, i % NUM_COLS, jitter);
+    }
+    self.stats.add(Counter::PrimaryRays, STRIP_PIXELS as u64);
+    Ok(STRIP_PIXELS)
+  }
+
+  // Render every strip into `frame`, see `render_hdr()`.
+  pub fn render_hdr_frame(&self, frame: &mut HdrFrame) {
+    let mut strip = HdrStrip::new();
+    for strip_id in 0..NUM_STRIPS {
+      // Every strip is in range, so this can't fail
+      self.render_hdr(strip_id as u32, &mut strip).unwrap();
+      frame.set_strip(strip_id, &strip);
+    }
+  }
+
+  fn trace_hdr(&self, row: usize, col: usize, (jx, jy): (f64, f64)) -> HdrPixel {
+    let eye = &self.scene.eye;
+    let dx = eye.w / (NUM_COLS as f64 - 1.0);
+    let dy = eye.h / (NUM_ROWS as f64 - 1.0);
+    let direction = Vector::new(
+      -eye.w / 2.0 + (col as f64 + 1.0 + jx) * dx,
+      eye.h / 2.0 - (row as f64 + jy) * dy,
+      eye.d,
+    );
+    let ray = Ray::new(eye.c, direction.normalise());
+    if self.depth == 0 {
+      return HdrPixel::default();
+    }
+
+    let mut colour = COL_BACKGROUND;
+    let closest = self.closest_hit(&ray);
+    self.shade_hit(&mut colour, self.depth, &ray, closest, 1.0);
+
+    // Follow the surface back to the last frame.  The background is always
+    // in the same place.
+    let (col, row) = (col as f64, row as f64);
+    let motion = match (&self.previous, closest) {
+      (Some(previous), Some(hit)) => previous
+        .pixel_then(&self.scene, hit.obj_id, &hit.pi)
+        .zip(eye.pixel_at(&hit.pi))
+        .map(|((then_col, then_row), (now_col, now_row))| (col + then_col - now_col, row + then_row - now_row)),
+      (Some(_), None) => Some((col, row)),
+      (None, _) => None,
+    };
+    match closest {
+      Some(hit) => HdrPixel {
+        colour,
+        obj_id: hit.obj_id,
+        motion,
+        albedo: hit.col,
+        normal: hit.norm,
+        depth: hit.t,
+      },
+      None => HdrPixel {
+        colour,
+        motion,
+        ..HdrPixel::default()
+      },
+    }
+  }
+}
+
+#[wasm_bindgen]
+impl RayTracer {
+  /**
+   * Render the `w` by `h` pixels with their top left at [x, y] into `buf`,
+   * as RGBA with `stride` bytes from the start of one row to the next.
+   * Returns the number of primary rays traced.
+   *
+   * The image is traced in SQUARE_SIZE squares: the corners of a square are
+   * traced first and if they're all black so is the square.  A region that
+   * cuts through a square still traces its corners, so the pixels come out
+   * the same however the image is split up.
+   */
+  pub fn render_region(
+    &self,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    buf: &mut [u8],
+    stride: u32,
+  ) -> Result<usize, RayTracerError> {
+    let (x, y, w, h, stride) = (x as usize, y as usize, w as usize, h as usize, stride as usize);
+    // usize is only 32 bits on wasm, so these sums can wrap
+    let fits = |start: usize, len: usize, max: usize| start.checked_add(len).is_some_and(|end| end <= max);
+    if !fits(x, w, NUM_COLS) || !fits(y, h, NUM_ROWS) {
+      return Err(RayTracerError::BadRegion(format!(
+        "{}x{} at [{}, {}] doesn't fit in the {}x{} image",
+        w, h, x, y, NUM_COLS, NUM_ROWS
+      )));
+    }
+    if w == 0 || h == 0 {
+      return Ok(0);
+    }
+    if stride < w * 4 {
+      return Err(RayTracerError::BadRegion(format!(
+        "a stride of {} bytes is too short for {} pixels",
+        stride, w
+      )));
+    }
+    let needed = match (h - 1).checked_mul(stride).and_then(|n| n.checked_add(w * 4)) {
+      Some(needed) => needed,
+      None => {
+        return Err(RayTracerError::BadRegion(format!(
+          "{} rows with a stride of {} bytes is too big",
+          h, stride
+        )))
+      }
+    };
+    if buf.len() < needed {
+      return Err(RayTracerError::BufferTooSmall { needed, len: buf.len() });
+    }
+
+    let mut primary_rays = 0;
+    let last = SQUARE_SIZE - 1;
+
+    // For each square the region touches
+    for row in (y - y % SQUARE_SIZE..y + h).step_by(SQUARE_SIZE) {
+      for col in (x - x % SQUARE_SIZE..x + w).step_by(SQUARE_SIZE) {
+        let pxl_tl = self.trace_pixel(row, col);
+        let pxl_tr = self.trace_pixel(row, col + last);
+        let pxl_bl = self.trace_pixel(row + last, col);
+        let pxl_br = self.trace_pixel(row + last, col + last);
+        primary_rays += 4;
+
+        // Check to see if we can fill the square with black
+        let all_elements_are_zero = pxl_tl.add(&pxl_tr).add(&pxl_bl).add(&pxl_br).sum_elements() <= 0.0001;
+
+        // Fill the part of the square in the region with colour (or black)
+        for r in row.max(y)..(row + SQUARE_SIZE).min(y + h) {
+          for c in col.max(x)..(col + SQUARE_SIZE).min(x + w) {
+            let colour = if all_elements_are_zero {
+              COL_BACKGROUND
+            } else {
+              // Don't need to calculate those that have already be calculated
+              let mut colour = match (r - row, c - col) {
+                (0, 0) => pxl_tl,
+                (0, cc) if cc == last => pxl_tr,
+                (rr, 0) if rr == last => pxl_bl,
+                (rr, cc) if rr == last && cc == last => pxl_br,
+                _ => {
+                  primary_rays += 1;
+                  self.trace_pixel(r, c)
+                }
+              };
+              colour.scale_in_place(255.0);
+              colour.max_val_in_place(255.0);
+              colour
+            };
+
+            let pnt = (r - y) * stride + (c - x) * 4;
+            buf[pnt] = colour.x as u8;
+            buf[pnt + 1] = colour.y as u8;
+            buf[pnt + 2] = colour.z as u8;
+            buf[pnt + 3] = 255u8;
+          }
+        }
+      }
+    }
+
+    self.stats.add(Counter::PrimaryRays, primary_rays as u64);
+    Ok(primary_rays)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::{NUM_COLS, NUM_ROWS, NUM_STRIPS, SQUARE_SIZE};
+  use crate::error::RayTracerError;
+  use crate::objects::{ObjectType, Ray};
+  use crate::raytracer::RayTracer;
+  use crate::scenes::{bouncing_balls, default_scene};
+  use crate::temporal::{HdrStrip, STRIP_PIXELS};
+  use crate::vector::Vector;
+
+  #[test]
+  fn edit_scene() {
+    let mut rt = RayTracer::with_scene(5, default_scene());
+    let n = rt.scene.objs.len() as u32;
+    assert_eq!(rt.add_sphere(0.0, 1.0, 0.0, 0.5, 1.0, 0.0, 0.0, 0.0, 0.0, 0.2), Ok(n));
+    assert!(rt.list_scene().contains(&format!("\"id\": {}, \"type\": \"sphere\"", n)));
+    assert!(rt.set_object_colour(n, 0.0, 1.0, 0.0).is_ok());
+    assert!(rt.scene.objs[n as usize].col.equals(&Vector::new(0.0, 1.0, 0.0)));
+    assert!(rt.set_object_material(n, 0.5, 1.2, 0.3, 0.1).is_ok());
+    assert!((rt.scene.objs[n as usize].spec - 0.7).abs() < 1e-9);
+
+    assert!(rt.remove_object(0).is_ok());
+    assert_eq!(rt.scene.objs.len() as u32, n);
+    assert_eq!(rt.remove_object(n), Err(RayTracerError::NoSuchObject(n)));
+    assert_eq!(rt.set_object_colour(n, 0.0, 0.0, 0.0), Err(RayTracerError::NoSuchObject(n)));
+
+    assert_eq!(rt.add_directional_light(0.0, -1.0, 0.0, 1.0, 1.0, 1.0), Ok(1));
+    assert!(rt.list_scene().contains("\"type\": \"directional\""));
+    assert!(rt.remove_light(0).is_ok());
+    assert_eq!(rt.remove_light(1), Err(RayTracerError::NoSuchLight(1)));
+  }
+
+  #[test]
+  fn bad_input_is_an_error() {
+    let mut rt = RayTracer::with_scene(5, default_scene());
+    let n = rt.scene.objs.len();
+    let invalid = |message: &str| RayTracerError::InvalidScene(message.to_string());
+    assert_eq!(
+      rt.add_sphere(0.0, 1.0, 0.0, -0.5, 1.0, 0.0, 0.0, 0.0, 0.0, 0.2),
+      Err(invalid("the radius must be positive, not -0.5"))
+    );
+    assert_eq!(
+      rt.add_disc(0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.2),
+      Err(invalid("the normal can't be zero length"))
+    );
+    assert_eq!(
+      rt.add_spot_light(0.0, 5.0, 0.0, 0.0, -1.0, 0.0, 30.0, 20.0, 1.0, 1.0, 1.0),
+      Err(invalid("the cone angles must be 0 <= inner <= outer <= 180, not 30 and 20"))
+    );
+    assert_eq!(rt.set_object_colour(0, -1.0, 0.0, 0.0), Err(invalid("colours can't be negative")));
+    assert_eq!(rt.set_object_position(0, f64::NAN, 0.0, 0.0), Err(invalid("the position isn't finite")));
+    // Nothing was changed
+    assert_eq!(rt.scene.objs.len(), n);
+    assert!(rt.scene.objs[0].col.sum_elements() >= 0.0);
+    assert!(rt.scene.objs[0].c.x.is_finite());
+
+    let mut strip = vec![0; NUM_COLS * SQUARE_SIZE * 4];
+    assert_eq!(
+      rt.render(NUM_STRIPS as u32, &mut strip),
+      Err(RayTracerError::NoSuchStrip {
+        strip_id: NUM_STRIPS as u32,
+        num_strips: NUM_STRIPS
+      })
+    );
+    assert_eq!(
+      rt.render(0, &mut strip[..16]),
+      Err(RayTracerError::BufferTooSmall {
+        needed: strip.len(),
+        len: 16
+      })
+    );
+
+    let error = |r: Result<RayTracer, RayTracerError>| r.err().unwrap().to_string();
+    assert_eq!(error(RayTracer::with_scene_name(5, "nope")), "there's no scene called \"nope\"");
+    assert_eq!(error(RayTracer::from_scene_text(5, "eye 1 2")), "scene file line 1: eye needs 6 numbers");
+    let mut text = rt.save_scene();
+    text = text.replacen("radius 1", "radius -1", 1);
+    assert!(error(RayTracer::from_scene_text(5, &text)).ends_with("the radius must be positive, not -1"));
+  }
+
+  #[test]
+  fn saved_scene_renders_the_same() {
+    let mut rt = RayTracer::with_scene(5, default_scene());
+    rt.set_time(2.0);
+    rt.add_sphere(0.0, 3.0, 0.0, 0.5, 0.2, 0.4, 1.0, 0.5, 0.0, 0.2).unwrap();
+    rt.set_object_material(1, 0.3, 1.3, 0.5, 0.1).unwrap();
+    let text = rt.save_scene();
+    let loaded = RayTracer::from_scene_text(5, &text).unwrap();
+    assert_eq!(loaded.save_scene(), text);
+
+    let mut strip = vec![0; NUM_COLS * SQUARE_SIZE * 4];
+    let mut loaded_strip = strip.clone();
+    for strip_id in &[20, 45, 60] {
+      rt.render(*strip_id, &mut strip).unwrap();
+      loaded.render(*strip_id, &mut loaded_strip).unwrap();
+      assert!(strip == loaded_strip);
+    }
+  }
+
+  #[test]
+  fn regions_match_strips() {
+    let rt = RayTracer::with_scene(5, default_scene());
+    let strip_len = NUM_COLS * SQUARE_SIZE * 4;
+    let mut strips = vec![0; 3 * strip_len];
+    for (i, chunk) in strips.chunks_exact_mut(strip_len).enumerate() {
+      rt.render(40 + i as u32, chunk).unwrap();
+    }
+    rt.take_stats();
+
+    // Odd tiles, cutting through squares, into a wider buffer
+    let stride = (NUM_COLS + 5) * 4;
+    let mut buf = vec![0; 3 * SQUARE_SIZE * stride];
+    let top = 40 * SQUARE_SIZE;
+    for &(x, y, w, h) in &[(0, 0, 101, 7), (101, 0, 300, 7), (401, 0, 295, 7), (0, 7, 696, 17)] {
+      let start = y * stride + x * 4;
+      rt.render_region(x as u32, (top + y) as u32, w as u32, h as u32, &mut buf[start..], stride as u32)
+        .unwrap();
+    }
+    for row in 0..3 * SQUARE_SIZE {
+      let line = &buf[row * stride..row * stride + NUM_COLS * 4];
+      assert!(line == &strips[row * NUM_COLS * 4..(row + 1) * NUM_COLS * 4]);
+    }
+
+    let error = |x, y, w, h, len, stride| rt.render_region(x, y, w, h, &mut vec![0; len], stride).err();
+    assert_eq!(
+      error(690, 0, 10, 1, 40, 40).unwrap().to_string(),
+      "bad region: 10x1 at [690, 0] doesn't fit in the 696x696 image"
+    );
+    assert!(error(0, 0, 10, 1, 40, 36).is_some());
+    assert_eq!(error(0, 0, 10, 2, 70, 40), Some(RayTracerError::BufferTooSmall { needed: 80, len: 70 }));
+    assert_eq!(error(0, 0, 0, 0, 0, 0), None);
+    // Sums that would wrap a 32 bit usize
+    assert!(error(u32::MAX, 0, 2, 1, 8, 8).is_some());
+    assert!(error(0, u32::MAX, 2, 1, 8, 8).is_some());
+    assert!(error(0, 0, 2, 696, 8, u32::MAX).is_some());
+  }
+
+  #[test]
+  fn edit_add_mesh_file() {
+    let mut rt = RayTracer::new(5);
+    let stl = b"solid t\nfacet normal 0 0 -1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid t\n";
+    let id = rt.add_mesh_file(stl, 1.0, 2.0, 3.0, 2.0, 0.0, 0.0, 0.1).unwrap() as usize;
+    let ray = Ray::new(Vector::new(1.5, 2.5, 0.0), Vector::new(0.0, 0.0, 1.0));
+    assert!((rt.scene.objs[id].intersect(&ray).unwrap().t - 3.0).abs() < 1e-9);
+    assert!(rt.add_mesh_file(b"nothing", 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.1).is_err());
+  }
+
+  #[test]
+  fn edit_dynamic_object() {
+    let mut rt = RayTracer::with_scene(5, bouncing_balls());
+    let id = rt.scene.objs.iter().position(|obj| obj.dynamic).unwrap();
+    rt.set_time(1.0);
+    assert!(rt.set_object_position(id as u32, 0.0, 5.0, 0.0).is_ok());
+    // Going back in time starts it from where it was put
+    rt.set_time(0.0);
+    assert!(rt.scene.objs[id].c.equals(&Vector::new(0.0, 5.0, 0.0)));
+    // Removing an object before it keeps the physics in step
+    assert!(rt.remove_object(0).is_ok());
+    rt.set_time(0.5);
+    rt.set_time(0.0);
+    assert!(rt.scene.objs[id - 1].c.equals(&Vector::new(0.0, 5.0, 0.0)));
+  }
+
+  #[test]
+  fn set_time_reaches_any_time() {
+    let positions = |rt: &RayTracer| rt.scene.objs.iter().map(|obj| (obj.c.x, obj.c.y, obj.c.z)).collect::<Vec<_>>();
+    let mut jumped = RayTracer::with_scene(3, bouncing_balls());
+    jumped.set_time(60.0);
+    let mut stepped = RayTracer::with_scene(3, bouncing_balls());
+    for i in 1..=1800 {
+      stepped.set_time(i as f64 / 30.0);
+    }
+    assert!(positions(&jumped) == positions(&stepped));
+
+    // Catching up takes a few calls, and ends up in the same place
+    let mut caught_up = RayTracer::with_scene(3, bouncing_balls());
+    let mut calls = 1;
+    while !caught_up.catch_up_to(60.0) {
+      calls += 1;
+    }
+    assert_eq!(calls, 6);
+    assert!(positions(&caught_up) == positions(&jumped));
+  }
+
+  #[test]
+  fn hdr_motion_follows_objects() {
+    let mut rt = RayTracer::with_scene(3, default_scene());
+    let mut strip = HdrStrip::new();
+    rt.set_time(1.0);
+    rt.begin_frame();
+    rt.render_hdr(55, &mut strip).unwrap();
+    assert!(strip.pixels.iter().all(|pxl| pxl.motion.is_none()));
+
+    rt.set_time(1.5);
+    rt.begin_frame();
+    rt.render_hdr(55, &mut strip).unwrap();
+    let mut then = RayTracer::with_scene(3, default_scene());
+    then.set_time(1.0);
+
+    // The middle of the orbiting sphere was on the sphere a frame ago too
+    let sphere: Vec<usize> = (0..strip.pixels.len()).filter(|&i| strip.pixels[i].obj_id == 1).collect();
+    let i = sphere[sphere.len() / 2];
+    let (then_col, then_row) = strip.pixels[i].motion.unwrap();
+    assert!((then_col - (i % NUM_COLS) as f64).abs() > 1.0);
+    let then_pxl = then.trace_hdr(then_row.round() as usize, then_col.round() as usize, (0.0, 0.0));
+    assert_eq!(then_pxl.obj_id, 1);
+
+    // The disc stays where it is
+    rt.render_hdr(80, &mut strip).unwrap();
+    let row = 80 * SQUARE_SIZE;
+    for (i, pxl) in strip.pixels.iter().enumerate() {
+      let (then_col, then_row) = pxl.motion.unwrap();
+      assert!((then_col - (i % NUM_COLS) as f64).abs() < 1e-6);
+      assert!((then_row - (row + i / NUM_COLS) as f64).abs() < 1e-6);
+    }
+
+    // After an edit there's nothing to follow
+    rt.set_object_colour(2, 0.0, 1.0, 0.0).unwrap();
+    rt.begin_frame();
+    rt.render_hdr(55, &mut strip).unwrap();
+    assert!(strip.pixels.iter().all(|pxl| pxl.motion.is_none()));
+  }
+
+  #[test]
+  fn render_frame_denoises() {
+    let mut rt = RayTracer::new(3);
+    let mut frame = vec![0; NUM_COLS * NUM_ROWS * 4];
+    assert!(rt.render_frame(&mut frame[1..]).is_err());
+    rt.render_frame(&mut frame).unwrap();
+    let mut strip = vec![0; STRIP_PIXELS * 4];
+    rt.render(40, &mut strip).unwrap();
+    assert!(frame[40 * STRIP_PIXELS * 4..41 * STRIP_PIXELS * 4] == strip[..]);
+
+    rt.set_denoise(true);
+    assert!(rt.filters_frames());
+    let mut denoised = vec![0; frame.len()];
+    rt.render_frame(&mut denoised).unwrap();
+    assert!(denoised != frame);
+    // Nothing is jittered without temporal accumulation, so nothing flickers
+    rt.begin_frame();
+    rt.render_frame(&mut frame).unwrap();
+    assert!(frame == denoised);
+  }
+
+  #[test]
+  fn glass_shadow_depends_on_index() {
+    let mut rt = RayTracer::with_scene(5, default_scene());
+    let floor = rt.scene.objs.iter().position(|obj| obj.typ == ObjectType::Disc).unwrap();
+    let id = rt.add_sphere(0.0, 10.0, 0.0, 0.5, 1.0, 1.0, 1.0, 0.0, 1.01, 0.2).unwrap();
+    let shade = |rt: &RayTracer| {
+      let pi = Vector::new(0.0, 8.0, 0.0);
+      rt.get_shading(&Vector::new(0.0, 4.0, 0.0), &pi, &rt.scene.objs[floor], floor).x
+    };
+    let thin = shade(&rt);
+    rt.set_object_material(id, 0.0, 2.4, 0.5, 0.2).unwrap();
+    let dense = shade(&rt);
+    assert!(thin > 0.99 && dense < 0.8, "{} {}", thin, dense);
+  }
+
+  #[test]
+  fn emitters_follow_edits() {
+    let mut rt = RayTracer::with_scene_name(3, "emissive").unwrap();
+    let (id, samples) = &rt.emitters[0];
+    let (id, before) = (*id, samples[0].pi);
+    let c = rt.scene.objs[id].c;
+    rt.set_object_position(id as u32, c.x + 1.0, c.y, c.z).unwrap();
+    assert!((rt.emitters[0].1[0].pi.x - before.x - 1.0).abs() < 1e-9);
+
+    rt.remove_object(id as u32).unwrap();
+    assert!(rt.emitters.iter().all(|&(i, _)| rt.scene.objs[i].is_emissive()));
+  }
+
+  #[test]
+  fn set_post_process() {
+    let mut rt = RayTracer::new(3);
+    assert!(!rt.post_process().is_on());
+    assert!(rt.set_post_process("bloom 1 0.5 8\nvignette 0.3").is_ok());
+    assert!(rt.post_process().is_on());
+    assert!(rt.save_scene().contains("\nbloom 1 0.5 8\nvignette 0.3\n"));
+
+    // A bad setting leaves them as they were
+    assert!(matches!(rt.set_post_process("vignette 2"), Err(RayTracerError::InvalidScene(_))));
+    assert!(matches!(rt.set_post_process("fog 1"), Err(RayTracerError::SceneFile(_))));
+    assert!((rt.post_process().vignette - 0.3).abs() < 1e-9);
+
+    assert!(rt.set_post_process("").is_ok());
+    assert!(!rt.post_process().is_on());
+  }
+
+  #[test]
+  fn render_frame_post_processes() {
+    let mut rt = RayTracer::new(3);
+    let mut frame = vec![0; NUM_COLS * NUM_ROWS * 4];
+    rt.render_frame(&mut frame).unwrap();
+    rt.set_post_process("vignette 1").unwrap();
+    assert!(rt.filters_frames());
+    let mut vignetted = vec![0; frame.len()];
+    rt.render_frame(&mut vignetted).unwrap();
+    // The bottom of the image gets darker, the middle stays as it was
+    let sum = |bytes: &[u8]| bytes.iter().map(|&b| b as u64).sum::<u64>();
+    let bottom = (NUM_ROWS - SQUARE_SIZE) * NUM_COLS * 4;
+    assert!(sum(&vignetted[bottom..]) < sum(&frame[bottom..]));
+    let middle = (NUM_ROWS / 2 * NUM_COLS + NUM_COLS / 2) * 4;
+    assert_eq!(frame[middle..middle + 4], vignetted[middle..middle + 4]);
   }
 }
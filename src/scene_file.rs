@@ -0,0 +1,819 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// A text format for scenes, to load them and save them again.  Numbers are
+// written so they read back exactly, so a saved scene renders the same.
+//
+// One item per line, `#` starts a comment:
+//
+//   eye 0 2 -15 0.75 0.75 2           # c, w, h, d
+//   light point 5 7.5 -2 1 1 1        # c, colour
+//   light directional 0 -1 0 1 1 1    # direction, colour
+//   light spot 0 5 0 0 -1 0 10 20 1 1 1  # c, direction, inner, outer, colour
+//   fog 0.002 0.002 0.002 0.02 0.02 0.02 0.7  # absorption, scattering, g
+//   volume 0 1 0 1.5 0.1 0.1 0.1 0.2 0.2 0.2 0  # c, radius, then as fog
+//   caustics true
+//   bloom 1 0.5 8                     # threshold, strength, radius in pixels
+//   lens 0.05 2                       # distortion, chromatic aberration in pixels
+//   vignette 0.3
+//   grade 2                           # a colour lookup table of size^3, size up to 64
+//     colour 0 0 0                    # colours, red changing fastest
+//   end
+//   mesh torus                        # shared by the objects that use it
+//     vertex 1 0 0
+//     normal 1 0 0                    # none, or one per vertex
+//     colour 1 0 0                    # none, or one per vertex
+//     triangle 0 1 2
+//   end
+//   object sphere                     # or disc, mesh <name>, csg, sdf
+//     c 0.7 1.2 0.4
+//     radius 1
+//     col 1 0 0
+//     ...
+//   end
+//
+// A CSG object holds its two children as nested objects, left first.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::animation::{Animation, Keyframe};
+use crate::constants::*;
+use crate::csg::{Csg, CsgOp};
+use crate::mesh::Mesh;
+use crate::objects::*;
+use crate::post::{Bloom, Lut, PostProcess, MAX_LUT_SIZE};
+use crate::sdf::Sdf;
+use crate::transform::{Matrix4, Transform};
+use crate::vector::Vector;
+use crate::volume::{Medium, Volume};
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+  pub line: usize, // Counted from 1
+  pub message: String,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+fn error<T>(line: usize, message: &str) -> Result<T, ParseError> {
+  Err(ParseError {
+    line,
+    message: message.to_string(),
+  })
+}
+
+//
+// Saving
+//
+
+/**
+ * Write the scene out in the text format.
+ */
+pub fn save(scene: &Scene) -> String {
+  let mut out = String::new();
+  let e = &scene.eye;
+  writeln!(out, "eye {} {} {} {}", vector(&e.c), e.w, e.h, e.d).unwrap();
+  for light in &scene.lights {
+    match light.kind {
+      LightKind::Point => writeln!(out, "light point {} {}", vector(&light.c), vector(&light.col)),
+      LightKind::Directional { direction } => {
+        writeln!(out, "light directional {} {}", vector(&direction), vector(&light.col))
+      }
+      LightKind::Spot {
+        direction,
+        inner,
+        outer,
+      } => writeln!(
+        out,
+        "light spot {} {} {} {} {}",
+        vector(&light.c),
+        vector(&direction),
+        inner,
+        outer,
+        vector(&light.col)
+      ),
+    }
+    .unwrap();
+  }
+  if let Some(fog) = &scene.fog {
+    writeln!(out, "fog {}", medium(fog)).unwrap();
+  }
+  for volume in &scene.volumes {
+    writeln!(out, "volume {} {} {}", vector(&volume.c), volume.radius, medium(&volume.medium)).unwrap();
+  }
+  if scene.caustics {
+    writeln!(out, "caustics true").unwrap();
+  }
+  let post = &scene.post;
+  if let Some(bloom) = &post.bloom {
+    writeln!(out, "bloom {} {} {}", bloom.threshold, bloom.strength, bloom.radius).unwrap();
+  }
+  if post.distortion != 0.0 || post.chromatic_aberration != 0.0 {
+    writeln!(out, "lens {} {}", post.distortion, post.chromatic_aberration).unwrap();
+  }
+  if post.vignette != 0.0 {
+    writeln!(out, "vignette {}", post.vignette).unwrap();
+  }
+  if let Some(lut) = &post.grading {
+    writeln!(out, "grade {}", lut.size).unwrap();
+    for colour in &lut.colours {
+      writeln!(out, "  colour {}", vector(colour)).unwrap();
+    }
+    writeln!(out, "end").unwrap();
+  }
+
+  // Each shared mesh is written once
+  let mut meshes: Vec<Arc<Mesh>> = vec![];
+  for obj in &scene.objs {
+    collect_meshes(obj, &mut meshes);
+  }
+  for (i, mesh) in meshes.iter().enumerate() {
+    writeln!(out, "mesh m{}", i).unwrap();
+    for v in &mesh.vertices {
+      writeln!(out, "  vertex {}", vector(v)).unwrap();
+    }
+    for n in &mesh.normals {
+      writeln!(out, "  normal {}", vector(n)).unwrap();
+    }
+    for c in &mesh.colours {
+      writeln!(out, "  colour {}", vector(c)).unwrap();
+    }
+    for [a, b, c] in &mesh.triangles {
+      writeln!(out, "  triangle {} {} {}", a, b, c).unwrap();
+    }
+    writeln!(out, "end").unwrap();
+  }
+
+  for obj in &scene.objs {
+    save_object(&mut out, obj, &meshes, 0);
+  }
+  out
+}
+
+fn vector(v: &Vector) -> String {
+  format!("{} {} {}", v.x, v.y, v.z)
+}
+
+fn medium(m: &Medium) -> String {
+  format!("{} {} {}", vector(&m.absorption), vector(&m.scattering), m.g)
+}
+
+fn collect_meshes(obj: &Object, meshes: &mut Vec<Arc<Mesh>>) {
+  if let Some(mesh) = &obj.mesh {
+    if !meshes.iter().any(|m| Arc::ptr_eq(m, mesh)) {
+      meshes.push(mesh.clone());
+    }
+  }
+  if let Some(csg) = &obj.csg {
+    collect_meshes(&csg.left, meshes);
+    collect_meshes(&csg.right, meshes);
+  }
+}
+
+fn save_object(out: &mut String, obj: &Object, meshes: &[Arc<Mesh>], depth: usize) {
+  let indent = "  ".repeat(depth);
+  let typ = match obj.typ {
+    ObjectType::Sphere => "sphere".to_string(),
+    ObjectType::Disc => "disc".to_string(),
+    ObjectType::Mesh => match &obj.mesh {
+      Some(mesh) => format!("mesh m{}", meshes.iter().position(|m| Arc::ptr_eq(m, mesh)).unwrap()),
+      None => "mesh".to_string(),
+    },
+    ObjectType::Csg => "csg".to_string(),
+    ObjectType::Sdf => "sdf".to_string(),
+  };
+  writeln!(out, "{}object {}", indent, typ).unwrap();
+  let mut line = |s: String| writeln!(out, "{}  {}", indent, s).unwrap();
+
+  line(format!("c {}", vector(&obj.c)));
+  if obj.typ == ObjectType::Disc {
+    line(format!("n {}", vector(&obj.n)));
+  }
+  line(format!("radius {}", obj.radius));
+  line(format!("col {}", vector(&obj.col)));
+  line(format!("diff {}", obj.diff));
+  line(format!("spec {}", obj.spec));
+  line(format!("rfl {}", obj.rfl));
+  line(format!("rfr {}", obj.rfr));
+  line(format!("ambient {}", obj.ambient_light));
+  line(format!("emission {} {}", vector(&obj.emission), obj.emission_strength));
+  line(format!("casts_shadow {}", obj.can_create_shadow));
+  line(format!("receives_shadow {}", obj.can_receive_shadow));
+  if obj.dynamic {
+    line("dynamic true".to_string());
+  }
+  line(format!("velocity {}", vector(&obj.velocity)));
+  match &obj.animation {
+    Animation::Static => {}
+    Animation::Orbit {
+      start,
+      degrees_per_sec,
+    } => line(format!("orbit {} {}", vector(start), degrees_per_sec)),
+    Animation::Keyframes { frames, looped } => {
+      line(format!("looped {}", looped));
+      for frame in frames {
+        line(format!("keyframe {} {}", frame.t, vector(&frame.c)));
+      }
+    }
+  }
+  if let Some(transform) = &obj.transform {
+    line(format!("matrix {}", matrix(&transform.matrix)));
+    line(format!("inverse {}", matrix(&transform.inverse)));
+  }
+  if let Some(sdf) = &obj.sdf {
+    match sdf.as_ref() {
+      Sdf::Sphere { radius } => line(format!("sdf sphere {}", radius)),
+      Sdf::RoundBox { half, radius } => line(format!("sdf round_box {} {}", vector(half), radius)),
+      Sdf::Metaballs { balls, smoothness } => {
+        line(format!("sdf metaballs {}", smoothness));
+        for (c, r) in balls {
+          line(format!("ball {} {}", vector(c), r));
+        }
+      }
+      Sdf::Mandelbulb { power, iterations } => line(format!("sdf mandelbulb {} {}", power, iterations)),
+    }
+  }
+  if let Some(csg) = &obj.csg {
+    let op = match csg.op {
+      CsgOp::Union => "union",
+      CsgOp::Intersection => "intersection",
+      CsgOp::Difference => "difference",
+    };
+    line(format!("op {}", op));
+    save_object(out, &csg.left, meshes, depth + 1);
+    save_object(out, &csg.right, meshes, depth + 1);
+  }
+  writeln!(out, "{}end", indent).unwrap();
+}
+
+fn matrix(m: &Matrix4) -> String {
+  let values: Vec<String> = m.m.iter().flatten().map(|v| v.to_string()).collect();
+  values.join(" ")
+}
+
+//
+// Loading
+//
+
+// The lines of the file that have something on them, with their numbers.
+struct Lines<'a> {
+  lines: Vec<(usize, Vec<&'a str>)>,
+  pos: usize,
+}
+
+impl<'a> Lines<'a> {
+  fn new(text: &'a str) -> Lines<'a> {
+    let lines = text
+      .lines()
+      .enumerate()
+      .map(|(i, line)| (i + 1, line.split('#').next().unwrap().split_whitespace().collect::<Vec<_>>()))
+      .filter(|(_, words)| !words.is_empty())
+      .collect();
+    Lines { lines, pos: 0 }
+  }
+
+  fn next(&mut self) -> Option<(usize, Vec<&'a str>)> {
+    let line = self.lines.get(self.pos).cloned();
+    self.pos += 1;
+    line
+  }
+
+  // The line after the last one, for errors about a missing `end`
+  fn last_line(&self) -> usize {
+    self.lines.last().map_or(1, |(n, _)| n + 1)
+  }
+}
+
+// The numbers after the keyword, there must be `count` of them.
+fn numbers(line: usize, words: &[&str], count: usize) -> Result<Vec<f64>, ParseError> {
+  if words.len() != count + 1 {
+    return error(line, &format!("{} needs {} numbers", words[0], count));
+  }
+  words[1..]
+    .iter()
+    .map(|w| match w.parse::<f64>() {
+      // "nan" and "inf" parse, but nothing can be placed or sized by them
+      Ok(v) if v.is_finite() => Ok(v),
+      _ => error(line, &format!("{} isn't a number", w)),
+    })
+    .collect()
+}
+
+fn vector_at(v: &[f64], i: usize) -> Vector {
+  Vector::new(v[i], v[i + 1], v[i + 2])
+}
+
+// A direction, normalised unless it already is.  The saved directions are,
+// and normalising them again could change them in the last bit.
+fn direction_at(line: usize, v: &[f64], i: usize) -> Result<Vector, ParseError> {
+  let direction = vector_at(v, i);
+  let length = direction.length();
+  if length == 0.0 {
+    return error(line, "the direction can't be zero length");
+  }
+  if (length - 1.0).abs() < 1e-12 {
+    Ok(direction)
+  } else {
+    Ok(direction.scale(1.0 / length))
+  }
+}
+
+fn flag(line: usize, words: &[&str]) -> Result<bool, ParseError> {
+  match words {
+    [_, "true"] => Ok(true),
+    [_, "false"] => Ok(false),
+    _ => error(line, &format!("{} needs true or false", words[0])),
+  }
+}
+
+fn index(line: usize, word: &str) -> Result<usize, ParseError> {
+  match word.parse::<usize>() {
+    Ok(i) => Ok(i),
+    Err(_) => error(line, &format!("{} isn't an index", word)),
+  }
+}
+
+fn matrix_from(v: &[f64]) -> Matrix4 {
+  let mut m = Matrix4::identity();
+  for (i, value) in v.iter().enumerate() {
+    m.m[i / 4][i % 4] = *value;
+  }
+  m
+}
+
+fn medium_from(v: &[f64]) -> Medium {
+  Medium {
+    absorption: vector_at(v, 0),
+    scattering: vector_at(v, 3),
+    g: v[6],
+  }
+}
+
+/**
+ * Read a scene written in the text format.
+ */
+pub fn load(text: &str) -> Result<Scene, ParseError> {
+  let mut lines = Lines::new(text);
+  let mut eye = None;
+  let mut scene = Scene {
+    eye: Eye {
+      c: ORIGIN,
+      w: 0.0,
+      h: 0.0,
+      d: 0.0,
+    },
+    lights: vec![],
+    objs: vec![],
+    fog: None,
+    volumes: vec![],
+    caustics: false,
+    post: PostProcess::default(),
+  };
+  let mut meshes: HashMap<String, Arc<Mesh>> = HashMap::new();
+
+  while let Some((line, words)) = lines.next() {
+    match words[0] {
+      "eye" => {
+        let v = numbers(line, &words, 6)?;
+        eye = Some(Eye {
+          c: vector_at(&v, 0),
+          w: v[3],
+          h: v[4],
+          d: v[5],
+        });
+      }
+      "light" => scene.add_light(load_light(line, &words)?),
+      "fog" => scene.fog = Some(medium_from(&numbers(line, &words, 7)?)),
+      "volume" => {
+        let v = numbers(line, &words, 11)?;
+        scene.add_volume(Volume {
+          medium: medium_from(&v[4..]),
+          c: vector_at(&v, 0),
+          radius: v[3],
+        });
+      }
+      "caustics" => scene.caustics = flag(line, &words)?,
+      "bloom" | "lens" | "vignette" | "grade" => load_post_item(&mut lines, line, &words, &mut scene.post)?,
+      "mesh" => {
+        if words.len() != 2 {
+          return error(line, "mesh needs a name");
+        }
+        let mesh = load_mesh(&mut lines)?;
+        meshes.insert(words[1].to_string(), Arc::new(mesh));
+      }
+      "object" => {
+        let obj = load_object(&mut lines, line, &words, &meshes)?;
+        scene.add_object(obj);
+      }
+      other => return error(line, &format!("unknown item {}", other)),
+    }
+  }
+
+  match eye {
+    Some(eye) => {
+      scene.eye = eye;
+      Ok(scene)
+    }
+    None => error(1, "the scene has no eye"),
+  }
+}
+
+/**
+ * Read post-processing settings on their own, in the same format: only
+ * `bloom`, `lens`, `vignette` and `grade`.
+ */
+pub fn load_post_process(text: &str) -> Result<PostProcess, ParseError> {
+  let mut lines = Lines::new(text);
+  let mut post = PostProcess::default();
+  while let Some((line, words)) = lines.next() {
+    match words[0] {
+      "bloom" | "lens" | "vignette" | "grade" => load_post_item(&mut lines, line, &words, &mut post)?,
+      other => return error(line, &format!("unknown post-processing item {}", other)),
+    }
+  }
+  Ok(post)
+}
+
+fn load_post_item(lines: &mut Lines, line: usize, words: &[&str], post: &mut PostProcess) -> Result<(), ParseError> {
+  match words[0] {
+    "bloom" => {
+      let v = numbers(line, words, 3)?;
+      post.bloom = Some(Bloom {
+        threshold: v[0],
+        strength: v[1],
+        radius: v[2],
+      });
+    }
+    "lens" => {
+      let v = numbers(line, words, 2)?;
+      post.distortion = v[0];
+      post.chromatic_aberration = v[1];
+    }
+    "vignette" => post.vignette = numbers(line, words, 1)?[0],
+    _ => {
+      if words.len() != 2 {
+        return error(line, "grade needs the size of the table");
+      }
+      let size = index(line, words[1])?;
+      if !(2..=MAX_LUT_SIZE).contains(&size) {
+        return error(line, &format!("grade size needs to be from 2 to {}", MAX_LUT_SIZE));
+      }
+      let mut colours = vec![];
+      loop {
+        match lines.next() {
+          Some((_, words)) if words[0] == "end" => break,
+          Some((line, words)) if words[0] == "colour" => colours.push(vector_at(&numbers(line, &words, 3)?, 0)),
+          Some((line, words)) => return error(line, &format!("unknown grade item {}", words[0])),
+          None => return error(lines.last_line(), "grade has no end"),
+        }
+      }
+      if colours.len() != size * size * size {
+        return error(line, &format!("grade {} needs {} colours", size, size * size * size));
+      }
+      post.grading = Some(Lut { size, colours });
+    }
+  }
+  Ok(())
+}
+
+fn load_light(line: usize, words: &[&str]) -> Result<Light, ParseError> {
+  let kind = words.get(1).copied().unwrap_or("");
+  let words = &words[1..];
+  match kind {
+    "point" => {
+      let v = numbers(line, words, 6)?;
+      Ok(Light::point(vector_at(&v, 0), vector_at(&v, 3)))
+    }
+    "directional" => {
+      let v = numbers(line, words, 6)?;
+      Ok(Light {
+        c: ORIGIN,
+        col: vector_at(&v, 3),
+        kind: LightKind::Directional {
+          direction: direction_at(line, &v, 0)?,
+        },
+      })
+    }
+    "spot" => {
+      let v = numbers(line, words, 11)?;
+      Ok(Light {
+        c: vector_at(&v, 0),
+        col: vector_at(&v, 8),
+        kind: LightKind::Spot {
+          direction: direction_at(line, &v, 3)?,
+          inner: v[6],
+          outer: v[7],
+        },
+      })
+    }
+    _ => error(line, "light needs to be point, directional or spot"),
+  }
+}
+
+fn load_mesh(lines: &mut Lines) -> Result<Mesh, ParseError> {
+  let (mut vertices, mut normals, mut colours, mut triangles) = (vec![], vec![], vec![], vec![]);
+  while let Some((line, words)) = lines.next() {
+    match words[0] {
+      "vertex" => vertices.push(vector_at(&numbers(line, &words, 3)?, 0)),
+      "normal" => normals.push(vector_at(&numbers(line, &words, 3)?, 0)),
+      "colour" => colours.push(vector_at(&numbers(line, &words, 3)?, 0)),
+      "triangle" => {
+        if words.len() != 4 {
+          return error(line, "triangle needs 3 vertex indices");
+        }
+        let mut tri = [0; 3];
+        for i in 0..3 {
+          tri[i] = index(line, words[i + 1])?;
+        }
+        triangles.push(tri);
+      }
+      "end" => {
+        if !normals.is_empty() && normals.len() != vertices.len() {
+          return error(line, "a mesh needs no normals, or one for each vertex");
+        }
+        if !colours.is_empty() && colours.len() != vertices.len() {
+          return error(line, "a mesh needs no colours, or one for each vertex");
+        }
+        if triangles.iter().flatten().any(|&i| i >= vertices.len()) {
+          return error(line, "a triangle uses a vertex that isn't there");
+        }
+        let mesh = Mesh::new(vertices, normals, triangles);
+        return Ok(if colours.is_empty() { mesh } else { mesh.with_colours(colours) });
+      }
+      other => return error(line, &format!("unknown mesh item {}", other)),
+    }
+  }
+  error(lines.last_line(), "mesh has no end")
+}
+
+fn load_object(
+  lines: &mut Lines,
+  start: usize,
+  header: &[&str],
+  meshes: &HashMap<String, Arc<Mesh>>,
+) -> Result<Object, ParseError> {
+  let mut obj = Object::new_sphere(ORIGIN, 0.0, COL_WHITE, 0.0, 0.0, 0.0);
+  obj.typ = match header.get(1).copied() {
+    Some("sphere") => ObjectType::Sphere,
+    Some("disc") => ObjectType::Disc,
+    Some("mesh") => {
+      let name = header.get(2).copied().unwrap_or("");
+      match meshes.get(name) {
+        Some(mesh) => obj.mesh = Some(mesh.clone()),
+        None => return error(start, &format!("there's no mesh called {}", name)),
+      }
+      ObjectType::Mesh
+    }
+    Some("csg") => ObjectType::Csg,
+    Some("sdf") => ObjectType::Sdf,
+    _ => return error(start, "object needs to be sphere, disc, mesh, csg or sdf"),
+  };
+
+  let mut frames = vec![];
+  let mut looped = false;
+  let mut matrix = None;
+  let mut inverse = None;
+  let mut sdf = None;
+  let mut op = None;
+  let mut children = vec![];
+  while let Some((line, words)) = lines.next() {
+    match words[0] {
+      "c" => obj.c = vector_at(&numbers(line, &words, 3)?, 0),
+      "n" => obj.n = direction_at(line, &numbers(line, &words, 3)?, 0)?,
+      "radius" => obj.radius = numbers(line, &words, 1)?[0],
+      "col" => obj.col = vector_at(&numbers(line, &words, 3)?, 0),
+      "diff" => obj.diff = numbers(line, &words, 1)?[0],
+      "spec" => obj.spec = numbers(line, &words, 1)?[0],
+      "rfl" => obj.rfl = numbers(line, &words, 1)?[0],
+      "rfr" => obj.rfr = numbers(line, &words, 1)?[0],
+      "ambient" => obj.ambient_light = numbers(line, &words, 1)?[0],
+      "emission" => {
+        let v = numbers(line, &words, 4)?;
+        obj.set_emission(vector_at(&v, 0), v[3]);
+      }
+      "casts_shadow" => obj.can_create_shadow = flag(line, &words)?,
+      "receives_shadow" => obj.can_receive_shadow = flag(line, &words)?,
+      "dynamic" => obj.dynamic = flag(line, &words)?,
+      "velocity" => obj.velocity = vector_at(&numbers(line, &words, 3)?, 0),
+      "orbit" => {
+        let v = numbers(line, &words, 4)?;
+        obj.animation = Animation::orbit(vector_at(&v, 0), v[3]);
+      }
+      "looped" => looped = flag(line, &words)?,
+      "keyframe" => {
+        let v = numbers(line, &words, 4)?;
+        frames.push(Keyframe {
+          t: v[0],
+          c: vector_at(&v, 1),
+        });
+      }
+      "matrix" => matrix = Some(matrix_from(&numbers(line, &words, 16)?)),
+      "inverse" => inverse = Some(matrix_from(&numbers(line, &words, 16)?)),
+      "sdf" => sdf = Some(load_sdf(line, &words)?),
+      "ball" => match &mut sdf {
+        Some(Sdf::Metaballs { balls, .. }) => {
+          let v = numbers(line, &words, 4)?;
+          balls.push((vector_at(&v, 0), v[3]));
+        }
+        _ => return error(line, "ball needs to follow sdf metaballs"),
+      },
+      "op" => {
+        op = Some(match words.get(1).copied() {
+          Some("union") => CsgOp::Union,
+          Some("intersection") => CsgOp::Intersection,
+          Some("difference") => CsgOp::Difference,
+          _ => return error(line, "op needs to be union, intersection or difference"),
+        })
+      }
+      "object" => children.push(load_object(lines, line, &words, meshes)?),
+      "end" => {
+        if !frames.is_empty() {
+          obj.animation = Animation::keyframes(frames, looped);
+        }
+        obj.transform = match (matrix, inverse) {
+          (Some(matrix), Some(inverse)) => Some(Transform { matrix, inverse }),
+          (Some(matrix), None) => match Transform::from_matrix(matrix) {
+            Some(transform) => Some(transform),
+            None => return error(line, "the matrix can't be inverted"),
+          },
+          (None, Some(_)) => return error(line, "inverse needs a matrix"),
+          (None, None) => None,
+        };
+        // Works out the plane of a disc
+        obj.set_position(obj.c);
+        match obj.typ {
+          ObjectType::Sdf => match sdf {
+            Some(sdf) => obj.sdf = Some(Arc::new(sdf)),
+            None => return error(start, "sdf object needs an sdf line"),
+          },
+          ObjectType::Csg => {
+            let right = children.pop();
+            let left = children.pop();
+            match (op, left, right, children.is_empty()) {
+              (Some(op), Some(left), Some(right), true) => obj.csg = Some(Arc::new(Csg { op, left, right })),
+              _ => return error(start, "csg object needs an op and two objects"),
+            }
+          }
+          _ if !children.is_empty() => return error(start, "only csg objects hold other objects"),
+          _ => {}
+        }
+        return Ok(obj);
+      }
+      other => return error(line, &format!("unknown object item {}", other)),
+    }
+  }
+  error(lines.last_line(), "object has no end")
+}
+
+fn load_sdf(line: usize, words: &[&str]) -> Result<Sdf, ParseError> {
+  let kind = words.get(1).copied().unwrap_or("");
+  let words = &words[1..];
+  match kind {
+    "sphere" => Ok(Sdf::Sphere {
+      radius: numbers(line, words, 1)?[0],
+    }),
+    "round_box" => {
+      let v = numbers(line, words, 4)?;
+      Ok(Sdf::RoundBox {
+        half: vector_at(&v, 0),
+        radius: v[3],
+      })
+    }
+    "metaballs" => Ok(Sdf::Metaballs {
+      balls: vec![],
+      smoothness: numbers(line, words, 1)?[0],
+    }),
+    "mandelbulb" => {
+      let v = numbers(line, words, 2)?;
+      Ok(Sdf::Mandelbulb {
+        power: v[0],
+        iterations: v[1] as usize,
+      })
+    }
+    _ => error(line, "sdf needs to be sphere, round_box, metaballs or mandelbulb"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::post::Lut;
+  use crate::scene_file::{load, load_post_process, save, ParseError};
+  use crate::objects::LightKind;
+  use crate::scenes::by_name;
+  use crate::vector::Vector;
+
+  #[test]
+  fn scene_file_round_trip() {
+    for name in &[
+      "default",
+      "bouncing_balls",
+      "transformed",
+      "instances",
+      "csg",
+      "sdf",
+      "fog",
+      "emissive",
+      "spotlights",
+    ] {
+      let text = save(&by_name(name).unwrap());
+      let scene = load(&text).unwrap_or_else(|e| panic!("{}: {}", name, e));
+      assert_eq!(save(&scene), text, "{}", name);
+    }
+  }
+
+  #[test]
+  fn scene_file_shares_meshes() {
+    let text = save(&by_name("instances").unwrap());
+    assert_eq!(text.matches("\nmesh ").count(), 1);
+    let scene = load(&text).unwrap();
+    let mesh = scene.objs[1].mesh.as_ref().unwrap();
+    assert!(scene.objs[1..].iter().all(|obj| std::sync::Arc::ptr_eq(obj.mesh.as_ref().unwrap(), mesh)));
+  }
+
+  #[test]
+  fn scene_file_errors() {
+    let err = |text: &str| load(text).err().unwrap();
+    assert_eq!(
+      err("eye 0 2 -15 0.75 0.75 2\nobject sphere\n  c 0 1\nend\n"),
+      ParseError {
+        line: 3,
+        message: "c needs 3 numbers".to_string()
+      }
+    );
+    assert_eq!(err("eye 0 2 -15 0.75 0.75 2\nobject sphere\n").line, 3);
+    assert_eq!(err("light point 0 1 0 1 1 1\n").message, "the scene has no eye");
+    assert_eq!(err("eye 0 2 -15 0.75 0.75 x").message, "x isn't a number");
+    assert_eq!(
+      err("eye 0 2 -15 0.75 0.75 2\nobject sphere\n  keyframe nan 0 0 0\nend").message,
+      "nan isn't a number"
+    );
+    assert_eq!(err("eye 0 2 -15 0.75 0.75 inf").message, "inf isn't a number");
+    assert_eq!(
+      err("eye 0 2 -15 0.75 0.75 2\nlight directional 0 0 0 1 1 1").message,
+      "the direction can't be zero length"
+    );
+    assert_eq!(
+      err("eye 0 2 -15 0.75 0.75 2\nobject mesh teapot\nend").message,
+      "there's no mesh called teapot"
+    );
+  }
+
+  #[test]
+  fn scene_file_normalises_directions() {
+    let scene = load("eye 0 2 -15 0.75 0.75 2\nlight directional 0 -2 0 1 1 1").unwrap();
+    match scene.lights[0].kind {
+      LightKind::Directional { direction } => assert!(direction.equals(&Vector::new(0.0, -1.0, 0.0))),
+      _ => panic!("not directional"),
+    }
+    let scene = load("eye 0 2 -15 0.75 0.75 2\nobject disc\n  c 0 3 0\n  n 0 2 0\n  radius 5\nend").unwrap();
+    assert!(scene.objs[0].n.equals(&Vector::new(0.0, 1.0, 0.0)));
+    assert!((scene.objs[0].d - 3.0).abs() < 1e-12);
+  }
+
+  #[test]
+  fn scene_file_post_process() {
+    let mut scene = by_name("default").unwrap();
+    scene.post = load_post_process("bloom 1 0.5 8\nlens 0.05 2\nvignette 0.3").unwrap();
+    scene.post.grading = Some(Lut::identity(3));
+    let text = save(&scene);
+    assert!(text.contains("bloom 1 0.5 8\nlens 0.05 2\nvignette 0.3\ngrade 3\n  colour 0 0 0\n"));
+    let loaded = load(&text).unwrap();
+    assert_eq!(save(&loaded), text);
+    assert_eq!(loaded.post.grading.as_ref().unwrap().colours.len(), 27);
+
+    let err = |text: &str| load_post_process(text).err().unwrap().message;
+    assert_eq!(err("bloom 1 2"), "bloom needs 3 numbers");
+    assert_eq!(err("grade 2\n  colour 0 0 0\nend"), "grade 2 needs 8 colours");
+    assert_eq!(err("grade 2\n  colour 0 0 0"), "grade has no end");
+    assert_eq!(err("grade 1\nend"), "grade size needs to be from 2 to 64");
+    assert_eq!(err("grade 4000000\nend"), "grade size needs to be from 2 to 64");
+    assert_eq!(err("caustics true"), "unknown post-processing item caustics");
+  }
+}
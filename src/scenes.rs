@@ -0,0 +1,492 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Canonical scenes, used by the demo, the bench and the tests.
+
+use std::sync::Arc;
+
+use crate::animation::Animation;
+use crate::constants::*;
+use crate::csg::CsgOp;
+use crate::mesh::Mesh;
+use crate::objects::*;
+use crate::post::PostProcess;
+use crate::sdf::Sdf;
+use crate::transform::Transform;
+use crate::vector::Vector;
+use crate::vector::Vector as Colour;
+use crate::volume::{Medium, Volume};
+
+// How fast the spheres of the default scene circle, in degrees per second.
+pub const ORBIT_SPEED: f64 = 360.0 / 10.0;
+
+fn empty_scene() -> Scene {
+  Scene {
+    eye: Eye {
+      c: Vector {
+        x: 0.0,
+        y: 2.0,
+        z: -15.0,
+      },
+      w: 0.75,
+      h: 0.75,
+      d: 2.0,
+    },
+    lights: vec![],
+    objs: vec![],
+    fog: None,
+    volumes: vec![],
+    caustics: false,
+    post: PostProcess::default(),
+  }
+}
+
+fn add_disc(scene: &mut Scene) {
+  let disc_norm = Vector {
+    x: 0.0,
+    y: 1.0,
+    z: 0.0,
+  };
+  let disc_centre = Vector {
+    x: 0.0,
+    y: 0.0,
+    z: 0.0,
+  };
+  let mut disc = Object::new_disc(
+    disc_centre,
+    disc_norm,
+    6.0,
+    0.7, // Reflectivity -> 0.0 to 1.0
+    0.0, // Refractivity
+    0.6, // Ambient light
+  );
+  disc.set_diffuse(0.2);
+  disc.can_create_shadow = false;
+  disc.can_receive_shadow = true;
+  scene.add_object(disc);
+}
+
+fn add_light(scene: &mut Scene) {
+  scene.add_light(Light::point(Vector::new(5.0, 7.5, -2.0), COL_WHITE));
+}
+
+/**
+ * The demo scene: a checkered disc with three spheres, one of them glass.
+ */
+pub fn default_scene() -> Scene {
+  let mut scene = empty_scene();
+
+  //
+  // Add the disc
+  //
+  add_disc(&mut scene);
+
+  //
+  // Add a sphere
+  //
+  let mut obj = Object::new_sphere(
+    Vector::new(0.7, 1.2, 0.4),
+    1.0,     // Radius
+    COL_RED, // Colour of sphere
+    0.9,     // Reflectivity -> 0.0 to 1.0
+    0.0,     // Refractivity
+    0.2,     // Ambient light
+  );
+  obj.set_diffuse(0.2);
+  obj.can_create_shadow = true;
+  obj.can_receive_shadow = false;
+  obj.animation = Animation::orbit(obj.c, ORBIT_SPEED);
+  scene.add_object(obj);
+
+  //
+  // ... and another sphere
+  //
+  let mut obj = Object::new_sphere(
+    Vector::new(-1.5, 1.6, 0.4),
+    0.8,       // Radius
+    COL_WHITE, // Colour of sphere
+    0.6,       // Reflectivity -> 0.0 to 1.0
+    0.0,       // Refractivity
+    0.2,       // Ambient light
+  );
+  obj.set_diffuse(0.7);
+  obj.can_create_shadow = true;
+  obj.can_receive_shadow = false;
+  obj.animation = Animation::orbit(obj.c, ORBIT_SPEED);
+  scene.add_object(obj);
+
+  //
+  // ... and another sphere
+  //
+  let mut obj = Object::new_sphere(
+    Vector::new(1.2, 0.8, -1.8),
+    0.8,       // Radius
+    COL_WHITE, // Colour of sphere
+    0.4,       // Reflectivity -> 0.0 to 1.0
+    1.12,      // Refractivity
+    0.05,      // Ambient light
+  );
+  obj.set_diffuse(0.0);
+  obj.can_create_shadow = true;
+  obj.can_receive_shadow = false;
+  obj.animation = Animation::orbit(obj.c, ORBIT_SPEED);
+  scene.add_object(obj);
+
+  //
+  // Add a light
+  //
+  add_light(&mut scene);
+
+  scene
+}
+
+/**
+ * A grid of `n` x `n` small reflective spheres sitting above the disc.
+ */
+pub fn many_spheres(n: usize) -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let spacing = 8.0 / n as f64;
+  let radius = spacing * 0.4;
+  for i in 0..n {
+    for j in 0..n {
+      let x = -4.0 + spacing * (i as f64 + 0.5);
+      let z = -4.0 + spacing * (j as f64 + 0.5);
+      let col = if (i + j) % 2 == 0 { COL_RED } else { COL_WHITE };
+      let mut obj = Object::new_sphere(Vector::new(x, radius + 0.5, z), radius, col, 0.5, 0.0, 0.2);
+      obj.set_diffuse(0.5);
+      scene.add_object(obj);
+    }
+  }
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * Balls dropped onto the disc, moved by the physics simulation.
+ */
+pub fn bouncing_balls() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let balls = [
+    (Vector::new(0.0, 2.5, 0.0), 0.8, COL_RED, Vector::new(0.4, 0.0, 0.2)),
+    (Vector::new(-1.8, 4.0, 0.6), 0.6, COL_WHITE, Vector::new(0.6, 0.0, -0.3)),
+    (Vector::new(1.8, 5.5, -0.5), 0.6, COL_WHITE, Vector::new(-0.5, 0.0, 0.4)),
+    (Vector::new(0.5, 7.0, -1.5), 0.5, COL_RED, Vector::new(0.0, 0.0, 0.5)),
+    (Vector::new(-0.6, 8.5, 1.2), 0.5, COL_WHITE, Vector::new(0.3, 0.0, -0.6)),
+  ];
+  for &(c, radius, col, velocity) in balls.iter() {
+    let mut obj = Object::new_sphere(c, radius, col, 0.6, 0.0, 0.2);
+    obj.set_diffuse(0.5);
+    obj.dynamic = true;
+    obj.velocity = velocity;
+    scene.add_object(obj);
+  }
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * The disc and spheres placed with transforms: the checker pattern is
+ * turned, one sphere is squashed into an ellipsoid and another stretched and
+ * tipped over.
+ */
+pub fn transformed() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+  scene.objs[0].set_transform(Transform::rotate_y(30.0));
+
+  let mut obj = Object::new_sphere(ORIGIN, 1.0, COL_RED, 0.6, 0.0, 0.2);
+  obj.set_diffuse(0.5);
+  obj.set_transform(
+    Transform::scale(&Vector::new(1.6, 0.6, 1.0)).then(&Transform::translate(&Vector::new(-1.2, 0.6, 0.0))),
+  );
+  scene.add_object(obj);
+
+  let mut obj = Object::new_sphere(ORIGIN, 1.0, COL_WHITE, 0.4, 0.0, 0.2);
+  obj.set_diffuse(0.7);
+  obj.set_transform(
+    Transform::scale(&Vector::new(0.5, 1.4, 0.5))
+      .then(&Transform::rotate_z(-35.0))
+      .then(&Transform::translate(&Vector::new(1.6, 1.4, -0.5))),
+  );
+  scene.add_object(obj);
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * `n` x `n` instances of one torus mesh, each turned differently, above the
+ * disc.  All the instances share the one mesh.
+ */
+pub fn instances(n: usize) -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let torus = Object::new_mesh(Arc::new(Mesh::torus(1.0, 0.35, 24, 12)), ORIGIN, COL_WHITE, 0.4, 0.0, 0.2);
+  let spacing = 8.0 / n as f64;
+  let size = spacing * 0.3;
+  for i in 0..n {
+    for j in 0..n {
+      let k = (i * n + j) as f64;
+      let transform = Transform::scale(&Vector::new(size, size, size))
+        .then(&Transform::rotate_x(40.0 + 17.0 * k))
+        .then(&Transform::rotate_y(23.0 * k))
+        .then(&Transform::translate(&Vector::new(
+          -4.0 + spacing * (i as f64 + 0.5),
+          size * 1.4 + 0.2,
+          -4.0 + spacing * (j as f64 + 0.5),
+        )));
+      let mut obj = torus.instance(transform);
+      obj.col = if (i + j) % 2 == 0 { COL_RED } else { COL_WHITE };
+      obj.set_diffuse(0.6);
+      scene.add_object(obj);
+    }
+  }
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * Solids made with CSG: a glass lens, a bowl and a ball with a bite out of it.
+ */
+pub fn csg() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let sphere = |c: Vector, radius: f64, col: Colour| Object::new_sphere(c, radius, col, 0.0, 0.0, 0.2);
+
+  // Lens, where two spheres overlap
+  let mut lens = Object::new_csg(
+    CsgOp::Intersection,
+    sphere(Vector::new(0.0, 0.0, -1.6), 2.0, COL_WHITE),
+    sphere(Vector::new(0.0, 0.0, 1.6), 2.0, COL_WHITE),
+    Vector::new(1.8, 1.6, -1.5),
+  );
+  lens.col = COL_WHITE;
+  lens.rfl = 0.3;
+  lens.rfr = 1.12;
+  lens.ambient_light = 0.05;
+  lens.set_diffuse(0.0);
+  lens.set_transform(Transform::rotate_y(-30.0));
+  scene.add_object(lens);
+
+  // Bowl, a shell with its top cut off
+  let shell = Object::new_csg(
+    CsgOp::Difference,
+    sphere(ORIGIN, 1.2, COL_WHITE),
+    sphere(ORIGIN, 1.05, COL_RED),
+    ORIGIN,
+  );
+  let mut bowl = Object::new_csg(
+    CsgOp::Difference,
+    shell,
+    sphere(Vector::new(0.0, 2.2, 0.0), 2.0, COL_WHITE),
+    Vector::new(-1.8, 1.2, 0.0),
+  );
+  bowl.col = COL_WHITE;
+  bowl.rfl = 0.2;
+  bowl.set_diffuse(0.7);
+  scene.add_object(bowl);
+
+  // Ball with a bite taken out
+  let mut bitten = Object::new_csg(
+    CsgOp::Difference,
+    sphere(ORIGIN, 0.9, COL_RED),
+    sphere(Vector::new(-0.6, 0.5, -0.6), 0.7, COL_WHITE),
+    Vector::new(0.3, 0.9, 1.5),
+  );
+  bitten.col = COL_RED;
+  bitten.rfl = 0.5;
+  bitten.set_diffuse(0.5);
+  scene.add_object(bitten);
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * Implicit surfaces: metaballs, a rounded box and a Mandelbulb.
+ */
+pub fn sdf() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let metaballs = Sdf::Metaballs {
+    balls: vec![
+      (Vector::new(-0.5, 0.0, 0.0), 0.6),
+      (Vector::new(0.5, 0.2, 0.0), 0.5),
+      (Vector::new(0.0, 0.7, 0.3), 0.4),
+    ],
+    smoothness: 0.5,
+  };
+  let mut blob = Object::new_sdf(metaballs, Vector::new(-1.7, 1.0, 0.5), COL_BLUE, 0.3, 0.0, 0.2);
+  blob.set_diffuse(0.6);
+  scene.add_object(blob);
+
+  let round_box = Sdf::RoundBox {
+    half: Vector::new(0.5, 0.5, 0.5),
+    radius: 0.2,
+  };
+  let mut cube = Object::new_sdf(round_box, Vector::new(0.0, 0.7, 1.2), COL_RED, 0.4, 0.0, 0.2);
+  cube.set_diffuse(0.7);
+  cube.set_transform(Transform::rotate_y(30.0));
+  scene.add_object(cube);
+
+  let mandelbulb = Sdf::Mandelbulb {
+    power: 8.0,
+    iterations: 8,
+  };
+  let mut bulb = Object::new_sdf(mandelbulb, Vector::new(0.0, 0.0, 0.0), COL_SILVER, 0.0, 0.0, 0.3);
+  bulb.set_diffuse(0.8);
+  bulb.set_transform(Transform::scale(&Vector::new(0.9, 0.9, 0.9)).then(&Transform::translate(&Vector::new(1.6, 1.2, -1.0))));
+  scene.add_object(bulb);
+
+  add_light(&mut scene);
+  scene
+}
+
+/**
+ * The default scene in fog, lit from behind so the spheres cast shafts of
+ * shadow towards the eye, with a bank of thicker blue mist to one side.
+ */
+pub fn fog() -> Scene {
+  let mut scene = default_scene();
+  scene.lights[0].c = Vector::new(0.0, 3.0, 12.0);
+  scene.fog = Some(Medium::new(0.002, 0.02, 0.7));
+  scene.add_volume(Volume {
+    medium: Medium {
+      absorption: Vector::new(0.3, 0.2, 0.05),
+      scattering: Vector::new(0.1, 0.2, 0.4),
+      g: 0.0,
+    },
+    c: Vector::new(-2.2, 0.6, -2.5),
+    radius: 1.2,
+  });
+  scene
+}
+
+/**
+ * A dim scene lit mostly by two glowing spheres and a panel of light.
+ */
+pub fn emissive() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+
+  let mut glow = Object::new_sphere(Vector::new(-1.6, 0.5, -0.5), 0.5, COL_WHITE, 0.0, 0.0, 0.2);
+  glow.set_emission(Vector::new(1.0, 0.5, 0.1), 2.0);
+  glow.can_create_shadow = false;
+  scene.add_object(glow);
+
+  let mut glow = Object::new_sphere(Vector::new(1.8, 0.4, -1.0), 0.4, COL_WHITE, 0.0, 0.0, 0.2);
+  glow.set_emission(Vector::new(0.2, 0.5, 1.0), 3.0);
+  glow.can_create_shadow = false;
+  scene.add_object(glow);
+
+  // A panel overhead, facing down
+  let panel = Mesh::new(
+    vec![
+      Vector::new(-1.0, 0.0, -1.0),
+      Vector::new(1.0, 0.0, -1.0),
+      Vector::new(1.0, 0.0, 1.0),
+      Vector::new(-1.0, 0.0, 1.0),
+    ],
+    vec![],
+    vec![[0, 1, 2], [0, 2, 3]],
+  );
+  let mut panel = Object::new_mesh(Arc::new(panel), Vector::new(0.0, 3.5, -2.0), COL_WHITE, 0.0, 0.0, 0.2);
+  panel.set_emission(COL_WHITE, 1.5);
+  panel.can_create_shadow = false;
+  scene.add_object(panel);
+
+  let mut ball = Object::new_sphere(Vector::new(0.0, 1.0, 0.0), 1.0, COL_WHITE, 0.3, 0.0, 0.05);
+  ball.set_diffuse(0.8);
+  ball.can_receive_shadow = true;
+  scene.add_object(ball);
+
+  scene.add_light(Light::point(Vector::new(5.0, 7.5, -2.0), Vector::new(0.15, 0.15, 0.15)));
+  scene
+}
+
+/**
+ * Spheres on the disc under a low evening sun and two coloured spotlights.
+ */
+pub fn spotlights() -> Scene {
+  let mut scene = empty_scene();
+  add_disc(&mut scene);
+  // Dim the disc so the pools of light show
+  scene.objs[0].set_diffuse(0.9);
+  scene.objs[0].ambient_light = 0.15;
+  scene.objs[0].rfl = 0.3;
+
+  for (i, col) in [COL_WHITE, COL_RED, COL_BLUE].iter().enumerate() {
+    let mut obj = Object::new_sphere(Vector::new(-2.0 + 2.0 * i as f64, 0.7, 0.0), 0.7, *col, 0.2, 0.0, 0.1);
+    obj.set_diffuse(0.8);
+    scene.add_object(obj);
+  }
+
+  scene.add_light(Light::directional(
+    Vector::new(-1.0, -0.5, 0.5),
+    Vector::new(0.3, 0.25, 0.2),
+  ));
+  scene.add_light(Light::spot(
+    Vector::new(-2.0, 6.0, -3.0),
+    Vector::new(0.3, -1.0, 0.5),
+    12.0,
+    20.0,
+    Vector::new(1.0, 0.9, 0.6),
+  ));
+  scene.add_light(Light::spot(
+    Vector::new(3.0, 5.0, -2.0),
+    Vector::new(-0.3, -1.0, 0.4),
+    8.0,
+    15.0,
+    Vector::new(0.5, 0.6, 1.0),
+  ));
+  scene
+}
+
+/**
+ * Look up a canonical scene by name.
+ */
+pub fn by_name(name: &str) -> Option<Scene> {
+  match name {
+    "default" => Some(default_scene()),
+    "many_spheres" => Some(many_spheres(10)),
+    "bouncing_balls" => Some(bouncing_balls()),
+    "transformed" => Some(transformed()),
+    "instances" => Some(instances(6)),
+    "csg" => Some(csg()),
+    "sdf" => Some(sdf()),
+    "fog" => Some(fog()),
+    "emissive" => Some(emissive()),
+    "spotlights" => Some(spotlights()),
+    _ => None,
+  }
+}
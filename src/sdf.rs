@@ -0,0 +1,262 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Implicit surfaces given by a signed distance function: the distance to the
+// surface, negative inside.  They are rendered by sphere tracing, stepping
+// along the ray by the distance to the surface, which can never overshoot.
+// Normals come from the gradient of the distance, by finite differences.
+
+use std::f64;
+
+use crate::objects::{finite, Hit, Ray};
+use crate::vector::Vector;
+use crate::vector::Vector as Colour;
+
+// Close enough to count as on the surface
+const HIT_DIST: f64 = 0.0001;
+const MAX_STEPS: usize = 256;
+// Step for the finite differences of the normal
+const NORMAL_DELTA: f64 = 0.00001;
+// Beyond these the Mandelbulb isn't inside its bound or takes too long
+const MAX_MANDELBULB_POWER: f64 = 16.0;
+const MAX_MANDELBULB_ITERATIONS: usize = 64;
+
+#[derive(Clone)]
+pub enum Sdf {
+  Sphere {
+    radius: f64,
+  },
+  // A box with its edges rounded off by `radius`, `half` is half its size.
+  RoundBox {
+    half: Vector,
+    radius: f64,
+  },
+  // Spheres (centre, radius) blended together, `smoothness` is how far the
+  // blend reaches.
+  Metaballs {
+    balls: Vec<(Vector, f64)>,
+    smoothness: f64,
+  },
+  // The Mandelbulb fractal, about 1.2 across.  Its distance is an estimate,
+  // good enough for sphere tracing.
+  Mandelbulb {
+    power: f64,
+    iterations: usize,
+  },
+}
+
+impl Sdf {
+  /**
+   * The signed distance from `p`, in object space, to the surface.
+   */
+  pub fn distance(&self, p: &Vector) -> f64 {
+    match self {
+      Sdf::Sphere { radius } => p.length() - radius,
+      Sdf::RoundBox { half, radius } => {
+        let q = Vector::new(p.x.abs() - half.x, p.y.abs() - half.y, p.z.abs() - half.z);
+        let outside = Vector::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+        let inside = q.x.max(q.y).max(q.z).min(0.0);
+        outside + inside - radius
+      }
+      Sdf::Metaballs { balls, smoothness } => balls
+        .iter()
+        .map(|(c, r)| p.sub(c).length() - r)
+        .fold(f64::MAX, |a, b| smooth_min(a, b, *smoothness)),
+      Sdf::Mandelbulb { power, iterations } => mandelbulb(p, *power, *iterations),
+    }
+  }
+
+  /**
+   * The radius of a sphere about the origin holding the whole surface.
+   */
+  pub fn bound(&self) -> f64 {
+    match self {
+      Sdf::Sphere { radius } => *radius,
+      Sdf::RoundBox { half, radius } => half.length() + radius,
+      Sdf::Metaballs { balls, smoothness } => {
+        balls.iter().map(|(c, r)| c.length() + r).fold(0.0, f64::max) + smoothness
+      }
+      Sdf::Mandelbulb { .. } => 1.5,
+    }
+  }
+
+  /**
+   * Ok if the surface can be sphere traced: sizes positive (or zero for the
+   * rounding) and finite, and not too many iterations.
+   */
+  pub fn validate(&self) -> Result<(), String> {
+    let size = |x: f64| x >= 0.0 && x.is_finite();
+    let ok = match self {
+      Sdf::Sphere { radius } => *radius > 0.0 && radius.is_finite(),
+      Sdf::RoundBox { half, radius } => size(half.x) && size(half.y) && size(half.z) && size(*radius),
+      Sdf::Metaballs { balls, smoothness } => {
+        !balls.is_empty() && size(*smoothness) && balls.iter().all(|(c, r)| finite(c) && *r > 0.0 && r.is_finite())
+      }
+      Sdf::Mandelbulb { power, iterations } => {
+        *power >= 1.0 && *power <= MAX_MANDELBULB_POWER && *iterations <= MAX_MANDELBULB_ITERATIONS
+      }
+    };
+    if ok {
+      Ok(())
+    } else {
+      Err("the sdf needs finite, positive sizes".to_string())
+    }
+  }
+
+  // The outward normal at `p`, from central differences.
+  pub fn normal(&self, p: &Vector) -> Vector {
+    let dx = Vector::new(NORMAL_DELTA, 0.0, 0.0);
+    let dy = Vector::new(0.0, NORMAL_DELTA, 0.0);
+    let dz = Vector::new(0.0, 0.0, NORMAL_DELTA);
+    Vector::new(
+      self.distance(&p.add(&dx)) - self.distance(&p.sub(&dx)),
+      self.distance(&p.add(&dy)) - self.distance(&p.sub(&dy)),
+      self.distance(&p.add(&dz)) - self.distance(&p.sub(&dz)),
+    )
+    .normalise()
+  }
+}
+
+// Polynomial smooth minimum, blending where `a` and `b` are within `k`.
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+  if k <= 0.0 {
+    return a.min(b);
+  }
+  let h = (k - (a - b).abs()).max(0.0) / k;
+  a.min(b) - h * h * k * 0.25
+}
+
+fn mandelbulb(p: &Vector, power: f64, iterations: usize) -> f64 {
+  let mut z = *p;
+  let mut dr = 1.0;
+  let mut r = 0.0;
+  for _ in 0..iterations {
+    r = z.length();
+    if r > 2.0 {
+      break;
+    }
+    let theta = (z.z / r).acos() * power;
+    let phi = z.y.atan2(z.x) * power;
+    dr = r.powf(power - 1.0) * power * dr + 1.0;
+    let zr = r.powf(power);
+    z = Vector::new(
+      theta.sin() * phi.cos(),
+      phi.sin() * theta.sin(),
+      theta.cos(),
+    )
+    .scale(zr)
+    .add(p);
+  }
+  0.5 * r.ln() * r / dr
+}
+
+/**
+ * Sphere trace `sdf`, centred on `sdf_c`.  A ray starting inside the surface
+ * finds its way out.  The ray is assumed to start clear of the surface, as
+ * the rays leaving a hit do, so a hit right at the start is skipped.
+ */
+pub fn intersect_sdf(sdf: &Sdf, sdf_c: &Vector, col: &Colour, ray: &Ray) -> Option<Hit> {
+  let origin = ray.origin.sub(sdf_c);
+  let len = ray.direction.length();
+  let dir = ray.direction.scale(1.0 / len);
+
+  // Only trace the part of the ray inside the bounding sphere
+  let bound = sdf.bound();
+  let b = origin.dot(&dir);
+  let c = origin.dot(&origin) - bound * bound;
+  let disc = b * b - c;
+  if disc < 0.0 {
+    return None;
+  }
+  let far = -b + disc.sqrt();
+  if far <= 0.0 {
+    return None;
+  }
+  let mut dist = (-b - disc.sqrt()).max(0.0);
+
+  // Coming from outside the bounding sphere we must be outside the surface,
+  // otherwise the ray may be leaving it, or starting inside
+  let (sign, mut left_surface) = if dist > 0.0 {
+    (1.0, true)
+  } else if sdf.distance(&origin) < 0.0 {
+    (-1.0, false)
+  } else {
+    (1.0, false)
+  };
+  for _ in 0..MAX_STEPS {
+    let p = origin.add(&dir.scale(dist));
+    let d = sign * sdf.distance(&p);
+    if d < HIT_DIST {
+      if left_surface {
+        let norm = sdf.normal(&p);
+        return Some(Hit::new(ray, dist / len, norm, norm, *col, 0.0, 0.0).face(ray));
+      }
+      dist += HIT_DIST;
+    } else {
+      left_surface = true;
+      dist += d;
+    }
+    // The surface may touch the bounding sphere
+    if dist > far + HIT_DIST {
+      return None;
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::COL_WHITE;
+  use crate::objects::Ray;
+  use crate::sdf::{intersect_sdf, Sdf};
+  use crate::vector::Vector;
+
+  #[test]
+  fn sdf_matches_analytic_sphere() {
+    let sdf = Sdf::Sphere { radius: 1.0 };
+    let c = Vector::new(0.0, 1.0, 0.0);
+    let ray = Ray::new(Vector::new(-5.0, 1.0, 0.0), Vector::new(2.0, 0.0, 0.0));
+    let hit = intersect_sdf(&sdf, &c, &COL_WHITE, &ray).unwrap();
+    assert!((hit.t - 2.0).abs() < 0.001);
+    assert!(hit.front_face);
+    assert!(hit.norm.sub(&Vector::new(-1.0, 0.0, 0.0)).length() < 0.001);
+
+    // And out again from the inside
+    let inside = Ray::new(Vector::new(0.0, 1.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let hit = intersect_sdf(&sdf, &c, &COL_WHITE, &inside).unwrap();
+    assert!((hit.t - 1.0).abs() < 0.001);
+    assert!(!hit.front_face);
+  }
+
+  #[test]
+  fn sdf_round_box() {
+    let sdf = Sdf::RoundBox {
+      half: Vector::new(1.0, 1.0, 1.0),
+      radius: 0.25,
+    };
+    assert!((sdf.distance(&Vector::new(3.0, 0.0, 0.0)) - 1.75).abs() < 1e-9);
+    assert!(sdf.distance(&Vector::new(0.0, 0.0, 0.0)) < 0.0);
+    let n = sdf.normal(&Vector::new(0.0, 1.25, 0.0));
+    assert!(n.sub(&Vector::new(0.0, 1.0, 0.0)).length() < 0.001);
+  }
+}
@@ -0,0 +1,456 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+//*********************************************************************/
+//**                                                                 **/
+//**      Shared memory renderer (wasm threads)                      **/
+//**                                                                 **/
+//*********************************************************************/
+//
+// One `RayTracer` and one framebuffer live in (shared) wasm memory.  Any
+// number of threads claim strips from an atomic counter and render them
+// straight into the framebuffer, so the scene only needs updating once per
+// frame.  Only built with the `threads` feature.
+//
+// With temporal accumulation, denoising or post-processing on, strips go
+// into an HDR frame instead, which `end_frame()` finishes and puts in the
+// framebuffer once they're all done.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use wasm_bindgen::prelude::*;
+
+use crate::constants::{NUM_COLS, NUM_ROWS, NUM_STRIPS};
+use crate::error::RayTracerError;
+use crate::raytracer::RayTracer;
+use crate::stats::RayStats;
+use crate::temporal::{HdrFrame, HdrStrip, Temporal, STRIP_PIXELS};
+
+/**
+ * Everything that is only changed between frames.  Rendering threads hold a
+ * read lock on it for as long as they render, and every method that changes
+ * the scene or touches the whole framebuffer takes the write lock, so the
+ * scene can't change under a thread and a frame can't be read half written.
+ */
+struct State {
+  rt: RayTracer,
+  hdr: Option<Mutex<HdrFrame>>, // Rendered into when this or `RayTracer::filters_frames()` is on
+  temporal: Option<Temporal>,
+}
+
+/**
+ * The RGBA framebuffer.  Each thread writes the strips it claimed from
+ * `next_strip` straight into it, so no two threads ever write the same bytes.
+ */
+struct FrameBuffer(Box<[UnsafeCell<u8>]>);
+
+// Shared between threads, see `FrameBuffer::bytes_mut()`.
+unsafe impl Sync for FrameBuffer {}
+
+impl FrameBuffer {
+  fn new(len: usize) -> Self {
+    FrameBuffer((0..len).map(|_| UnsafeCell::new(0)).collect())
+  }
+
+  #[cfg(target_arch = "wasm32")]
+  fn as_ptr(&self) -> *const u8 {
+    self.0.as_ptr() as *const u8
+  }
+
+  fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  /**
+   * The bytes `start..start + len`.
+   *
+   * Safety: nothing else may read or write these bytes while the slice lives,
+   * i.e. the caller has claimed the strip, or holds the write lock on `State`.
+   */
+  #[allow(clippy::mut_from_ref)]
+  unsafe fn bytes_mut(&self, start: usize, len: usize) -> &mut [u8] {
+    assert!(start + len <= self.0.len());
+    std::slice::from_raw_parts_mut((self.0.as_ptr() as *mut u8).add(start), len)
+  }
+}
+
+/**
+ * The addresses of the renderers handed out by `handle()` that are still
+ * alive.  `shared_render_strips()` holds a read lock on it while it renders,
+ * and dropping a renderer takes the write lock to take it out, so a renderer
+ * can't go away under a thread.
+ */
+static HANDLES: RwLock<Vec<usize>> = RwLock::new(Vec::new());
+
+#[wasm_bindgen]
+pub struct SharedRayTracer {
+  state: RwLock<State>,
+  frame: FrameBuffer,
+  next_strip: AtomicU32,
+  handle: AtomicUsize, // The address in `HANDLES`, 0 until there is one
+}
+
+#[wasm_bindgen]
+impl SharedRayTracer {
+  #[wasm_bindgen(constructor)]
+  pub fn new(depth: i32) -> Self {
+    SharedRayTracer::from_ray_tracer(RayTracer::new(depth))
+  }
+
+  // See `RayTracer::with_scene_name()`.
+  pub fn with_scene_name(depth: i32, name: &str) -> Result<SharedRayTracer, RayTracerError> {
+    RayTracer::with_scene_name(depth, name).map(SharedRayTracer::from_ray_tracer)
+  }
+
+  /**
+   * The address of this renderer in wasm memory.  Pass it to other threads so
+   * they can call `shared_render_strips`.
+   */
+  #[cfg(target_arch = "wasm32")]
+  pub fn handle(&self) -> u32 {
+    self.register() as u32
+  }
+
+  /**
+   * The address of the RGBA framebuffer in wasm memory.
+   */
+  #[cfg(target_arch = "wasm32")]
+  pub fn frame_ptr(&self) -> u32 {
+    self.frame.as_ptr() as u32
+  }
+
+  // Length of the framebuffer in bytes.
+  pub fn frame_len(&self) -> u32 {
+    self.frame.len() as u32
+  }
+
+  /**
+   * Update the scene.  This waits for any thread still rendering, so call it
+   * between frames.
+   */
+  pub fn set_time(&self, t: f64) {
+    self.write().rt.set_time(t);
+  }
+
+  // See `RayTracer::catch_up_to()`, like `set_time` call this between frames.
+  pub fn catch_up_to(&self, t: f64) -> bool {
+    self.write().rt.catch_up_to(t)
+  }
+
+  // Like `set_time`, call this between frames.
+  pub fn increment(&self, angle: f64) {
+    self.write().rt.increment(angle);
+  }
+
+  // The ray counts from all threads since the last call.
+  pub fn take_stats(&self) -> RayStats {
+    self.read().rt.take_stats()
+  }
+
+  /**
+   * Turn temporal anti-aliasing and accumulation on or off, see
+   * `temporal.rs`.  It starts off.
+   */
+  pub fn set_temporal(&self, on: bool) {
+    let mut state = self.write();
+    if !on {
+      state.temporal = None;
+    } else if state.temporal.is_none() {
+      state.temporal = Some(Temporal::new());
+    }
+    state.rt.set_jitter(on);
+    state.update_hdr();
+  }
+
+  /**
+   * Turn the edge-aware denoiser on or off, see `denoise.rs`.  It runs after
+   * temporal accumulation, if that's on, and starts off.
+   */
+  pub fn set_denoise(&self, on: bool) {
+    let mut state = self.write();
+    state.rt.set_denoise(on);
+    state.update_hdr();
+  }
+
+  // See `RayTracer::set_post_process()`, this runs after the denoiser.
+  pub fn set_post_process(&self, text: &str) -> Result<(), RayTracerError> {
+    let mut state = self.write();
+    state.rt.set_post_process(text)?;
+    state.update_hdr();
+    Ok(())
+  }
+
+  /**
+   * Start a frame, after `set_time()`, and make all the strips available to
+   * be claimed again.
+   */
+  pub fn begin_frame(&self) {
+    let mut state = self.write();
+    state.rt.begin_frame();
+    self.next_strip.store(0, Ordering::SeqCst);
+  }
+
+  /**
+   * Finish a frame once every strip is rendered.  With temporal accumulation,
+   * denoising or post-processing on this puts the frame in the framebuffer,
+   * otherwise there's nothing to do.
+   */
+  pub fn end_frame(&self) {
+    let mut state = self.write();
+    let State { rt, hdr, temporal } = &mut *state;
+    if let Some(hdr) = hdr {
+      let hdr = hdr.get_mut().unwrap_or_else(PoisonError::into_inner);
+      if let Some(temporal) = temporal {
+        temporal.resolve(hdr);
+      }
+      rt.filter_frame(hdr);
+      // The write lock keeps every other thread out of the framebuffer
+      hdr.to_rgba(unsafe { self.frame.bytes_mut(0, self.frame.len()) });
+    }
+  }
+
+  /**
+   * Render strips on this thread until none are left for this frame.
+   * Returns the number of strips rendered.
+   */
+  pub fn render_strips(&self) -> u32 {
+    let state = self.read();
+    let mut count = 0;
+    while self.render_next_strip(&state) {
+      count += 1;
+    }
+    count
+  }
+}
+
+impl State {
+  // Only keep an HDR frame while something needs it.
+  fn update_hdr(&mut self) {
+    if self.temporal.is_none() && !self.rt.filters_frames() {
+      self.hdr = None;
+    } else if self.hdr.is_none() {
+      self.hdr = Some(Mutex::new(HdrFrame::new()));
+    }
+  }
+}
+
+impl SharedRayTracer {
+  pub fn from_ray_tracer(rt: RayTracer) -> Self {
+    let mut state = State {
+      rt,
+      hdr: None,
+      temporal: None,
+    };
+    // The scene may ask for post-processing
+    state.update_hdr();
+    SharedRayTracer {
+      state: RwLock::new(state),
+      frame: FrameBuffer::new(NUM_COLS * NUM_ROWS * 4),
+      next_strip: AtomicU32::new(NUM_STRIPS as u32),
+      handle: AtomicUsize::new(0),
+    }
+  }
+
+  /**
+   * Add this renderer to `HANDLES` and return its address.  It mustn't move
+   * after this, which it can't once wasm_bindgen has handed it to JavaScript.
+   */
+  #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+  fn register(&self) -> usize {
+    let address = self as *const SharedRayTracer as usize;
+    let mut handles = HANDLES.write().unwrap_or_else(PoisonError::into_inner);
+    if self.handle.swap(address, Ordering::SeqCst) == 0 {
+      handles.push(address);
+    }
+    address
+  }
+
+  // A panic on another thread doesn't leave the state half changed, so carry on.
+  fn read(&self) -> RwLockReadGuard<'_, State> {
+    self.state.read().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn write(&self) -> RwLockWriteGuard<'_, State> {
+    self.state.write().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /**
+   * Claim the next free strip and render it.  Returns false once there is no
+   * work left for this frame.
+   */
+  fn render_next_strip(&self, state: &State) -> bool {
+    let strip_id = self.next_strip.fetch_add(1, Ordering::SeqCst);
+    if strip_id as usize >= NUM_STRIPS {
+      return false;
+    }
+
+    if let Some(hdr) = &state.hdr {
+      let mut strip = HdrStrip::new();
+      // The strip is in range, so this can't fail
+      state.rt.render_hdr(strip_id, &mut strip).unwrap();
+      hdr
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_strip(strip_id as usize, &strip);
+      return true;
+    }
+
+    // Only this thread claimed the strip, so only it writes these bytes
+    let strip_data = unsafe { self.frame.bytes_mut(strip_id as usize * STRIP_PIXELS * 4, STRIP_PIXELS * 4) };
+    // The strip is in range and the buffer is the right size, so this can't fail
+    state.rt.render(strip_id, strip_data).unwrap();
+    true
+  }
+
+  // Copy the framebuffer out as RGBA bytes.
+  pub fn frame(&self) -> Vec<u8> {
+    let _state = self.write();
+    unsafe { self.frame.bytes_mut(0, self.frame.len()) }.to_vec()
+  }
+
+  /**
+   * Render a whole frame using `num_threads` native threads.
+   */
+  #[cfg(not(target_arch = "wasm32"))]
+  pub fn render_frame(&self, num_threads: usize) {
+    self.begin_frame();
+    std::thread::scope(|s| {
+      for _ in 0..num_threads.max(1) {
+        s.spawn(|| self.render_strips());
+      }
+    });
+    self.end_frame();
+  }
+}
+
+impl Drop for SharedRayTracer {
+  // Waits for any thread still rendering through the handle.
+  fn drop(&mut self) {
+    let address = *self.handle.get_mut();
+    if address != 0 {
+      let mut handles = HANDLES.write().unwrap_or_else(PoisonError::into_inner);
+      handles.retain(|&h| h != address);
+    }
+  }
+}
+
+/**
+ * Render strips on the calling thread until none are left for this frame.
+ * `handle` comes from `SharedRayTracer::handle()`, anything that isn't the
+ * handle of a live renderer is an error.  This bypasses the wasm_bindgen
+ * borrow checking, which isn't thread safe, so many workers can render at
+ * once.  That's sound because every method only borrows the renderer
+ * shared, the lock in it keeps the scene from changing while a thread
+ * renders, and `HANDLES` keeps it alive.  Returns the number of strips
+ * rendered.
+ */
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn shared_render_strips(handle: u32) -> Result<u32, RayTracerError> {
+  render_strips_by_handle(handle as usize)
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+fn render_strips_by_handle(handle: usize) -> Result<u32, RayTracerError> {
+  let handles = HANDLES.read().unwrap_or_else(PoisonError::into_inner);
+  if handle == 0 || !handles.contains(&handle) {
+    return Err(RayTracerError::NoSuchRenderer(handle as u32));
+  }
+  // It's live, and can't be dropped until `handles` is released
+  let srt = unsafe { &*(handle as *const SharedRayTracer) };
+  Ok(srt.render_strips())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::{NUM_COLS, NUM_ROWS, NUM_STRIPS, SQUARE_SIZE};
+  use crate::error::RayTracerError;
+  use crate::raytracer::RayTracer;
+  use crate::shared::{render_strips_by_handle, SharedRayTracer};
+  use crate::temporal::{HdrFrame, Temporal};
+
+  #[test]
+  fn shared_frame_matches_strips() {
+    let mut rt = RayTracer::new(3);
+    let srt = SharedRayTracer::new(3);
+    rt.increment(25.0);
+    srt.increment(25.0);
+    srt.render_frame(4);
+
+    let frame = srt.frame();
+    let strip_len = NUM_COLS * SQUARE_SIZE * 4;
+    let mut strip_data = vec![0; strip_len];
+    for strip_id in 0..NUM_STRIPS {
+      rt.render(strip_id as u32, &mut strip_data).unwrap();
+      let start = strip_id * strip_len;
+      assert!(frame[start..start + strip_len] == strip_data[..]);
+    }
+  }
+
+  #[test]
+  fn shared_handles_are_checked() {
+    let srt = SharedRayTracer::new(1);
+    let handle = srt.register();
+    assert_eq!(srt.register(), handle);
+    srt.begin_frame();
+    assert_eq!(render_strips_by_handle(handle), Ok(NUM_STRIPS as u32));
+    assert_eq!(render_strips_by_handle(handle + 8), Err(RayTracerError::NoSuchRenderer((handle + 8) as u32)));
+    assert!(render_strips_by_handle(0).is_err());
+    drop(srt);
+    assert!(render_strips_by_handle(handle).is_err());
+  }
+
+  #[test]
+  fn shared_temporal_matches_one_thread() {
+    let mut rt = RayTracer::new(3);
+    rt.set_jitter(true);
+    let srt = SharedRayTracer::new(3);
+    srt.set_temporal(true);
+    let mut temporal = Temporal::new();
+    let mut hdr = HdrFrame::new();
+    let mut expected = vec![0; NUM_COLS * NUM_ROWS * 4];
+    for &t in [1.0, 1.2].iter() {
+      rt.set_time(t);
+      rt.begin_frame();
+      rt.render_hdr_frame(&mut hdr);
+      temporal.resolve(&mut hdr);
+      hdr.to_rgba(&mut expected);
+
+      srt.set_time(t);
+      srt.render_frame(4);
+      assert!(srt.frame() == expected);
+    }
+  }
+
+  #[test]
+  fn shared_post_process_without_temporal_is_steady() {
+    let srt = SharedRayTracer::new(3);
+    srt.set_post_process("vignette 0.3").unwrap();
+    srt.render_frame(4);
+    let first = srt.frame();
+    // Nothing is jittered, so the next frame of a still scene is the same
+    srt.render_frame(4);
+    assert!(srt.frame() == first);
+  }
+}
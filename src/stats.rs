@@ -0,0 +1,200 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Ray and intersection counters.  Only counted when built with the `stats`
+// feature, otherwise every call here compiles down to nothing.
+
+use std::fmt;
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use wasm_bindgen::prelude::*;
+
+#[derive(Clone, Copy)]
+pub enum Counter {
+  PrimaryRays,
+  ReflectionRays,
+  RefractionRays,
+  ShadowRays,
+  IntersectCalls,
+}
+
+const NUM_COUNTERS: usize = 5;
+
+// A snapshot of the counters.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct RayStats {
+  pub primary_rays: u64,
+  pub reflection_rays: u64,
+  pub refraction_rays: u64,
+  pub shadow_rays: u64,
+  pub intersect_calls: u64,
+}
+
+impl RayStats {
+  pub fn total_rays(&self) -> u64 {
+    self.primary_rays + self.reflection_rays + self.refraction_rays + self.shadow_rays
+  }
+
+  pub fn add_in_place(&mut self, w: &RayStats) {
+    self.primary_rays += w.primary_rays;
+    self.reflection_rays += w.reflection_rays;
+    self.refraction_rays += w.refraction_rays;
+    self.shadow_rays += w.shadow_rays;
+    self.intersect_calls += w.intersect_calls;
+  }
+}
+
+impl fmt::Display for RayStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "primary: {}, reflection: {}, refraction: {}, shadow: {}, total: {}, intersect calls: {}",
+      self.primary_rays,
+      self.reflection_rays,
+      self.refraction_rays,
+      self.shadow_rays,
+      self.total_rays(),
+      self.intersect_calls
+    )
+  }
+}
+
+impl RayStats {
+  pub fn to_json(&self) -> String {
+    format!(
+      "{{ \"primary_rays\": {}, \"reflection_rays\": {}, \"refraction_rays\": {}, \"shadow_rays\": {}, \"intersect_calls\": {} }}",
+      self.primary_rays, self.reflection_rays, self.refraction_rays, self.shadow_rays, self.intersect_calls
+    )
+  }
+}
+
+// The live counters, safe to share between threads.
+#[derive(Default)]
+pub struct Stats {
+  #[cfg(feature = "stats")]
+  counters: [AtomicU64; NUM_COUNTERS],
+}
+
+impl Stats {
+  #[inline(always)]
+  pub fn add(&self, _counter: Counter, _n: u64) {
+    #[cfg(feature = "stats")]
+    self.counters[_counter as usize].fetch_add(_n, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> RayStats {
+    to_ray_stats(self.values())
+  }
+
+  /**
+   * Return the counters and set them back to zero.  Each counter is swapped
+   * out in one step, so counts other threads add meanwhile go to the next
+   * call rather than being lost.
+   */
+  pub fn take(&self) -> RayStats {
+    to_ray_stats(self.take_values())
+  }
+
+  pub fn reset(&self) {
+    #[cfg(feature = "stats")]
+    for c in &self.counters {
+      c.store(0, Ordering::Relaxed);
+    }
+  }
+
+  #[cfg(feature = "stats")]
+  fn values(&self) -> [u64; NUM_COUNTERS] {
+    let mut values = [0; NUM_COUNTERS];
+    for (v, c) in values.iter_mut().zip(&self.counters) {
+      *v = c.load(Ordering::Relaxed);
+    }
+    values
+  }
+
+  #[cfg(feature = "stats")]
+  fn take_values(&self) -> [u64; NUM_COUNTERS] {
+    let mut values = [0; NUM_COUNTERS];
+    for (v, c) in values.iter_mut().zip(&self.counters) {
+      *v = c.swap(0, Ordering::Relaxed);
+    }
+    values
+  }
+
+  #[cfg(not(feature = "stats"))]
+  fn values(&self) -> [u64; NUM_COUNTERS] {
+    [0; NUM_COUNTERS]
+  }
+
+  #[cfg(not(feature = "stats"))]
+  fn take_values(&self) -> [u64; NUM_COUNTERS] {
+    [0; NUM_COUNTERS]
+  }
+}
+
+fn to_ray_stats(c: [u64; NUM_COUNTERS]) -> RayStats {
+  RayStats {
+    primary_rays: c[Counter::PrimaryRays as usize],
+    reflection_rays: c[Counter::ReflectionRays as usize],
+    refraction_rays: c[Counter::RefractionRays as usize],
+    shadow_rays: c[Counter::ShadowRays as usize],
+    intersect_calls: c[Counter::IntersectCalls as usize],
+  }
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+  use crate::stats::{Counter, Stats};
+
+  #[test]
+  fn stats_take_resets() {
+    let stats = Stats::default();
+    stats.add(Counter::PrimaryRays, 3);
+    stats.add(Counter::ShadowRays, 1);
+    let taken = stats.take();
+    assert_eq!(taken.primary_rays, 3);
+    assert_eq!(taken.shadow_rays, 1);
+    assert_eq!(taken.total_rays(), 4);
+    assert_eq!(stats.snapshot().total_rays(), 0);
+  }
+
+  #[test]
+  fn stats_take_loses_nothing() {
+    let stats = Stats::default();
+    let mut total = 0;
+    std::thread::scope(|s| {
+      for _ in 0..4 {
+        s.spawn(|| {
+          for _ in 0..10000 {
+            stats.add(Counter::PrimaryRays, 1);
+          }
+        });
+      }
+      for _ in 0..100 {
+        total += stats.take().primary_rays;
+      }
+    });
+    total += stats.take().primary_rays;
+    assert_eq!(total, 40000);
+  }
+}
@@ -0,0 +1,334 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+//
+// Temporal anti-aliasing and accumulation.
+//
+// Each frame every pixel is traced once, through a point jittered within the
+// pixel that's different every frame.  `Temporal::resolve()` blends the new
+// frame into the history of the ones before: each pixel follows its motion
+// vector to where its surface was last frame, takes the history from there
+// and averages the new sample into it.  A still scene converges on the
+// average of many samples per pixel, and what moves keeps most of its
+// history too.
+//
+// History that can't be right is dropped: from off the image, from another
+// object, or from before an edit.  What's left is clamped to the colours
+// around the pixel in the new frame, so a moving shadow or reflection,
+// which the motion vectors don't follow, doesn't leave a ghost behind.
+//
+
+use crate::constants::{COL_BACKGROUND, NUM_COLS, NUM_ROWS, OBJECT_ID_NONE, SQUARE_SIZE};
+use crate::vector::Vector;
+use crate::vector::Vector as Colour;
+
+// The most frames the history counts as, so it still follows slow changes.
+pub const MAX_HISTORY: f64 = 16.0;
+
+pub const STRIP_PIXELS: usize = NUM_COLS * SQUARE_SIZE;
+
+#[derive(Clone, Copy)]
+pub struct HdrPixel {
+  pub colour: Colour,             // Linear, not clamped to 1.0
+  pub obj_id: usize,              // What the primary ray hit, OBJECT_ID_NONE for nothing
+  pub motion: Option<(f64, f64)>, // Where the pixel was last frame, (col, row)
+  pub albedo: Colour,             // The colour of the surface hit
+  pub normal: Vector,             // Facing the eye, zero for nothing
+  pub depth: f64,                 // Distance to the surface, infinite for nothing
+}
+
+impl Default for HdrPixel {
+  fn default() -> HdrPixel {
+    HdrPixel {
+      colour: COL_BACKGROUND,
+      obj_id: OBJECT_ID_NONE,
+      motion: None,
+      albedo: COL_BACKGROUND,
+      normal: Vector::new(0.0, 0.0, 0.0),
+      depth: f64::INFINITY,
+    }
+  }
+}
+
+// One strip of HDR pixels, by row.
+pub struct HdrStrip {
+  pub pixels: Vec<HdrPixel>,
+}
+
+impl HdrStrip {
+  pub fn new() -> HdrStrip {
+    HdrStrip {
+      pixels: vec![HdrPixel::default(); STRIP_PIXELS],
+    }
+  }
+}
+
+impl Default for HdrStrip {
+  fn default() -> HdrStrip {
+    HdrStrip::new()
+  }
+}
+
+// A whole frame of HDR pixels, by row.
+pub struct HdrFrame {
+  pub pixels: Vec<HdrPixel>,
+}
+
+impl HdrFrame {
+  pub fn new() -> HdrFrame {
+    HdrFrame {
+      pixels: vec![HdrPixel::default(); NUM_COLS * NUM_ROWS],
+    }
+  }
+
+  pub fn set_strip(&mut self, strip_id: usize, strip: &HdrStrip) {
+    let start = strip_id * STRIP_PIXELS;
+    self.pixels[start..start + STRIP_PIXELS].copy_from_slice(&strip.pixels);
+  }
+
+  /**
+   * Write the frame into `buf` as RGBA, clamped to 8 bits the same way
+   * `RayTracer::render()` does.  `buf` needs room for the whole image.
+   */
+  pub fn to_rgba(&self, buf: &mut [u8]) {
+    for (pxl, rgba) in self.pixels.iter().zip(buf.chunks_exact_mut(4)) {
+      let mut colour = pxl.colour.scale(255.0);
+      colour.max_val_in_place(255.0);
+      rgba[0] = colour.x as u8;
+      rgba[1] = colour.y as u8;
+      rgba[2] = colour.z as u8;
+      rgba[3] = 255u8;
+    }
+  }
+}
+
+impl Default for HdrFrame {
+  fn default() -> HdrFrame {
+    HdrFrame::new()
+  }
+}
+
+/**
+ * Where to trace through pixels on frame `frame`, as an offset in pixels
+ * from -0.5 to 0.5.  A Halton sequence, so the samples spread evenly over
+ * the pixel whatever the number of frames.
+ */
+pub fn jitter(frame: u32) -> (f64, f64) {
+  let halton = |mut i: u32, base: u32| {
+    let mut f = 1.0;
+    let mut r = 0.0;
+    while i > 0 {
+      f /= base as f64;
+      r += f * (i % base) as f64;
+      i /= base;
+    }
+    r
+  };
+  // Start at 1, as 0 is the corner of the pixel
+  let i = frame % 64 + 1;
+  (halton(i, 2) - 0.5, halton(i, 3) - 0.5)
+}
+
+// The history of the frames resolved so far.
+pub struct Temporal {
+  history: Vec<Colour>,
+  ids: Vec<usize>,
+  weights: Vec<f64>, // How many frames each pixel's history is worth, 0 for none
+}
+
+impl Temporal {
+  pub fn new() -> Temporal {
+    Temporal {
+      history: vec![COL_BACKGROUND; NUM_COLS * NUM_ROWS],
+      ids: vec![OBJECT_ID_NONE; NUM_COLS * NUM_ROWS],
+      weights: vec![0.0; NUM_COLS * NUM_ROWS],
+    }
+  }
+
+  // Forget the history, the next frame starts afresh.
+  pub fn reset(&mut self) {
+    self.weights.iter_mut().for_each(|w| *w = 0.0);
+  }
+
+  /**
+   * Blend `frame` into the history, and replace its colours with the result.
+   */
+  pub fn resolve(&mut self, frame: &mut HdrFrame) {
+    let current: Vec<Colour> = frame.pixels.iter().map(|pxl| pxl.colour).collect();
+    let mut weights = vec![1.0; NUM_COLS * NUM_ROWS];
+
+    for row in 0..NUM_ROWS {
+      for col in 0..NUM_COLS {
+        let i = row * NUM_COLS + col;
+        let pxl = &mut frame.pixels[i];
+        let previous = pxl.motion.and_then(|(c, r)| self.sample(c, r, pxl.obj_id));
+        if let Some((colour, weight)) = previous {
+          let (lo, hi) = neighbourhood(&current, row, col);
+          let history = Colour::new(
+            colour.x.clamp(lo.x, hi.x),
+            colour.y.clamp(lo.y, hi.y),
+            colour.z.clamp(lo.z, hi.z),
+          );
+          let n = (weight + 1.0).min(MAX_HISTORY);
+          pxl.colour = history.add(&current[i].sub(&history).scale(1.0 / n));
+          weights[i] = n;
+        }
+      }
+    }
+
+    for (i, pxl) in frame.pixels.iter().enumerate() {
+      self.history[i] = pxl.colour;
+      self.ids[i] = pxl.obj_id;
+    }
+    self.weights = weights;
+  }
+
+  /**
+   * The history at [row, col], between pixels, from the pixels around it that
+   * saw object `obj_id`.  Returns the colour and how many frames it's worth,
+   * or None if there's no history for it.
+   */
+  fn sample(&self, col: f64, row: f64, obj_id: usize) -> Option<(Colour, f64)> {
+    if !(col > -1.0 && row > -1.0 && col < NUM_COLS as f64 && row < NUM_ROWS as f64) {
+      return None;
+    }
+    let (col0, row0) = (col.floor(), row.floor());
+    let (fc, fr) = (col - col0, row - row0);
+    let mut colour = Colour::new(0.0, 0.0, 0.0);
+    let mut weight = 0.0;
+    let mut total = 0.0;
+    for (dr, wr) in [(0, 1.0 - fr), (1, fr)] {
+      for (dc, wc) in [(0, 1.0 - fc), (1, fc)] {
+        let (r, c) = (row0 as i64 + dr, col0 as i64 + dc);
+        if r < 0 || c < 0 || r >= NUM_ROWS as i64 || c >= NUM_COLS as i64 {
+          continue;
+        }
+        let i = r as usize * NUM_COLS + c as usize;
+        let w = wr * wc;
+        if w > 0.0 && self.weights[i] > 0.0 && self.ids[i] == obj_id {
+          colour.add_in_place(&self.history[i].scale(w));
+          weight += self.weights[i] * w;
+          total += w;
+        }
+      }
+    }
+    // Too little of it to go on
+    if total < 0.25 {
+      return None;
+    }
+    Some((colour.scale(1.0 / total), weight / total))
+  }
+}
+
+impl Default for Temporal {
+  fn default() -> Temporal {
+    Temporal::new()
+  }
+}
+
+// The lowest and highest of each colour in the 3x3 pixels around [row, col].
+fn neighbourhood(colours: &[Colour], row: usize, col: usize) -> (Colour, Colour) {
+  let mut lo = Colour::new(f64::MAX, f64::MAX, f64::MAX);
+  let mut hi = Colour::new(-f64::MAX, -f64::MAX, -f64::MAX);
+  for r in row.saturating_sub(1)..(row + 2).min(NUM_ROWS) {
+    for c in col.saturating_sub(1)..(col + 2).min(NUM_COLS) {
+      let colour = &colours[r * NUM_COLS + c];
+      lo = Colour::new(lo.x.min(colour.x), lo.y.min(colour.y), lo.z.min(colour.z));
+      hi = Colour::new(hi.x.max(colour.x), hi.y.max(colour.y), hi.z.max(colour.z));
+    }
+  }
+  (lo, hi)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::constants::NUM_COLS;
+  use crate::temporal::{jitter, HdrFrame, Temporal, MAX_HISTORY};
+  use crate::vector::Vector as Colour;
+
+  fn still_frame(colour: Colour, obj_id: usize) -> HdrFrame {
+    let mut frame = HdrFrame::new();
+    for (i, pxl) in frame.pixels.iter_mut().enumerate() {
+      pxl.colour = colour;
+      pxl.obj_id = obj_id;
+      pxl.motion = Some(((i % NUM_COLS) as f64, (i / NUM_COLS) as f64));
+    }
+    frame
+  }
+
+  #[test]
+  fn jitter_spreads_over_the_pixel() {
+    let samples: Vec<(f64, f64)> = (0..16).map(jitter).collect();
+    for (i, &(x, y)) in samples.iter().enumerate() {
+      assert!((-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y));
+      assert!(samples[..i].iter().all(|&(px, py)| px != x || py != y));
+    }
+    let (mx, my) = samples.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    assert!((mx / 16.0f64).abs() < 0.05 && (my / 16.0f64).abs() < 0.05);
+  }
+
+  #[test]
+  fn resolve_accumulates_and_rejects() {
+    let mut temporal = Temporal::new();
+    let grey = Colour::new(0.5, 0.5, 0.5);
+
+    // The first frame has no history
+    let mut frame = still_frame(grey, 1);
+    temporal.resolve(&mut frame);
+    assert!(frame.pixels[1000].colour.equals(&grey));
+
+    // A still pixel averages the frames, whatever the noise
+    let mut frame = still_frame(grey, 1);
+    frame.pixels[1000].colour = Colour::new(1.0, 1.0, 1.0);
+    temporal.resolve(&mut frame);
+    assert!(frame.pixels[1000].colour.equals(&Colour::new(0.75, 0.75, 0.75)));
+    assert!(frame.pixels[1001].colour.equals(&grey));
+
+    // ... up to MAX_HISTORY frames
+    temporal.resolve(&mut still_frame(grey, 1));
+    temporal.weights.iter_mut().for_each(|w| *w = MAX_HISTORY);
+    let mut frame = still_frame(grey, 1);
+    frame.pixels[1000].colour = Colour::new(1.0, 1.0, 1.0);
+    temporal.resolve(&mut frame);
+    assert!((frame.pixels[1000].colour.x - (0.5 + 0.5 / MAX_HISTORY)).abs() < 1e-9);
+
+    // History of another object is thrown away
+    let red = Colour::new(1.0, 0.0, 0.0);
+    let mut frame = still_frame(red, 2);
+    temporal.resolve(&mut frame);
+    assert!(frame.pixels[1000].colour.equals(&red));
+
+    // So is history from off the image, or without a motion vector
+    let mut frame = still_frame(grey, 2);
+    frame.pixels[1000].motion = Some((-5.0, 3.0));
+    frame.pixels[1001].motion = None;
+    temporal.resolve(&mut frame);
+    assert!(frame.pixels[1000].colour.equals(&grey));
+    assert!(frame.pixels[1001].colour.equals(&grey));
+
+    // History out of the range of colours around the pixel is clamped to it,
+    // so the red doesn't linger
+    let mut frame = still_frame(grey, 2);
+    temporal.resolve(&mut frame);
+    assert!(frame.pixels[1003].colour.equals(&grey));
+  }
+}
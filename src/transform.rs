@@ -0,0 +1,310 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+#![allow(clippy::needless_range_loop)]
+
+use std::f64;
+
+use crate::objects::Ray;
+use crate::vector::Vector;
+
+// A 4x4 matrix, row major, acting on column vectors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix4 {
+  pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+  pub fn identity() -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for i in 0..4 {
+      m[i][i] = 1.0;
+    }
+    Matrix4 { m }
+  }
+
+  // Matrix product, self * w
+  pub fn mul(&self, w: &Matrix4) -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for r in 0..4 {
+      for c in 0..4 {
+        m[r][c] = (0..4).map(|k| self.m[r][k] * w.m[k][c]).sum();
+      }
+    }
+    Matrix4 { m }
+  }
+
+  pub fn transpose(&self) -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for r in 0..4 {
+      for c in 0..4 {
+        m[r][c] = self.m[c][r];
+      }
+    }
+    Matrix4 { m }
+  }
+
+  // Transform a point, i.e. w = 1.
+  pub fn transform_point(&self, p: &Vector) -> Vector {
+    let m = &self.m;
+    let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+    let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+    let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+    let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+    if (w - 1.0).abs() > f64::EPSILON && w != 0.0 {
+      Vector::new(x / w, y / w, z / w)
+    } else {
+      Vector::new(x, y, z)
+    }
+  }
+
+  // Transform a direction, i.e. w = 0.  Ignores translation.
+  pub fn transform_vector(&self, v: &Vector) -> Vector {
+    let m = &self.m;
+    Vector::new(
+      m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+      m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+      m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+  }
+
+  // Transform a direction by the transpose, without building it.
+  pub fn transform_vector_transposed(&self, v: &Vector) -> Vector {
+    let m = &self.m;
+    Vector::new(
+      m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+      m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+      m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+    )
+  }
+
+  /**
+   * The inverse, by Gauss-Jordan elimination.  None if the matrix is singular.
+   */
+  pub fn inverse(&self) -> Option<Matrix4> {
+    let mut a = self.m;
+    let mut inv = Matrix4::identity().m;
+    for col in 0..4 {
+      // Partial pivoting
+      let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+      // Not finite counts as singular too
+      if !(a[pivot][col].abs() >= 1e-12 && a[pivot][col].is_finite()) {
+        return None;
+      }
+      a.swap(col, pivot);
+      inv.swap(col, pivot);
+
+      let f = 1.0 / a[col][col];
+      for c in 0..4 {
+        a[col][c] *= f;
+        inv[col][c] *= f;
+      }
+      for r in 0..4 {
+        if r != col {
+          let f = a[r][col];
+          for c in 0..4 {
+            a[r][c] -= f * a[col][c];
+            inv[r][c] -= f * inv[col][c];
+          }
+        }
+      }
+    }
+    Some(Matrix4 { m: inv })
+  }
+}
+
+/**
+ * Places an object in the world: `matrix` takes object space to world
+ * space, `inverse` takes it back.  Build transforms up with `then()`, e.g.
+ * `Transform::scale(...).then(&Transform::rotate_y(...))` scales first.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+  pub matrix: Matrix4,
+  pub inverse: Matrix4,
+}
+
+impl Transform {
+  pub fn identity() -> Transform {
+    Transform {
+      matrix: Matrix4::identity(),
+      inverse: Matrix4::identity(),
+    }
+  }
+
+  // None if the matrix can't be inverted.
+  pub fn from_matrix(matrix: Matrix4) -> Option<Transform> {
+    matrix.inverse().map(|inverse| Transform { matrix, inverse })
+  }
+
+  pub fn translate(v: &Vector) -> Transform {
+    let mut matrix = Matrix4::identity();
+    let mut inverse = Matrix4::identity();
+    matrix.m[0][3] = v.x;
+    matrix.m[1][3] = v.y;
+    matrix.m[2][3] = v.z;
+    inverse.m[0][3] = -v.x;
+    inverse.m[1][3] = -v.y;
+    inverse.m[2][3] = -v.z;
+    Transform { matrix, inverse }
+  }
+
+  // Scale along each axis, none of them may be zero.
+  pub fn scale(v: &Vector) -> Transform {
+    let mut matrix = Matrix4::identity();
+    let mut inverse = Matrix4::identity();
+    matrix.m[0][0] = v.x;
+    matrix.m[1][1] = v.y;
+    matrix.m[2][2] = v.z;
+    inverse.m[0][0] = 1.0 / v.x;
+    inverse.m[1][1] = 1.0 / v.y;
+    inverse.m[2][2] = 1.0 / v.z;
+    Transform { matrix, inverse }
+  }
+
+  // Rotations are in degrees, anticlockwise looking down the axis.
+  pub fn rotate_x(angle: f64) -> Transform {
+    Transform::rotation(1, 2, angle)
+  }
+
+  pub fn rotate_y(angle: f64) -> Transform {
+    Transform::rotation(2, 0, angle)
+  }
+
+  pub fn rotate_z(angle: f64) -> Transform {
+    Transform::rotation(0, 1, angle)
+  }
+
+  // Rotate in the plane of axes `a` and `b`
+  fn rotation(a: usize, b: usize, angle: f64) -> Transform {
+    let angle = angle * f64::consts::PI / 180.0;
+    let (sin_t, cos_t) = angle.sin_cos();
+    let mut matrix = Matrix4::identity();
+    matrix.m[a][a] = cos_t;
+    matrix.m[a][b] = -sin_t;
+    matrix.m[b][a] = sin_t;
+    matrix.m[b][b] = cos_t;
+    // A rotation's inverse is its transpose
+    Transform {
+      matrix,
+      inverse: matrix.transpose(),
+    }
+  }
+
+  // Apply `self` and then `next`.
+  pub fn then(&self, next: &Transform) -> Transform {
+    Transform {
+      matrix: next.matrix.mul(&self.matrix),
+      inverse: self.inverse.mul(&next.inverse),
+    }
+  }
+
+  pub fn point_to_world(&self, p: &Vector) -> Vector {
+    self.matrix.transform_point(p)
+  }
+
+  pub fn point_to_object(&self, p: &Vector) -> Vector {
+    self.inverse.transform_point(p)
+  }
+
+  /**
+   * Take a world ray into object space.  The direction isn't normalised, so
+   * the distance `t` along the ray is the same in both spaces.
+   */
+  pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+    Ray::new(
+      self.inverse.transform_point(&ray.origin),
+      self.inverse.transform_vector(&ray.direction),
+    )
+  }
+
+  // Normals go back to world space with the inverse transpose.
+  pub fn normal_to_world(&self, n: &Vector) -> Vector {
+    self.inverse.transform_vector_transposed(n).normalise()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::objects::Ray;
+  use crate::transform::{Matrix4, Transform};
+  use crate::vector::Vector;
+
+  fn assert_matrix(a: &Matrix4, b: &Matrix4) {
+    for r in 0..4 {
+      for c in 0..4 {
+        assert!((a.m[r][c] - b.m[r][c]).abs() < 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn transform_compose() {
+    let t = Transform::scale(&Vector::new(2.0, 1.0, 1.0))
+      .then(&Transform::rotate_z(90.0))
+      .then(&Transform::translate(&Vector::new(0.0, 0.0, 5.0)));
+    // Scale x by 2, swing onto y, then move along z.
+    let p = t.point_to_world(&Vector::new(1.0, 0.0, 0.0));
+    assert!(p.equals(&Vector::new(0.0, 2.0, 5.0)));
+    assert!(t.point_to_object(&p).equals(&Vector::new(1.0, 0.0, 0.0)));
+    assert_matrix(&t.matrix.mul(&t.inverse), &Matrix4::identity());
+  }
+
+  #[test]
+  fn transform_inverse() {
+    let t = Transform::rotate_x(30.0)
+      .then(&Transform::scale(&Vector::new(1.0, 3.0, 0.5)))
+      .then(&Transform::translate(&Vector::new(1.0, -2.0, 3.0)));
+    assert_matrix(&t.matrix.inverse().unwrap(), &t.inverse);
+
+    let mut singular = Matrix4::identity();
+    singular.m[1][1] = 0.0;
+    assert!(Transform::from_matrix(singular).is_none());
+    singular.m[1][1] = f64::NAN;
+    assert!(Transform::from_matrix(singular).is_none());
+  }
+
+  #[test]
+  fn transform_ray_keeps_t() {
+    let t = Transform::scale(&Vector::new(2.0, 2.0, 2.0));
+    let ray = Ray::new(Vector::new(0.0, 0.0, -10.0), Vector::new(0.0, 0.0, 1.0));
+    let local = t.ray_to_object(&ray);
+    // The world point at t = 4 is the local point at t = 4
+    let world = ray.origin.add(&ray.direction.scale(4.0));
+    let back = t.point_to_world(&local.origin.add(&local.direction.scale(4.0)));
+    assert!(world.equals(&back));
+  }
+
+  #[test]
+  fn transform_normal() {
+    // Squash y, a 45 degree normal should tip towards y.
+    let t = Transform::scale(&Vector::new(1.0, 0.5, 1.0));
+    let n = t.normal_to_world(&Vector::new(1.0, 1.0, 0.0).normalise());
+    assert!(n.y > n.x);
+    assert!((n.length() - 1.0).abs() < 1e-9);
+
+    let t = Transform::rotate_x(30.0).then(&Transform::scale(&Vector::new(1.0, 3.0, 0.5)));
+    let v = Vector::new(0.3, -1.0, 2.0);
+    assert!(t.inverse.transform_vector_transposed(&v).equals(&t.inverse.transpose().transform_vector(&v)));
+  }
+}
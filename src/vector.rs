@@ -57,6 +57,15 @@ impl Vector {
     self.x * w.x + self.y * w.y + self.z * w.z
   }
 
+  // Cross product
+  pub fn cross(&self, w: &Vector) -> Vector {
+    Vector {
+      x: self.y * w.z - self.z * w.y,
+      y: self.z * w.x - self.x * w.z,
+      z: self.x * w.y - self.y * w.x,
+    }
+  }
+
   // Add two vectors
   pub fn add(&self, w: &Vector) -> Vector {
     Vector {
@@ -151,7 +160,7 @@ impl Vector {
 
   // Sum each element together and return the result
   pub fn sum_elements(&self) -> f64 {
-    self.x + self.y + self.z as f64
+    self.x + self.y + self.z
   }
 
   // limit the values in place
@@ -171,6 +180,12 @@ impl Vector {
 }
 
 #[cfg(test)]
+#[allow(
+  clippy::bool_assert_comparison,
+  clippy::identity_op,
+  clippy::legacy_numeric_constants,
+  clippy::neg_multiply
+)]
 mod tests {
   use crate::constants::EPSILON;
   use crate::vector::Vector;
@@ -232,6 +247,23 @@ mod tests {
     assert_f64(d_ans, d);
   }
 
+  #[test]
+  fn vector_cross() {
+    let v1 = Vector {
+      x: 1.0,
+      y: 0.0,
+      z: 0.0,
+    };
+    let v3 = v1.cross(&Vector {
+      x: 0.0,
+      y: 1.0,
+      z: 0.0,
+    });
+    assert_f64(v3.x, 0.0);
+    assert_f64(v3.y, 0.0);
+    assert_f64(v3.z, 1.0);
+  }
+
   #[test]
   fn vector_add() {
     let v1 = Vector {
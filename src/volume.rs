@@ -0,0 +1,164 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+// Participating media: fog filling the whole scene, and bounded volumes.
+// The light scattered towards the eye is found by ray marching, with a shadow
+// ray to the light at each step, so objects cast shafts of shadow through
+// the medium.
+
+use std::f64;
+
+use crate::objects::{finite, Ray};
+use crate::vector::Vector;
+use crate::vector::Vector as Colour;
+
+// How far global fog reaches along a ray that hits nothing
+pub const FOG_FAR: f64 = 40.0;
+// Number of ray marching steps through each stretch of medium
+pub const VOLUME_STEPS: usize = 24;
+
+/**
+ * A homogeneous medium.  Absorption and scattering are per unit distance, `g`
+ * is the Henyey-Greenstein asymmetry: 0 scatters evenly, towards 1 scatters
+ * mostly forwards.
+ */
+#[derive(Clone, Copy)]
+pub struct Medium {
+  pub absorption: Colour,
+  pub scattering: Colour,
+  pub g: f64,
+}
+
+impl Medium {
+  pub fn new(absorption: f64, scattering: f64, g: f64) -> Medium {
+    Medium {
+      absorption: Vector::new(absorption, absorption, absorption),
+      scattering: Vector::new(scattering, scattering, scattering),
+      g,
+    }
+  }
+
+  // How much light is lost per unit distance
+  pub fn extinction(&self) -> Colour {
+    self.absorption.add(&self.scattering)
+  }
+
+  // Ok if the coefficients are non-negative and `g` is strictly between -1 and 1.
+  pub fn validate(&self) -> Result<(), String> {
+    let non_negative = |c: &Colour| finite(c) && c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0;
+    if !non_negative(&self.absorption) || !non_negative(&self.scattering) {
+      return Err("absorption and scattering can't be negative".to_string());
+    }
+    if !(self.g > -1.0 && self.g < 1.0) {
+      return Err(format!("g must be between -1 and 1, not {}", self.g));
+    }
+    Ok(())
+  }
+
+  // The fraction of light that gets through `dist` of the medium
+  pub fn transmittance(&self, dist: f64) -> Colour {
+    let e = self.extinction();
+    Vector::new((-e.x * dist).exp(), (-e.y * dist).exp(), (-e.z * dist).exp())
+  }
+}
+
+/**
+ * A medium held in a sphere.
+ */
+#[derive(Clone, Copy)]
+pub struct Volume {
+  pub medium: Medium,
+  pub c: Vector,
+  pub radius: f64,
+}
+
+impl Volume {
+  /**
+   * The stretch (t_near, t_far) of the ray inside the volume, clipped to
+   * [0, t_max].
+   */
+  pub fn clip(&self, ray: &Ray, t_max: f64) -> Option<(f64, f64)> {
+    let local = ray.origin.sub(&self.c);
+    let a = ray.direction.dot(&ray.direction);
+    let b = ray.direction.dot(&local);
+    let c = local.dot(&local) - self.radius * self.radius;
+    let d = b * b - a * c;
+    if d <= 0.0 {
+      return None;
+    }
+    let sqrt_d = d.sqrt();
+    let t0 = ((-b - sqrt_d) / a).max(0.0);
+    let t1 = ((-b + sqrt_d) / a).min(t_max);
+    if t0 < t1 {
+      Some((t0, t1))
+    } else {
+      None
+    }
+  }
+}
+
+/**
+ * The Henyey-Greenstein phase function, the share of light scattered by an
+ * angle whose cosine is `cos_theta`.
+ */
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+  let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+  (1.0 - g * g) / (4.0 * f64::consts::PI * denom * denom.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::objects::Ray;
+  use crate::vector::Vector;
+  use crate::volume::{henyey_greenstein, Medium, Volume};
+  use std::f64;
+
+  #[test]
+  fn volume_henyey_greenstein_integrates_to_one() {
+    for &g in &[0.0, 0.5, -0.3, 0.9] {
+      let n = 20000;
+      let sum: f64 = (0..n)
+        .map(|i| {
+          let cos_theta = -1.0 + 2.0 * (i as f64 + 0.5) / n as f64;
+          henyey_greenstein(cos_theta, g) * 2.0 * f64::consts::PI * 2.0 / n as f64
+        })
+        .sum();
+      assert!((sum - 1.0).abs() < 0.001, "g = {}: {}", g, sum);
+    }
+  }
+
+  #[test]
+  fn volume_clip() {
+    let volume = Volume {
+      medium: Medium::new(0.1, 0.1, 0.0),
+      c: Vector::new(0.0, 0.0, 0.0),
+      radius: 1.0,
+    };
+    let ray = Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    assert_eq!(volume.clip(&ray, 100.0), Some((4.0, 6.0)));
+    assert_eq!(volume.clip(&ray, 5.0), Some((4.0, 5.0)));
+    assert_eq!(volume.clip(&ray, 3.0), None);
+    let inside = Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    assert_eq!(volume.clip(&inside, 100.0), Some((0.0, 1.0)));
+  }
+}
@@ -0,0 +1,306 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+
+//
+// Golden image regression tests.
+//
+// Each canonical scene is rendered at full size, box filtered down by
+// `SCALE` and compared against the reference in `tests/golden/`.  A test
+// fails when too many pixels are outside the per-pixel tolerance or the
+// PSNR drops below the threshold, and then writes the render and a diff
+// image to `target/golden/`.
+//
+// To (re)generate the references after an intended change run:
+//
+//   UPDATE_GOLDEN=1 cargo test --test golden
+//
+
+extern crate rust_web_rtrt;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rust_web_rtrt::constants::*;
+use rust_web_rtrt::objects::Scene;
+use rust_web_rtrt::raytracer::RayTracer;
+use rust_web_rtrt::scene_file;
+use rust_web_rtrt::scenes;
+
+// How much the references are scaled down.
+const SCALE: usize = 8;
+const WIDTH: usize = NUM_COLS / SCALE;
+const HEIGHT: usize = NUM_ROWS / SCALE;
+
+// A pixel matches if every channel is within this many levels.
+const PIXEL_TOLERANCE: u8 = 6;
+// The fraction of pixels that may be outside PIXEL_TOLERANCE.
+const MAX_BAD_PIXELS: f64 = 0.005;
+// Minimum peak signal to noise ratio, in dB.
+const MIN_PSNR: f64 = 40.0;
+
+// An RGB image, 8 bits per channel.
+struct Image {
+  width: usize,
+  height: usize,
+  data: Vec<u8>,
+}
+
+impl Image {
+  fn read_ppm(path: &Path) -> Option<Image> {
+    let bytes = fs::read(path).ok()?;
+    // The header is "P6\n<width> <height>\n255\n"
+    let mut fields = vec![];
+    let mut pos = 0;
+    while fields.len() < 4 {
+      while bytes.get(pos)?.is_ascii_whitespace() {
+        pos += 1;
+      }
+      let start = pos;
+      while !bytes.get(pos)?.is_ascii_whitespace() {
+        pos += 1;
+      }
+      fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+    }
+    if fields[0] != "P6" || fields[3] != "255" {
+      return None;
+    }
+    let width = fields[1].parse().ok()?;
+    let height = fields[2].parse().ok()?;
+    let data = bytes.get(pos + 1..pos + 1 + width * height * 3)?.to_vec();
+    Some(Image {
+      width,
+      height,
+      data,
+    })
+  }
+
+  fn write_ppm(&self, path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut bytes = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+    bytes.extend_from_slice(&self.data);
+    fs::write(path, bytes).unwrap();
+  }
+}
+
+// Render a whole frame and box filter it down to WIDTH x HEIGHT.
+fn render(depth: i32, scene: Scene, setup: fn(&mut RayTracer)) -> Image {
+  let mut rt = RayTracer::with_scene(depth, scene);
+  setup(&mut rt);
+
+  let mut sums = vec![0u32; WIDTH * HEIGHT * 3];
+  let mut strip_data = vec![0; NUM_COLS * SQUARE_SIZE * 4];
+  for strip_id in 0..NUM_STRIPS {
+    rt.render(strip_id as u32, &mut strip_data).unwrap();
+    for r in 0..SQUARE_SIZE {
+      let row = strip_id * SQUARE_SIZE + r;
+      for col in 0..NUM_COLS {
+        let src = (r * NUM_COLS + col) * 4;
+        let dst = ((row / SCALE) * WIDTH + col / SCALE) * 3;
+        for ch in 0..3 {
+          sums[dst + ch] += u32::from(strip_data[src + ch]);
+        }
+      }
+    }
+  }
+
+  let n = (SCALE * SCALE) as u32;
+  Image {
+    width: WIDTH,
+    height: HEIGHT,
+    data: sums.iter().map(|s| ((s + n / 2) / n) as u8).collect(),
+  }
+}
+
+fn psnr(a: &Image, b: &Image) -> f64 {
+  let mse = a
+    .data
+    .iter()
+    .zip(&b.data)
+    .map(|(x, y)| (f64::from(*x) - f64::from(*y)).powi(2))
+    .sum::<f64>()
+    / a.data.len() as f64;
+  if mse == 0.0 {
+    f64::INFINITY
+  } else {
+    10.0 * (255.0 * 255.0 / mse).log10()
+  }
+}
+
+// Bad pixels are red, the rest is the difference amplified.
+fn diff_image(a: &Image, b: &Image) -> Image {
+  let mut data = vec![0; a.data.len()];
+  for (i, (pa, pb)) in a.data.chunks(3).zip(b.data.chunks(3)).enumerate() {
+    let diff = pa.iter().zip(pb).map(|(x, y)| x.abs_diff(*y)).max().unwrap();
+    let px = if diff > PIXEL_TOLERANCE {
+      [255, 0, 0]
+    } else {
+      let v = (diff as usize * 255 / PIXEL_TOLERANCE.max(1) as usize) as u8;
+      [v, v, v]
+    };
+    data[i * 3..i * 3 + 3].copy_from_slice(&px);
+  }
+  Image {
+    width: a.width,
+    height: a.height,
+    data,
+  }
+}
+
+fn golden_dir() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn output_dir() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("golden")
+}
+
+fn check(name: &str, image: &Image) {
+  let reference_path = golden_dir().join(format!("{}.ppm", name));
+  if env::var("UPDATE_GOLDEN").is_ok() {
+    image.write_ppm(&reference_path);
+    return;
+  }
+
+  let reference = Image::read_ppm(&reference_path).unwrap_or_else(|| {
+    panic!(
+      "missing or invalid reference {}, run with UPDATE_GOLDEN=1 to create it",
+      reference_path.display()
+    )
+  });
+  assert_eq!((reference.width, reference.height), (image.width, image.height));
+
+  let bad_pixels = reference
+    .data
+    .chunks(3)
+    .zip(image.data.chunks(3))
+    .filter(|(a, b)| a.iter().zip(b.iter()).any(|(x, y)| x.abs_diff(*y) > PIXEL_TOLERANCE))
+    .count();
+  let bad_fraction = bad_pixels as f64 / (image.width * image.height) as f64;
+  let psnr = psnr(&reference, image);
+
+  if bad_fraction > MAX_BAD_PIXELS || psnr < MIN_PSNR {
+    let out = output_dir();
+    image.write_ppm(&out.join(format!("{}.ppm", name)));
+    diff_image(&reference, image).write_ppm(&out.join(format!("{}.diff.ppm", name)));
+    panic!(
+      "{}: {} pixels ({:.2}%) outside tolerance, PSNR {:.1} dB; see {}",
+      name,
+      bad_pixels,
+      bad_fraction * 100.0,
+      psnr,
+      out.display()
+    );
+  }
+}
+
+#[test]
+fn golden_default_scene() {
+  check("default", &render(5, scenes::default_scene(), |_| {}));
+}
+
+// Saved and loaded again, a scene still matches its reference.
+#[test]
+fn golden_scene_file() {
+  for (name, scene) in [("default", scenes::default_scene()), ("csg", scenes::csg())] {
+    let scene = scene_file::load(&scene_file::save(&scene)).unwrap();
+    check(name, &render(5, scene, |_| {}));
+  }
+}
+
+#[test]
+fn golden_caustics() {
+  let mut scene = scenes::default_scene();
+  scene.caustics = true;
+  check("caustics", &render(5, scene, |_| {}));
+}
+
+#[test]
+fn golden_default_scene_rotated() {
+  check(
+    "default_rotated",
+    &render(5, scenes::default_scene(), |rt| rt.increment(120.0)),
+  );
+}
+
+// set_time() must land exactly where the equivalent increment() does.
+#[test]
+fn golden_set_time() {
+  check(
+    "default_rotated",
+    &render(5, scenes::default_scene(), |rt| rt.set_time(120.0 / scenes::ORBIT_SPEED)),
+  );
+}
+
+#[test]
+fn golden_no_recursion() {
+  check("no_recursion", &render(1, scenes::default_scene(), |_| {}));
+}
+
+#[test]
+fn golden_bouncing_balls() {
+  check(
+    "bouncing_balls",
+    &render(5, scenes::bouncing_balls(), |rt| rt.set_time(1.5)),
+  );
+}
+
+#[test]
+fn golden_transformed() {
+  check("transformed", &render(5, scenes::transformed(), |_| {}));
+}
+
+#[test]
+fn golden_instances() {
+  check("instances", &render(5, scenes::instances(3), |_| {}));
+}
+
+#[test]
+fn golden_csg() {
+  check("csg", &render(5, scenes::csg(), |_| {}));
+}
+
+#[test]
+fn golden_sdf() {
+  check("sdf", &render(5, scenes::sdf(), |_| {}));
+}
+
+#[test]
+fn golden_fog() {
+  check("fog", &render(5, scenes::fog(), |_| {}));
+}
+
+#[test]
+fn golden_emissive() {
+  check("emissive", &render(5, scenes::emissive(), |_| {}));
+}
+
+#[test]
+fn golden_spotlights() {
+  check("spotlights", &render(5, scenes::spotlights(), |_| {}));
+}
+
+#[test]
+fn golden_many_spheres() {
+  check("many_spheres", &render(5, scenes::many_spheres(4), |_| {}));
+}
@@ -0,0 +1,123 @@
+/*********************************************************************
+ *                                                                   *
+ *   Copyright 2018 Simon M. Werner                                  *
+ *                                                                   *
+ *   Licensed to the Apache Software Foundation (ASF) under one      *
+ *   or more contributor license agreements.  See the NOTICE file    *
+ *   distributed with this work for additional information           *
+ *   regarding copyright ownership.  The ASF licenses this file      *
+ *   to you under the Apache License, Version 2.0 (the               *
+ *   "License"); you may not use this file except in compliance      *
+ *   with the License.  You may obtain a copy of the License at      *
+ *                                                                   *
+ *      http://www.apache.org/licenses/LICENSE-2.0                   *
+ *                                                                   *
+ *   Unless required by applicable law or agreed to in writing,      *
+ *   software distributed under the License is distributed on an     *
+ *   "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY          *
+ *   KIND, either express or implied.  See the License for the       *
+ *   specific language governing permissions and limitations         *
+ *   under the License.                                              *
+ *                                                                   *
+ *********************************************************************/
+//
+// Rendering only the squares that changed must give the same frame as
+// rendering everything.
+//
+
+extern crate rust_web_rtrt;
+
+use rust_web_rtrt::animation::Animation;
+use rust_web_rtrt::constants::*;
+use rust_web_rtrt::objects::Scene;
+use rust_web_rtrt::raytracer::RayTracer;
+use rust_web_rtrt::scenes;
+
+const STRIP_LEN: usize = NUM_COLS * SQUARE_SIZE * 4;
+
+fn full_frame(scene: Scene, t: f64) -> Vec<u8> {
+  let mut rt = RayTracer::with_scene(3, scene);
+  rt.set_time(t);
+  let mut frame = vec![0; NUM_STRIPS * STRIP_LEN];
+  for (strip_id, strip) in frame.chunks_exact_mut(STRIP_LEN).enumerate() {
+    rt.render(strip_id as u32, strip).unwrap();
+  }
+  frame
+}
+
+// Render frames at `times` incrementally, checking each against a full render.
+// Returns how many squares were rendered after the first frame.
+fn check_incremental(make_scene: fn() -> Scene, times: &[f64]) -> usize {
+  let mut rt = RayTracer::with_scene(3, make_scene());
+  let mut frame = vec![0; NUM_STRIPS * STRIP_LEN];
+  let mut rendered = 0;
+  for (i, &t) in times.iter().enumerate() {
+    rt.set_time(t);
+    let dirty = rt.begin_frame() as usize;
+    let mut squares = 0;
+    for (strip_id, strip) in frame.chunks_exact_mut(STRIP_LEN).enumerate() {
+      squares += rt.render_changed(strip_id as u32, strip).unwrap().len();
+    }
+    assert_eq!(squares, dirty);
+    if i > 0 {
+      rendered += squares;
+    }
+    assert!(frame == full_frame(make_scene(), t), "frame at t = {} differs", t);
+  }
+  rendered
+}
+
+// The default scene with matte spheres, only the first of which moves, over
+// the mirror disc.
+fn one_moving_sphere() -> Scene {
+  let mut scene = scenes::default_scene();
+  for (i, obj) in scene.objs.iter_mut().enumerate().skip(1) {
+    obj.rfl = 0.0;
+    obj.rfr = 0.0;
+    obj.set_diffuse(0.7);
+    if i > 1 {
+      obj.animation = Animation::Static;
+    }
+  }
+  scene
+}
+
+#[test]
+fn incremental_default_scene() {
+  check_incremental(scenes::default_scene, &[1.0, 1.1]);
+}
+
+#[test]
+fn incremental_saves_work() {
+  let all = NUM_STRIPS * NUM_COLS / SQUARE_SIZE;
+  let rendered = check_incremental(one_moving_sphere, &[1.0, 1.1, 1.3]);
+  assert!(rendered < all, "rendered {} squares for two frames of {}", rendered, all);
+}
+
+#[test]
+fn incremental_lights_and_shadows() {
+  check_incremental(scenes::spotlights, &[0.5, 0.7]);
+  check_incremental(scenes::emissive, &[0.5, 0.7]);
+}
+
+#[test]
+fn incremental_moving_shapes() {
+  check_incremental(scenes::bouncing_balls, &[0.2, 0.3]);
+  check_incremental(scenes::transformed, &[0.5, 0.7]);
+  check_incremental(scenes::csg, &[0.5, 0.7]);
+}
+
+#[test]
+fn incremental_idle() {
+  let mut rt = RayTracer::with_scene(3, scenes::default_scene());
+  rt.set_time(1.0);
+  assert_eq!(rt.begin_frame() as usize, NUM_STRIPS * NUM_COLS / SQUARE_SIZE);
+  // Nothing moved
+  rt.set_time(1.0);
+  assert_eq!(rt.begin_frame(), 0);
+  let mut strip = vec![0; STRIP_LEN];
+  assert!(rt.render_changed(40, &mut strip).unwrap().is_empty());
+  // An edit redraws everything
+  rt.set_object_colour(1, 0.0, 1.0, 0.0).unwrap();
+  assert_eq!(rt.begin_frame() as usize, NUM_STRIPS * NUM_COLS / SQUARE_SIZE);
+}